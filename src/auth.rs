@@ -5,12 +5,11 @@
 //! are implementations for the following types:
 //!
 //! * `Permissions`: The [`Permissions`] type itself is an auth provider, it will allow
-//!                  access with the given permissions to any non-anonymous client.
+//!   access with the given permissions to any non-anonymous client.
 //! * `HashMap<String, Secret<String>>`: A mapping of usernames to (unencrypted) passwords.
 //! * `Secret<String>`: Master password, ignores all usernames and just compares the password.
 //! * `Anonymous`: A decorator that wraps around another [`AuthProvider`], will grant a fixed set
-//!                of permissions to anonymous user, while deferring everything else to the inner
-//!                provider.
+//!   of permissions to anonymous user, while deferring everything else to the inner provider.
 //!
 //! All the above implementations deal with **authentication** only, once authorized, full
 //! write access to everything is granted.
@@ -28,6 +27,7 @@ use axum::{
         request::Parts,
         StatusCode,
     },
+    response::{IntoResponse, Response},
 };
 use sec::Secret;
 use thiserror::Error;
@@ -109,19 +109,21 @@ impl ValidCredentials {
 
 #[async_trait]
 impl FromRequestParts<Arc<ContainerRegistry>> for ValidCredentials {
-    type Rejection = StatusCode;
+    type Rejection = Response;
 
     #[inline(always)]
     async fn from_request_parts(
         parts: &mut Parts,
         state: &Arc<ContainerRegistry>,
     ) -> Result<Self, Self::Rejection> {
-        let unverified = Unverified::from_request_parts(parts, state).await?;
+        let unverified = Unverified::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
 
         // We got a set of credentials, now verify.
         match state.auth_provider.check_credentials(&unverified).await {
             Some(creds) => Ok(creds),
-            None => Err(StatusCode::UNAUTHORIZED),
+            None => Err(state.unauthorized_response()),
         }
     }
 }
@@ -259,7 +261,11 @@ where
     async fn check_credentials(&self, unverified: &Unverified) -> Option<ValidCredentials> {
         match unverified {
             Unverified::NoCredentials => Some(ValidCredentials::new(AnonCreds::Anonymous)),
-            _other => self.inner.check_credentials(unverified).await,
+            _other => self
+                .inner
+                .check_credentials(unverified)
+                .await
+                .map(|creds| ValidCredentials::new(AnonCreds::Valid(creds))),
         }
     }
 
@@ -270,14 +276,14 @@ where
     ) -> Permissions {
         match creds.extract_ref::<AnonCreds>() {
             AnonCreds::Anonymous => self.anon_permissions,
-            _other => self.inner.image_permissions(creds, image).await,
+            AnonCreds::Valid(inner_creds) => self.inner.image_permissions(inner_creds, image).await,
         }
     }
 
     async fn blob_permissions(&self, creds: &ValidCredentials, blob: &ImageDigest) -> Permissions {
         match creds.extract_ref::<AnonCreds>() {
             AnonCreds::Anonymous => self.anon_permissions,
-            _other => self.inner.blob_permissions(creds, blob).await,
+            AnonCreds::Valid(inner_creds) => self.inner.blob_permissions(inner_creds, blob).await,
         }
     }
 }
@@ -366,19 +372,15 @@ where
     #[inline(always)]
     async fn image_permissions(
         &self,
-        _creds: &ValidCredentials,
-        _image: &ImageLocation,
+        creds: &ValidCredentials,
+        image: &ImageLocation,
     ) -> Permissions {
-        Permissions::ReadWrite
+        <T as AuthProvider>::image_permissions(self, creds, image).await
     }
 
     #[inline(always)]
-    async fn blob_permissions(
-        &self,
-        _creds: &ValidCredentials,
-        _blob: &ImageDigest,
-    ) -> Permissions {
-        Permissions::ReadWrite
+    async fn blob_permissions(&self, creds: &ValidCredentials, blob: &ImageDigest) -> Permissions {
+        <T as AuthProvider>::blob_permissions(self, creds, blob).await
     }
 }
 
@@ -395,19 +397,15 @@ where
     #[inline(always)]
     async fn image_permissions(
         &self,
-        _creds: &ValidCredentials,
-        _image: &ImageLocation,
+        creds: &ValidCredentials,
+        image: &ImageLocation,
     ) -> Permissions {
-        Permissions::ReadWrite
+        <T as AuthProvider>::image_permissions(self, creds, image).await
     }
 
     #[inline(always)]
-    async fn blob_permissions(
-        &self,
-        _creds: &ValidCredentials,
-        _blob: &ImageDigest,
-    ) -> Permissions {
-        Permissions::ReadWrite
+    async fn blob_permissions(&self, creds: &ValidCredentials, blob: &ImageDigest) -> Permissions {
+        <T as AuthProvider>::blob_permissions(self, creds, blob).await
     }
 }
 
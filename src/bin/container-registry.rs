@@ -1,11 +1,12 @@
-use std::{fmt, fs, net::SocketAddr, path, process::ExitCode, sync::Arc};
+use std::{fmt, fs, net::SocketAddr, path, process::ExitCode, sync::Arc, time::Duration};
 
 use anyhow::Context;
 use axum::{async_trait, extract::DefaultBodyLimit, Router};
 use container_registry::{
     auth::{self, AuthProvider},
+    gc::GarbageCollector,
     hooks::RegistryHooks,
-    storage::ManifestReference,
+    storage::{IntegrityCheck, ManifestReference, RegistryStorage},
 };
 use sec::Secret;
 use structopt::StructOpt;
@@ -23,6 +24,50 @@ struct Opts {
     /// Password to require.
     #[structopt(short, long)]
     password: Option<String>,
+    /// Remove uploads that received no data for this many seconds.
+    #[structopt(long)]
+    upload_ttl: Option<u64>,
+    /// S3 bucket to use as storage instead of a directory, configured through the usual AWS
+    /// environment variables.
+    #[cfg(feature = "s3")]
+    #[structopt(long)]
+    s3_bucket: Option<String>,
+    /// Key prefix to store all objects below in the S3 bucket.
+    #[cfg(feature = "s3")]
+    #[structopt(long, default_value = "")]
+    s3_prefix: String,
+    /// Use path-style addressing for S3, as required by most S3-compatible services.
+    #[cfg(feature = "s3")]
+    #[structopt(long)]
+    s3_path_style: bool,
+    /// Redirect blob downloads to presigned S3 URLs valid for this many seconds.
+    #[cfg(feature = "s3")]
+    #[structopt(long)]
+    s3_presign_ttl: Option<u64>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Remove manifests and blobs no longer reachable from any tag, then exit.
+    Gc {
+        /// Only report what would be removed.
+        #[structopt(long)]
+        dry_run: bool,
+        /// Spare manifests and blobs written less than this many seconds ago.
+        #[structopt(long, default_value = "3600")]
+        grace_period: u64,
+    },
+    /// Check the storage directory for corruption and leftovers, printing a JSON report.
+    ///
+    /// Uploads are reported once they received no data for `--upload-ttl` seconds, a day by
+    /// default. Exits with a non-zero status if any issues were found.
+    Fsck {
+        /// Move offending files into the `quarantine` directory of the storage.
+        #[structopt(long)]
+        repair: bool,
+    },
 }
 
 struct LoggingHook;
@@ -36,30 +81,65 @@ impl RegistryHooks for LoggingHook {
     }
 }
 
-async fn run() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env().add_directive(Level::INFO.into()),
-        )
-        .init();
-
+async fn run() -> anyhow::Result<ExitCode> {
     let opts = Opts::from_args();
 
-    let (_tmpdir, storage) = if let Some(storage) = opts.storage {
+    let subscriber = tracing_subscriber::fmt().with_env_filter(
+        tracing_subscriber::EnvFilter::from_default_env().add_directive(Level::INFO.into()),
+    );
+    if matches!(opts.command, Some(Command::Fsck { .. })) {
+        // Keeps stdout free for the report.
+        subscriber.with_writer(std::io::stderr).init();
+    } else {
+        subscriber.init();
+    }
+
+    if let Some(Command::Fsck { repair }) = opts.command {
+        let storage = opts
+            .storage
+            .context("checking storage requires a storage directory")?;
+
+        let mut check = IntegrityCheck::new().repair(repair);
+        if let Some(upload_ttl) = opts.upload_ttl {
+            check = check.upload_ttl(Duration::from_secs(upload_ttl));
+        }
+
+        info!(path=%storage.display(), repair, "checking storage");
+        let report = check.run(&storage).await.context("storage check failed")?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+
+        if report.is_clean() {
+            info!("no issues found");
+            return Ok(ExitCode::SUCCESS);
+        }
+        warn!(issues = report.issues().len(), repair, "issues found");
+        return Ok(ExitCode::FAILURE);
+    }
+
+    let mut builder = container_registry::ContainerRegistry::builder();
+
+    let mut _tmpdir = None;
+    if let Some(storage) = s3_storage(&opts).await {
+        builder = builder.storage_backend(storage);
+    } else if let Some(storage) = opts.storage {
         info!(path=%storage.display(), "storage set");
         if !storage.exists() {
             fs::create_dir(&storage).context("could not create non-existant storage dir")?;
         }
 
-        (None, storage)
+        builder = builder.storage(storage);
+    } else if matches!(opts.command, Some(Command::Gc { .. })) {
+        // Collecting a fresh temporary directory would always succeed, pointlessly.
+        anyhow::bail!("garbage collection requires a storage directory or bucket");
     } else {
         let tmp_dir = tempdir::TempDir::new("container_registry_test")
             .context("could not create temporary storage dir")?;
         let storage = tmp_dir.path().to_owned();
 
         info!(path=%storage.display(), "using temporary storage");
-        (Some(tmp_dir), storage)
-    };
+        _tmpdir = Some(tmp_dir);
+        builder = builder.storage(storage);
+    }
 
     let auth_provider: Arc<dyn AuthProvider> = if let Some(password) = opts.password {
         info!("using password supplied on command line");
@@ -70,12 +150,50 @@ async fn run() -> anyhow::Result<()> {
         Arc::new(auth::Permissions::ReadWrite)
     };
 
-    let registry = container_registry::ContainerRegistry::builder()
-        .storage(storage)
+    builder = builder
         .hooks(Box::new(LoggingHook))
-        .auth_provider(auth_provider)
-        .build()
-        .context("failed to instantiate registry")?;
+        .auth_provider(auth_provider);
+
+    if let Some(upload_ttl) = opts.upload_ttl {
+        info!(upload_ttl, "expiring abandoned uploads");
+        builder = builder.upload_ttl(Duration::from_secs(upload_ttl));
+    }
+
+    let registry = builder.build().context("failed to instantiate registry")?;
+
+    if let Some(Command::Gc {
+        dry_run,
+        grace_period,
+    }) = opts.command
+    {
+        let collector = GarbageCollector::new()
+            .dry_run(dry_run)
+            .grace_period(Duration::from_secs(grace_period));
+        let report = registry
+            .collect_garbage(&collector)
+            .await
+            .context("garbage collection failed")?;
+
+        for manifest_reference in report.manifests() {
+            info!(%manifest_reference, dry_run, "removing manifest");
+        }
+        for manifest in report.unlinked_manifests() {
+            info!(%manifest, dry_run, "removing unlinked manifest");
+        }
+        for blob in report.blobs() {
+            info!(%blob, dry_run, "removing blob");
+        }
+        info!(
+            manifests = report.manifests().len(),
+            unlinked_manifests = report.unlinked_manifests().len(),
+            blobs = report.blobs().len(),
+            reclaimable_bytes = report.reclaimable_bytes(),
+            dry_run,
+            "garbage collection finished"
+        );
+
+        return Ok(ExitCode::SUCCESS);
+    }
 
     let app = Router::new()
         .merge(registry.make_router())
@@ -93,7 +211,36 @@ async fn run() -> anyhow::Result<()> {
 
     axum::serve(listener, app).await?;
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Creates the S3 storage, if a bucket has been given.
+#[cfg(feature = "s3")]
+async fn s3_storage(opts: &Opts) -> Option<Box<dyn RegistryStorage>> {
+    use container_registry::storage::S3Storage;
+
+    let bucket = opts.s3_bucket.as_ref()?;
+    info!(%bucket, prefix=%opts.s3_prefix, "using S3 storage");
+
+    let config = aws_config::load_from_env().await;
+    let client = aws_sdk_s3::Client::from_conf(
+        aws_sdk_s3::config::Builder::from(&config)
+            .force_path_style(opts.s3_path_style)
+            .build(),
+    );
+
+    let mut storage = S3Storage::new(client, bucket).prefix(&opts.s3_prefix);
+    if let Some(presign_ttl) = opts.s3_presign_ttl {
+        storage = storage.presign_blobs(Duration::from_secs(presign_ttl));
+    }
+
+    Some(Box::new(storage))
+}
+
+/// Creates the S3 storage, which is never used without the `s3` feature.
+#[cfg(not(feature = "s3"))]
+async fn s3_storage(_opts: &Opts) -> Option<Box<dyn RegistryStorage>> {
+    None
 }
 
 struct FormatErr(anyhow::Error);
@@ -115,10 +262,11 @@ impl fmt::Display for FormatErr {
 #[tokio::main]
 
 async fn main() -> ExitCode {
-    if let Err(err) = run().await {
-        error!(err=%FormatErr(err), "failed");
-        ExitCode::FAILURE
-    } else {
-        ExitCode::SUCCESS
+    match run().await {
+        Ok(exit_code) => exit_code,
+        Err(err) => {
+            error!(err=%FormatErr(err), "failed");
+            ExitCode::FAILURE
+        }
     }
 }
@@ -0,0 +1,821 @@
+//! Garbage collection of unreferenced blobs and manifests.
+//!
+//! Overwriting or deleting a tag leaves the manifest it pointed to behind, along with the blobs
+//! only that manifest referenced. The [`GarbageCollector`] removes these in two phases:
+//!
+//! 1. **Mark**: Starting from the tags of every image, all reachable manifests are marked,
+//!    following indexes to the manifests they list and image manifests to their config and layers.
+//!    Manifests referring to a marked manifest of the same image through their `subject`
+//!    (referrers) are marked as well.
+//! 2. **Sweep**: Unmarked manifests are removed from their image, unless they have been tagged in
+//!    the meantime, along with manifests not linked to any image at all. Then all blobs not
+//!    referenced by any marked manifest are deleted.
+//!
+//! Collection may run while the registry is serving requests. Blobs and manifests written within
+//! the [grace period](GarbageCollector::grace_period) are never removed, as they may belong to a
+//! push still in progress, and manifests pushed while the collection is running are taken into
+//! account before any blob is deleted.
+//!
+//! Manifests not linked to any image are left behind by older versions of the registry whenever a
+//! tag was overwritten.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    storage::{
+        BlobMetadata, Digest, Error, ImageLocation, ManifestReference, Reference, RegistryStorage,
+    },
+    types::{ContentDescriptor, Manifest},
+    ImageDigest,
+};
+
+/// Default grace period, see [`GarbageCollector::grace_period`].
+const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// A mark-and-sweep garbage collector for registry storage.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use container_registry::gc::GarbageCollector;
+///
+/// // Only report what would be removed, sparing anything written in the last ten minutes.
+/// let collector = GarbageCollector::new()
+///     .dry_run(true)
+///     .grace_period(Duration::from_secs(10 * 60));
+/// ```
+///
+/// See the [module documentation](self) for details.
+#[derive(Clone, Debug)]
+pub struct GarbageCollector {
+    /// Whether to leave storage untouched, only reporting what would be removed.
+    dry_run: bool,
+    /// Minimum age of blobs and manifests to be removed.
+    grace_period: Duration,
+}
+
+impl Default for GarbageCollector {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            grace_period: DEFAULT_GRACE_PERIOD,
+        }
+    }
+}
+
+impl GarbageCollector {
+    /// Creates a new garbage collector with a grace period of one hour.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether to only report what would be removed, without removing anything.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Sets the grace period.
+    ///
+    /// Blobs and manifests written less than `grace_period` ago are never removed. It should
+    /// comfortably exceed the time it takes to push an image, as blobs are uploaded before the
+    /// manifest referencing them.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Collects garbage in the given storage.
+    ///
+    /// Returns everything that was removed or, in dry-run mode, would have been.
+    pub async fn run(
+        &self,
+        storage: &dyn RegistryStorage,
+    ) -> Result<GarbageCollectionReport, Error> {
+        let mut collection = Collection {
+            storage,
+            grace_period: self.grace_period,
+            started: SystemTime::now(),
+            manifests: HashMap::new(),
+            reachable_manifests: HashSet::new(),
+            reachable_blobs: HashSet::new(),
+        };
+
+        let mut images = Vec::new();
+        for location in storage.list_repositories().await? {
+            let marked = collection.mark_image(&location).await?;
+            images.push((location, marked));
+        }
+
+        let mut report = GarbageCollectionReport {
+            dry_run: self.dry_run,
+            ..Default::default()
+        };
+        let mut counted = HashSet::new();
+        let mut swept = HashSet::new();
+        let mut linked = HashSet::new();
+
+        for (location, marked) in &mut images {
+            for digest in storage.list_image_manifests(location).await? {
+                linked.insert(digest);
+                if marked.contains(&digest) {
+                    continue;
+                }
+
+                // Checked right before removal, the manifest may have been pushed again by now.
+                let Some(metadata) = storage.get_manifest_metadata(digest).await? else {
+                    continue;
+                };
+                if collection.is_recent(&metadata) {
+                    collection.mark(location, marked, digest).await?;
+                    continue;
+                }
+
+                // Tags created since marking are skipped by storage, never removed.
+                if !self.dry_run && !storage.delete_untagged_manifest(location, digest).await? {
+                    continue;
+                }
+                swept.insert(digest);
+
+                // The data of manifests still reachable through another image is kept.
+                if !collection.reachable_manifests.contains(&digest) && counted.insert(digest) {
+                    report.reclaimable_bytes += metadata.size();
+                }
+                report.manifests.push(ManifestReference::new(
+                    location.clone(),
+                    Reference::new_digest(digest),
+                ));
+            }
+        }
+
+        for digest in storage.list_manifests().await? {
+            if linked.contains(&digest) || collection.reachable_manifests.contains(&digest) {
+                continue;
+            }
+
+            let Some(metadata) = storage.get_manifest_metadata(digest).await? else {
+                continue;
+            };
+            if collection.is_recent(&metadata) {
+                continue;
+            }
+
+            // Linked to an image since listing, if pushed again.
+            if !self.dry_run && !storage.delete_unlinked_manifest(digest).await? {
+                continue;
+            }
+            swept.insert(digest);
+
+            report.reclaimable_bytes += metadata.size();
+            report.unlinked_manifests.push(digest);
+        }
+
+        // Manifests pushed since marking started may reference blobs considered garbage so far.
+        // This includes manifests swept above and pushed again since, which are recent again.
+        for location in storage.list_repositories().await? {
+            let mut marked = HashSet::new();
+            for digest in storage.list_image_manifests(&location).await? {
+                let is_new =
+                    !collection.reachable_manifests.contains(&digest) && !swept.contains(&digest);
+                let is_recent = match storage.get_manifest_metadata(digest).await? {
+                    Some(metadata) => collection.is_recent(&metadata),
+                    None => false,
+                };
+                if is_new || is_recent {
+                    collection.mark(&location, &mut marked, digest).await?;
+                }
+            }
+        }
+
+        for blob in storage.list_blobs().await? {
+            let digest = blob.digest();
+            if collection.reachable_blobs.contains(&digest) || collection.is_recent(&blob) {
+                continue;
+            }
+
+            // Checked right before removal, the blob may have been uploaded again by now.
+            let Some(blob) = storage.get_blob_metadata(digest).await? else {
+                continue;
+            };
+            if collection.is_recent(&blob) {
+                continue;
+            }
+
+            if !self.dry_run && !storage.delete_blob(digest).await? {
+                continue;
+            }
+
+            report.reclaimable_bytes += blob.size();
+            report.blobs.push(digest);
+        }
+
+        Ok(report)
+    }
+}
+
+/// The outcome of a garbage collection.
+#[derive(Clone, Debug, Default)]
+pub struct GarbageCollectionReport {
+    /// Whether storage was left untouched.
+    dry_run: bool,
+    /// Manifests removed from images.
+    manifests: Vec<ManifestReference>,
+    /// Removed manifests that were not linked to any image.
+    unlinked_manifests: Vec<Digest>,
+    /// Deleted blobs.
+    blobs: Vec<Digest>,
+    /// Total size of all removed data.
+    reclaimable_bytes: u64,
+}
+
+impl GarbageCollectionReport {
+    /// Returns whether this is the report of a dry run, i.e. nothing has actually been removed.
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Returns the manifests removed from their image.
+    ///
+    /// A manifest still reachable through another image is only unlinked from the listed one.
+    pub fn manifests(&self) -> &[ManifestReference] {
+        &self.manifests
+    }
+
+    /// Returns the removed manifests that were not linked to any image.
+    pub fn unlinked_manifests(&self) -> &[Digest] {
+        &self.unlinked_manifests
+    }
+
+    /// Returns the deleted blobs.
+    pub fn blobs(&self) -> &[Digest] {
+        &self.blobs
+    }
+
+    /// Returns the total size of all removed manifests and blobs, in bytes.
+    pub fn reclaimable_bytes(&self) -> u64 {
+        self.reclaimable_bytes
+    }
+}
+
+/// The parts of a manifest relevant for garbage collection.
+#[derive(Clone, Debug, Default)]
+struct References {
+    /// Blobs referenced by an image manifest.
+    blobs: Vec<Digest>,
+    /// Manifests listed by an index.
+    manifests: Vec<Digest>,
+    /// The manifest this one refers to.
+    subject: Option<Digest>,
+}
+
+impl References {
+    fn new(manifest: &Manifest) -> Self {
+        Self {
+            blobs: manifest.blobs().filter_map(descriptor_digest).collect(),
+            manifests: manifest.manifests().filter_map(descriptor_digest).collect(),
+            subject: manifest.subject().and_then(descriptor_digest),
+        }
+    }
+}
+
+/// Returns the digest a descriptor points to, if it is supported.
+fn descriptor_digest(descriptor: &ContentDescriptor) -> Option<Digest> {
+    descriptor
+        .digest()
+        .parse::<ImageDigest>()
+        .ok()
+        .map(|digest| digest.digest())
+}
+
+/// State of a single garbage collection.
+struct Collection<'a> {
+    storage: &'a dyn RegistryStorage,
+    grace_period: Duration,
+    /// Time the collection started, ages are relative to it.
+    started: SystemTime,
+    /// References of all manifests loaded so far, `None` if missing or unparsable.
+    manifests: HashMap<Digest, Option<References>>,
+    /// Manifests marked in any image.
+    reachable_manifests: HashSet<Digest>,
+    /// Blobs referenced by any marked manifest.
+    reachable_blobs: HashSet<Digest>,
+}
+
+impl Collection<'_> {
+    /// Checks whether a blob or manifest is too recent to be removed.
+    ///
+    /// Anything without a known modification time is considered recent.
+    fn is_recent(&self, metadata: &BlobMetadata) -> bool {
+        !metadata.modified().is_some_and(|modified| {
+            self.started
+                .duration_since(modified)
+                .is_ok_and(|age| age >= self.grace_period)
+        })
+    }
+
+    /// Loads the references of a manifest, caching them.
+    async fn references(
+        &mut self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<Option<References>, Error> {
+        if let Some(references) = self.manifests.get(&digest) {
+            return Ok(references.clone());
+        }
+
+        // Manifests are not tied to a location when retrieved by digest.
+        let manifest_reference =
+            ManifestReference::new(location.clone(), Reference::new_digest(digest));
+        let references = self
+            .storage
+            .get_manifest(&manifest_reference)
+            .await?
+            .and_then(|manifest_json| Manifest::from_slice(&manifest_json).ok())
+            .map(|manifest| References::new(&manifest));
+
+        self.manifests.insert(digest, references.clone());
+        Ok(references)
+    }
+
+    /// Marks all reachable manifests of an image, returning them.
+    async fn mark_image(&mut self, location: &ImageLocation) -> Result<HashSet<Digest>, Error> {
+        let mut marked = HashSet::new();
+
+        for tag in self.storage.list_tags(location).await?.unwrap_or_default() {
+            let manifest_reference =
+                ManifestReference::new(location.clone(), Reference::new_tag(tag));
+            if let Some(manifest_json) = self.storage.get_manifest(&manifest_reference).await? {
+                let digest = Digest::from_contents(&manifest_json);
+                self.mark(location, &mut marked, digest).await?;
+            }
+        }
+
+        let digests = self.storage.list_image_manifests(location).await?;
+
+        // Recent manifests may be part of a push in progress. Unparsable ones cannot be followed,
+        // thus are kept along with anything they might reference.
+        for &digest in &digests {
+            let recent = match self.storage.get_manifest_metadata(digest).await? {
+                Some(metadata) => self.is_recent(&metadata),
+                None => false,
+            };
+            if recent || self.references(location, digest).await?.is_none() {
+                self.mark(location, &mut marked, digest).await?;
+            }
+        }
+
+        // Referrers may themselves be referred to, repeat until no more are found.
+        loop {
+            let mut found = false;
+
+            for &digest in &digests {
+                if marked.contains(&digest) {
+                    continue;
+                }
+
+                let subject = self
+                    .references(location, digest)
+                    .await?
+                    .and_then(|references| references.subject);
+                if subject.is_some_and(|subject| marked.contains(&subject)) {
+                    self.mark(location, &mut marked, digest).await?;
+                    found = true;
+                }
+            }
+
+            if !found {
+                break;
+            }
+        }
+
+        Ok(marked)
+    }
+
+    /// Marks a manifest, along with all manifests and blobs it references.
+    async fn mark(
+        &mut self,
+        location: &ImageLocation,
+        marked: &mut HashSet<Digest>,
+        digest: Digest,
+    ) -> Result<(), Error> {
+        let mut pending = vec![digest];
+
+        while let Some(digest) = pending.pop() {
+            if !marked.insert(digest) {
+                continue;
+            }
+            self.reachable_manifests.insert(digest);
+
+            let Some(references) = self.references(location, digest).await? else {
+                continue;
+            };
+            self.reachable_blobs.extend(references.blobs);
+            pending.extend(references.manifests);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        path::Path,
+        sync::Mutex,
+        time::{Duration, SystemTime},
+    };
+
+    use axum::async_trait;
+    use tokio::io::{AsyncWrite, AsyncWriteExt};
+    use uuid::Uuid;
+
+    use super::GarbageCollector;
+    use crate::{
+        storage::{
+            BlobMetadata, BlobReader, Digest, Error, FilesystemStorage, ImageLocation,
+            ManifestReference, MemoryStorage, Reference, RegistryStorage,
+        },
+        types::OCI_IMAGE_MANIFEST,
+    };
+
+    /// Storage pushing a manifest again when the collector removes it.
+    struct RepushingStorage {
+        inner: FilesystemStorage,
+        /// The manifest to push, along with the tag to push it as.
+        repush: Mutex<Option<(ManifestReference, Vec<u8>)>>,
+        /// Whether to push right before the removal instead of right after.
+        repush_first: bool,
+    }
+
+    impl RepushingStorage {
+        fn new(dir: &Path, repush_first: bool) -> Self {
+            Self {
+                inner: FilesystemStorage::new(dir).unwrap(),
+                repush: Mutex::new(None),
+                repush_first,
+            }
+        }
+
+        async fn repush(&self) -> Result<(), Error> {
+            let repush = self.repush.lock().unwrap().take();
+            if let Some((manifest_reference, manifest)) = repush {
+                self.inner
+                    .put_manifest(&manifest_reference, &manifest, OCI_IMAGE_MANIFEST, true)
+                    .await?;
+            }
+
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl RegistryStorage for RepushingStorage {
+        async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+            self.inner.begin_new_upload().await
+        }
+
+        async fn get_blob_reader(
+            &self,
+            digest: Digest,
+        ) -> Result<Option<Box<dyn BlobReader>>, Error> {
+            self.inner.get_blob_reader(digest).await
+        }
+
+        async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+            self.inner.get_blob_metadata(digest).await
+        }
+
+        async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+            self.inner.list_blobs().await
+        }
+
+        async fn delete_blob(&self, digest: Digest) -> Result<bool, Error> {
+            self.inner.delete_blob(digest).await
+        }
+
+        async fn get_upload_size(&self, upload: Uuid) -> Result<Option<u64>, Error> {
+            self.inner.get_upload_size(upload).await
+        }
+
+        async fn get_upload_writer(
+            &self,
+            start_at: u64,
+            upload: Uuid,
+        ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
+            self.inner.get_upload_writer(start_at, upload).await
+        }
+
+        async fn finalize_upload(&self, upload: Uuid, hash: Digest) -> Result<(), Error> {
+            self.inner.finalize_upload(upload, hash).await
+        }
+
+        async fn cancel_upload(&self, upload: Uuid) -> Result<bool, Error> {
+            self.inner.cancel_upload(upload).await
+        }
+
+        async fn expire_uploads(&self, max_age: Duration) -> Result<Vec<Uuid>, Error> {
+            self.inner.expire_uploads(max_age).await
+        }
+
+        async fn get_manifest(
+            &self,
+            manifest_reference: &ManifestReference,
+        ) -> Result<Option<Vec<u8>>, Error> {
+            self.inner.get_manifest(manifest_reference).await
+        }
+
+        async fn put_manifest(
+            &self,
+            manifest_reference: &ManifestReference,
+            manifest: &[u8],
+            media_type: &str,
+            replace_tag: bool,
+        ) -> Result<Digest, Error> {
+            self.inner
+                .put_manifest(manifest_reference, manifest, media_type, replace_tag)
+                .await
+        }
+
+        async fn get_manifest_media_type(&self, digest: Digest) -> Result<Option<String>, Error> {
+            self.inner.get_manifest_media_type(digest).await
+        }
+
+        async fn get_manifest_metadata(
+            &self,
+            digest: Digest,
+        ) -> Result<Option<BlobMetadata>, Error> {
+            self.inner.get_manifest_metadata(digest).await
+        }
+
+        async fn list_manifests(&self) -> Result<Vec<Digest>, Error> {
+            self.inner.list_manifests().await
+        }
+
+        async fn list_image_manifests(
+            &self,
+            location: &ImageLocation,
+        ) -> Result<Vec<Digest>, Error> {
+            self.inner.list_image_manifests(location).await
+        }
+
+        async fn delete_tag(&self, location: &ImageLocation, tag: &str) -> Result<bool, Error> {
+            self.inner.delete_tag(location, tag).await
+        }
+
+        async fn delete_manifest(
+            &self,
+            location: &ImageLocation,
+            digest: Digest,
+        ) -> Result<bool, Error> {
+            self.inner.delete_manifest(location, digest).await
+        }
+
+        async fn delete_untagged_manifest(
+            &self,
+            location: &ImageLocation,
+            digest: Digest,
+        ) -> Result<bool, Error> {
+            if self.repush_first {
+                self.repush().await?;
+            }
+
+            let deleted = self
+                .inner
+                .delete_untagged_manifest(location, digest)
+                .await?;
+
+            self.repush().await?;
+
+            Ok(deleted)
+        }
+
+        async fn delete_unlinked_manifest(&self, digest: Digest) -> Result<bool, Error> {
+            self.inner.delete_unlinked_manifest(digest).await
+        }
+
+        async fn list_tags(&self, location: &ImageLocation) -> Result<Option<Vec<String>>, Error> {
+            self.inner.list_tags(location).await
+        }
+
+        async fn list_repositories(&self) -> Result<Vec<ImageLocation>, Error> {
+            self.inner.list_repositories().await
+        }
+    }
+
+    /// Sets the modification time of a file.
+    fn set_modified(path: &Path, modified: SystemTime) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn spares_recent_content() {
+        let storage = MemoryStorage::new();
+        let location = ImageLocation::new("gc/recent").unwrap();
+
+        // An untagged manifest, unreachable but just pushed.
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_MANIFEST,
+            "config": {
+                "mediaType": "application/vnd.oci.empty.v1+json",
+                "size": 2,
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+            },
+            "layers": [],
+        });
+        let digest = storage
+            .put_manifest(
+                &ManifestReference::new(location.clone(), Reference::new_tag("latest")),
+                manifest.to_string().as_bytes(),
+                OCI_IMAGE_MANIFEST,
+                true,
+            )
+            .await
+            .unwrap();
+        storage.delete_tag(&location, "latest").await.unwrap();
+
+        let report = GarbageCollector::new().run(&storage).await.unwrap();
+        assert!(report.manifests().is_empty());
+        assert_eq!(report.reclaimable_bytes(), 0);
+        assert_eq!(
+            storage.list_image_manifests(&location).await.unwrap(),
+            vec![digest]
+        );
+
+        let report = GarbageCollector::new()
+            .grace_period(Duration::ZERO)
+            .run(&storage)
+            .await
+            .unwrap();
+        assert_eq!(report.manifests().len(), 1);
+        assert!(storage
+            .list_image_manifests(&location)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn removes_unlinked_manifests() {
+        let dir = tempdir::TempDir::new("container-registry-gc-unlinked").unwrap();
+        let storage = FilesystemStorage::new(dir.path()).unwrap();
+        let location = ImageLocation::new("gc/unlinked").unwrap();
+        let long_ago = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+
+        let manifest = |name: &str| {
+            serde_json::json!({
+                "schemaVersion": 2,
+                "mediaType": OCI_IMAGE_MANIFEST,
+                "config": {
+                    "mediaType": "application/vnd.oci.empty.v1+json",
+                    "size": 2,
+                    "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+                },
+                "layers": [],
+                "annotations": {"org.example.name": name},
+            })
+            .to_string()
+        };
+
+        let tagged = storage
+            .put_manifest(
+                &ManifestReference::new(location.clone(), Reference::new_tag("latest")),
+                manifest("tagged").as_bytes(),
+                OCI_IMAGE_MANIFEST,
+                true,
+            )
+            .await
+            .unwrap();
+        set_modified(
+            &dir.path().join("manifests").join(tagged.to_string()),
+            long_ago,
+        );
+
+        // Older versions left the previous manifest behind unlinked when overwriting a tag.
+        let unlinked_json = manifest("unlinked");
+        let unlinked = Digest::from_contents(unlinked_json.as_bytes());
+        let unlinked_path = dir.path().join("manifests").join(unlinked.to_string());
+        let media_type_path = dir
+            .path()
+            .join("manifests")
+            .join(format!("{unlinked}.media-type"));
+        std::fs::write(&unlinked_path, &unlinked_json).unwrap();
+        std::fs::write(&media_type_path, OCI_IMAGE_MANIFEST).unwrap();
+
+        let report = GarbageCollector::new().run(&storage).await.unwrap();
+        assert!(report.unlinked_manifests().is_empty());
+
+        set_modified(&unlinked_path, long_ago);
+        let report = GarbageCollector::new()
+            .dry_run(true)
+            .run(&storage)
+            .await
+            .unwrap();
+        assert_eq!(report.unlinked_manifests(), [unlinked]);
+        assert_eq!(report.reclaimable_bytes(), unlinked_json.len() as u64);
+        assert!(unlinked_path.exists());
+
+        let report = GarbageCollector::new().run(&storage).await.unwrap();
+        assert_eq!(report.unlinked_manifests(), [unlinked]);
+        assert!(report.manifests().is_empty());
+        assert!(!unlinked_path.exists());
+        assert!(!media_type_path.exists());
+        assert_eq!(storage.list_manifests().await.unwrap(), vec![tagged]);
+    }
+
+    /// Stores an image untagged long ago, to be pushed again as `latest` during collection.
+    ///
+    /// Returns the tag, the manifest and the digest of its config blob.
+    async fn setup_repush(
+        storage: &RepushingStorage,
+        dir: &Path,
+    ) -> (ManifestReference, Vec<u8>, Digest) {
+        let location = ImageLocation::new("gc/repush").unwrap();
+        let latest = ManifestReference::new(location.clone(), Reference::new_tag("latest"));
+
+        let config = b"{}";
+        let upload = storage.begin_new_upload().await.unwrap();
+        let mut writer = storage.get_upload_writer(0, upload).await.unwrap();
+        writer.write_all(config).await.unwrap();
+        writer.shutdown().await.unwrap();
+        let config_digest = Digest::from_contents(config);
+        storage
+            .finalize_upload(upload, config_digest)
+            .await
+            .unwrap();
+
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_MANIFEST,
+            "config": {
+                "mediaType": "application/vnd.oci.empty.v1+json",
+                "size": config.len(),
+                "digest": format!("sha256:{config_digest}"),
+            },
+            "layers": [],
+        })
+        .to_string()
+        .into_bytes();
+        let digest = storage
+            .put_manifest(&latest, &manifest, OCI_IMAGE_MANIFEST, true)
+            .await
+            .unwrap();
+
+        storage.delete_tag(&location, "latest").await.unwrap();
+        let long_ago = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        set_modified(&dir.join("blobs").join(config_digest.to_string()), long_ago);
+        set_modified(&dir.join("manifests").join(digest.to_string()), long_ago);
+        *storage.repush.lock().unwrap() = Some((latest.clone(), manifest.clone()));
+
+        (latest, manifest, config_digest)
+    }
+
+    #[tokio::test]
+    async fn keeps_content_pushed_again_during_collection() {
+        let dir = tempdir::TempDir::new("container-registry-gc-repush").unwrap();
+        let storage = RepushingStorage::new(dir.path(), false);
+
+        // The image is pushed again right after it has been swept.
+        let (latest, manifest, config_digest) = setup_repush(&storage, dir.path()).await;
+
+        let report = GarbageCollector::new().run(&storage).await.unwrap();
+        assert_eq!(report.manifests().len(), 1);
+        assert!(report.blobs().is_empty());
+
+        assert_eq!(storage.get_manifest(&latest).await.unwrap(), Some(manifest));
+        assert!(storage
+            .get_blob_metadata(config_digest)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn keeps_manifests_tagged_during_collection() {
+        let dir = tempdir::TempDir::new("container-registry-gc-retag").unwrap();
+        let storage = RepushingStorage::new(dir.path(), true);
+
+        // The image is tagged again right before it would have been swept.
+        let (latest, manifest, config_digest) = setup_repush(&storage, dir.path()).await;
+
+        let report = GarbageCollector::new().run(&storage).await.unwrap();
+        assert!(report.manifests().is_empty());
+        assert!(report.blobs().is_empty());
+
+        assert_eq!(storage.get_manifest(&latest).await.unwrap(), Some(manifest));
+        assert!(storage
+            .get_blob_metadata(config_digest)
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
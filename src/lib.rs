@@ -40,7 +40,10 @@
 //! Afterwards, `app` can be launched via [`axum::serve()`], see its documentation for details.
 
 pub mod auth;
+pub mod gc;
 pub mod hooks;
+pub mod policy;
+mod route;
 pub mod storage;
 #[cfg(any(feature = "test-support", test))]
 pub mod test_support;
@@ -50,43 +53,52 @@ mod types;
 mod www_authenticate;
 
 use std::{
+    collections::HashSet,
     fmt::{self, Display},
     io,
     path::PathBuf,
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, Weak},
+    time::Duration,
 };
 
 use self::{
     auth::ValidCredentials,
+    gc::{GarbageCollectionReport, GarbageCollector},
+    route::RegistryRoute,
     storage::{FilesystemStorage, ImageLocation, RegistryStorage},
-    types::{ImageManifest, OciError, OciErrors},
+    types::{Catalog, ImageIndex, Manifest, OciError, OciErrors, TagList},
 };
 use auth::{MissingPermission, Permissions};
 use axum::{
-    body::Body,
-    extract::{Path, Query, State},
+    body::{Body, Bytes},
+    extract::{Extension, Path, Query, Request, State},
+    handler::Handler,
     http::{
-        header::{CONTENT_LENGTH, CONTENT_TYPE, LOCATION, RANGE},
-        StatusCode,
+        header::{
+            ACCEPT, ACCEPT_RANGES, ALLOW, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LINK,
+            LOCATION, RANGE, WWW_AUTHENTICATE,
+        },
+        HeaderMap, HeaderValue, Method, StatusCode,
     },
     response::{IntoResponse, Response},
-    routing::{get, head, patch, post, put},
-    Router,
+    routing::{any, get},
+    Json, Router,
 };
 use futures::stream::StreamExt;
 use hex::FromHex;
 use serde::{Deserialize, Deserializer, Serialize};
 use storage::Reference;
 use thiserror::Error;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio_util::io::ReaderStream;
-use tracing::info;
+use tracing::{info, warn};
 use uuid::Uuid;
 
 pub(crate) use {
     auth::{AuthProvider, Unverified},
     hooks::RegistryHooks,
+    policy::ImmutableTags,
     storage::{FilesystemStorageError, ManifestReference},
 };
 
@@ -96,9 +108,54 @@ pub(crate) use {
 /// returned straight to the user without security concerns.
 #[derive(Debug, Error)]
 pub enum RegistryError {
-    /// A requested item (eg. manifest, blob, etc.) was not found.
+    /// The requested blob is not known to the registry.
     #[error("missing item")]
     NotFound,
+    /// The requested manifest is not known to the registry.
+    #[error("manifest unknown")]
+    ManifestUnknown,
+    /// The requested upload is not known to the registry.
+    #[error("blob upload unknown")]
+    BlobUploadUnknown,
+    /// The given image name is invalid.
+    #[error("invalid name")]
+    NameInvalid(#[from] storage::ImageLocationParseError),
+    /// The given digest is invalid.
+    #[error("invalid digest")]
+    DigestInvalid(#[from] ImageDigestParseError),
+    /// The given manifest reference is neither a valid tag nor a valid digest.
+    #[error("invalid reference")]
+    ReferenceInvalid(#[from] storage::ReferenceParseError),
+    /// Submitted content did not match the digest it was submitted under.
+    #[error("digest mismatch")]
+    DigestMismatch,
+    /// The `Content-Type` of a pushed manifest does not match its contents.
+    #[error("content type {content_type} does not match manifest media type {media_type}")]
+    ManifestMediaTypeMismatch {
+        content_type: String,
+        media_type: String,
+    },
+    /// The stored manifest's media type is not acceptable to the client and cannot be converted.
+    #[error("manifest media type {0} not acceptable")]
+    ManifestNotAcceptable(String),
+    /// A pushed manifest references blobs or manifests that are missing or differ in size.
+    #[error("manifest references unknown blobs")]
+    ManifestBlobUnknown(Vec<String>),
+    /// The requested path does not correspond to any endpoint.
+    #[error("unknown endpoint")]
+    UnknownEndpoint,
+    /// The requested endpoint does not support the request's method.
+    #[error("method not allowed, expected one of {0}")]
+    MethodNotAllowed(&'static str),
+    /// The requested repository/image name is not known to the registry.
+    #[error("name unknown")]
+    NameUnknown,
+    /// An immutable tag would have been changed or removed.
+    #[error("tag {0} is immutable")]
+    TagImmutable(String),
+    /// A blob could not be deleted, because it is still referenced by a manifest.
+    #[error("blob is still referenced")]
+    BlobReferenced,
     /// Access to a resource was denied.
     #[error("permission denied")]
     PermissionDenied(#[from] MissingPermission),
@@ -110,14 +167,35 @@ pub enum RegistryError {
     #[error("could not parse manifest")]
     ParseManifest(serde_json::Error),
     /// A requested/required feature was not supported by this registry.
-    #[error("feature not supported: {0}")]
-    NotSupported(&'static str),
+    #[error("feature not supported: {feature}")]
+    NotSupported {
+        /// The unsupported feature.
+        feature: &'static str,
+        /// The methods the endpoint still supports.
+        allow: &'static str,
+    },
+    /// Malformed content range supplied for an upload chunk.
+    #[error("error parsing content range")]
+    ContentRangeMalformed,
+    /// An upload chunk did not start at the current end of the upload.
+    #[error("upload chunk out of order")]
+    RangeNotSatisfiable {
+        /// The location of the image.
+        location: ImageLocation,
+        /// The upload the chunk was meant for.
+        upload: Uuid,
+        /// The total amount of bytes uploaded so far.
+        completed: u64,
+    },
     /// Invalid integer supplied for content length.
     #[error("error parsing content length")]
     ContentLengthMalformed(#[source] Box<dyn std::error::Error + Send + Sync>),
     /// Incoming stream read error.
     #[error("failed to read incoming data stream")]
     IncomingReadFailed(#[source] axum::Error),
+    /// Failed to read local data from storage.
+    #[error("local read failed")]
+    LocalReadFailed(#[source] io::Error),
     /// Failed to write local data to storage.
     #[error("local write failed")]
     LocalWriteFailed(#[source] io::Error),
@@ -130,52 +208,125 @@ pub enum RegistryError {
 impl IntoResponse for RegistryError {
     #[inline(always)]
     fn into_response(self) -> Response {
-        match self {
-            // TODO: Need better OciError handling here. Not everything is blob unknown.
-            RegistryError::NotFound => (
+        use types::ErrorCode;
+
+        let (status, error) = match self {
+            RegistryError::NotFound => {
+                (StatusCode::NOT_FOUND, OciError::new(ErrorCode::BlobUnknown))
+            }
+            RegistryError::ManifestUnknown => (
                 StatusCode::NOT_FOUND,
-                OciErrors::single(OciError::new(types::ErrorCode::BlobUnknown)),
-            )
-                .into_response(),
-            RegistryError::PermissionDenied(_) => (
+                OciError::new(ErrorCode::ManifestUnknown),
+            ),
+            RegistryError::BlobUploadUnknown => (
+                StatusCode::NOT_FOUND,
+                OciError::new(ErrorCode::BlobUploadUnknown),
+            ),
+            RegistryError::NameInvalid(err) => (
+                StatusCode::BAD_REQUEST,
+                OciError::new(ErrorCode::NameInvalid).with_detail(err.to_string()),
+            ),
+            RegistryError::ReferenceInvalid(err) => (
+                StatusCode::BAD_REQUEST,
+                OciError::new(ErrorCode::TagInvalid).with_detail(err.to_string()),
+            ),
+            RegistryError::DigestInvalid(_) | RegistryError::DigestMismatch => (
+                StatusCode::BAD_REQUEST,
+                OciError::new(ErrorCode::DigestInvalid),
+            ),
+            err @ RegistryError::ManifestMediaTypeMismatch { .. } => (
+                StatusCode::BAD_REQUEST,
+                OciError::new(ErrorCode::ManifestInvalid).with_detail(err.to_string()),
+            ),
+            RegistryError::ManifestNotAcceptable(media_type) => (
+                StatusCode::NOT_ACCEPTABLE,
+                OciError::new(ErrorCode::ManifestUnknown).with_detail(media_type),
+            ),
+            RegistryError::ManifestBlobUnknown(digests) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    digests
+                        .into_iter()
+                        .map(|digest| {
+                            OciError::new(ErrorCode::ManifestBlobUnknown).with_detail(digest)
+                        })
+                        .collect::<OciErrors>(),
+                )
+                    .into_response()
+            }
+            RegistryError::UnknownEndpoint => {
+                (StatusCode::NOT_FOUND, OciError::new(ErrorCode::Unsupported))
+            }
+            RegistryError::MethodNotAllowed(allowed) => {
+                return (
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    [(ALLOW, allowed)],
+                    OciErrors::single(OciError::new(ErrorCode::Unsupported)),
+                )
+                    .into_response()
+            }
+            RegistryError::NameUnknown => {
+                (StatusCode::NOT_FOUND, OciError::new(ErrorCode::NameUnknown))
+            }
+            RegistryError::BlobReferenced => {
+                (StatusCode::CONFLICT, OciError::new(ErrorCode::Denied))
+            }
+            err @ RegistryError::TagImmutable(_) => (
                 StatusCode::FORBIDDEN,
-                // TODO: Should this be a proper OCI error?
-                "access to request resource was denied",
-            )
-                .into_response(),
-            RegistryError::Storage(err) => err.into_response(),
+                OciError::new(ErrorCode::Denied).with_detail(err.to_string()),
+            ),
+            RegistryError::PermissionDenied(_) => {
+                (StatusCode::FORBIDDEN, OciError::new(ErrorCode::Denied))
+            }
+            RegistryError::Storage(err) => return err.into_response(),
             RegistryError::ParseManifest(err) => (
                 StatusCode::BAD_REQUEST,
-                format!("could not parse manifest: {}", err),
-            )
-                .into_response(),
-            RegistryError::NotSupported(feature) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("feature not supported: {}", feature),
-            )
-                .into_response(),
+                OciError::new(ErrorCode::ManifestInvalid).with_detail(err.to_string()),
+            ),
+            RegistryError::NotSupported { feature, allow } => {
+                return (
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    [(ALLOW, allow)],
+                    OciErrors::single(OciError::new(ErrorCode::Unsupported).with_detail(feature)),
+                )
+                    .into_response()
+            }
+            RegistryError::ContentRangeMalformed | RegistryError::IncomingReadFailed(_) => (
+                StatusCode::BAD_REQUEST,
+                OciError::new(ErrorCode::BlobUploadInvalid),
+            ),
+            RegistryError::RangeNotSatisfiable {
+                location,
+                upload,
+                completed,
+            } => {
+                // Tells the client where to resume the upload.
+                let state = UploadState {
+                    location,
+                    completed: Some(completed),
+                    upload,
+                };
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    state.headers(),
+                    OciErrors::single(OciError::new(ErrorCode::BlobUploadInvalid)),
+                )
+                    .into_response();
+            }
             RegistryError::ContentLengthMalformed(err) => (
                 StatusCode::BAD_REQUEST,
-                format!("invalid content length value: {}", err),
-            )
-                .into_response(),
-            RegistryError::IncomingReadFailed(_err) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "could not read input stream",
-            )
-                .into_response(),
-            RegistryError::LocalWriteFailed(_err) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "could not write image locally",
-            )
-                .into_response(),
-            RegistryError::AxumHttp(_err) => (
+                OciError::new(ErrorCode::SizeInvalid).with_detail(err.to_string()),
+            ),
+            // Internal errors carry no details, we don't want to leak anything.
+            RegistryError::LocalReadFailed(_)
+            | RegistryError::LocalWriteFailed(_)
+            | RegistryError::AxumHttp(_) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                // Fixed message, we don't want to leak anything. This should never happen anyway.
-                "error building axum HTTP response",
-            )
-                .into_response(),
-        }
+                OciError::new(ErrorCode::Unknown),
+            ),
+        };
+
+        (status, OciErrors::single(error)).into_response()
     }
 }
 
@@ -191,8 +342,17 @@ pub struct ContainerRegistry {
     storage: Box<dyn RegistryStorage>,
     /// A hook consumer for the registry.
     hooks: Box<dyn RegistryHooks>,
+    /// Whether clients may delete manifests, tags and blobs.
+    allow_deletion: bool,
+    /// Whether to refuse deleting blobs still referenced by a manifest of the same image.
+    protect_referenced_blobs: bool,
+    /// Tags that may not be changed once pushed.
+    immutable_tags: ImmutableTags,
 }
 
+/// Minimum interval between two sweeps for abandoned uploads.
+const MIN_UPLOAD_SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
 impl ContainerRegistry {
     /// Creates a new builder for a [`ContainerRegistry`].
     ///
@@ -208,48 +368,405 @@ impl ContainerRegistry {
     pub fn make_router(self: Arc<ContainerRegistry>) -> Router {
         Router::new()
             .route("/v2/", get(index_v2))
-            .route("/v2/:repository/:image/blobs/:digest", head(blob_check))
-            .route("/v2/:repository/:image/blobs/:digest", get(blob_get))
-            .route("/v2/:repository/:image/blobs/uploads/", post(upload_new))
-            .route(
-                "/v2/:repository/:image/uploads/:upload",
-                patch(upload_add_chunk),
-            )
-            .route(
-                "/v2/:repository/:image/uploads/:upload",
-                put(upload_finalize),
-            )
-            .route(
-                "/v2/:repository/:image/manifests/:reference",
-                put(manifest_put),
-            )
-            .route(
-                "/v2/:repository/:image/manifests/:reference",
-                get(manifest_get),
-            )
+            .route("/v2/_catalog", get(catalog))
+            .route("/v2/*path", any(dispatch))
             .with_state(self)
     }
+
+    /// Removes manifests and blobs no longer reachable from any tag.
+    ///
+    /// Safe to call while the registry is serving requests, see [`gc`] for details.
+    pub async fn collect_garbage(
+        &self,
+        collector: &GarbageCollector,
+    ) -> Result<GarbageCollectionReport, storage::Error> {
+        collector.run(self.storage.as_ref()).await
+    }
+
+    /// Checks whether a blob can be mounted from another repository.
+    ///
+    /// Blobs are shared between all repositories, thus mounting a blob only requires it to exist and
+    /// the client to be allowed to read the source repository.
+    async fn can_mount_blob(
+        &self,
+        creds: &ValidCredentials,
+        blob: ImageDigest,
+        from: &str,
+    ) -> Result<bool, RegistryError> {
+        // Invalid sources are treated like any other blob that cannot be mounted.
+        let Ok(source) = ImageLocation::new(from) else {
+            return Ok(false);
+        };
+
+        if !self
+            .auth_provider
+            .image_permissions(creds, &source)
+            .await
+            .has_read_permission()
+        {
+            return Ok(false);
+        }
+
+        Ok(self.storage.get_blob_metadata(blob.digest).await?.is_some())
+    }
+
+    /// Appends the body of a request to an upload.
+    ///
+    /// If the request carries a `Content-Range` header, it must start exactly at the end of the
+    /// data uploaded so far, otherwise the data is appended. A body not matching the length of the
+    /// range is discarded. Returns the total size of the upload afterwards.
+    async fn append_to_upload(
+        &self,
+        location: &ImageLocation,
+        upload: Uuid,
+        request: axum::extract::Request,
+    ) -> Result<u64, RegistryError> {
+        let current_size = self
+            .storage
+            .get_upload_size(upload)
+            .await?
+            .ok_or(RegistryError::BlobUploadUnknown)?;
+
+        let expected_len = match request.headers().get(CONTENT_RANGE) {
+            Some(value) => {
+                let (start, end) = parse_content_range(value)?;
+                if start != current_size {
+                    return Err(RegistryError::RangeNotSatisfiable {
+                        location: location.clone(),
+                        upload,
+                        completed: current_size,
+                    });
+                }
+
+                let range_len = end - start + 1;
+                if let Some(content_length) = request.headers().get(CONTENT_LENGTH) {
+                    if parse_content_length(content_length)? != range_len {
+                        return Err(RegistryError::ContentRangeMalformed);
+                    }
+                }
+
+                Some(range_len)
+            }
+            None => None,
+        };
+
+        let mut writer = self.storage.get_upload_writer(current_size, upload).await?;
+
+        let mut body = request.into_body().into_data_stream();
+
+        let mut written: u64 = 0;
+        while let Some(result) = body.next().await {
+            let chunk = result.map_err(RegistryError::IncomingReadFailed)?;
+            written += chunk.len() as u64;
+            if expected_len.is_some_and(|expected_len| written > expected_len) {
+                break;
+            }
+            writer
+                .write_all(chunk.as_ref())
+                .await
+                .map_err(RegistryError::LocalWriteFailed)?;
+        }
+
+        writer
+            .flush()
+            .await
+            .map_err(RegistryError::LocalWriteFailed)?;
+
+        if expected_len.is_some_and(|expected_len| expected_len != written) {
+            // Drop the partial chunk, so the client can retry it.
+            drop(writer);
+            self.storage
+                .get_upload_writer(current_size, upload)
+                .await?
+                .shutdown()
+                .await
+                .map_err(RegistryError::LocalWriteFailed)?;
+
+            return Err(RegistryError::ContentRangeMalformed);
+        }
+
+        Ok(current_size + written)
+    }
+
+    /// Retrieves a manifest, along with the response headers describing it.
+    ///
+    /// Shared implementation of `GET` and `HEAD` requests for manifests. If the client does not
+    /// accept the stored manifest's media type, it is converted between the Docker and OCI formats
+    /// if possible.
+    async fn manifest_response(
+        &self,
+        manifest_reference: &ManifestReference,
+        creds: &ValidCredentials,
+        headers: &HeaderMap,
+    ) -> Result<(axum::http::response::Builder, Vec<u8>), RegistryError> {
+        self.auth_provider
+            .image_permissions(creds, manifest_reference.location())
+            .await
+            .require_read()?;
+
+        let manifest_json = self
+            .storage
+            .get_manifest(manifest_reference)
+            .await?
+            .ok_or(RegistryError::ManifestUnknown)?;
+
+        let manifest =
+            Manifest::from_slice(&manifest_json).map_err(RegistryError::ParseManifest)?;
+
+        let stored_media_type = self
+            .storage
+            .get_manifest_media_type(storage::Digest::from_contents(&manifest_json))
+            .await?
+            .unwrap_or_else(|| manifest.media_type().to_owned());
+
+        let (media_type, manifest_json) = match accepted_media_types(headers) {
+            Some(accepted) if !accepted.contains(&stored_media_type) => {
+                // Conversion changes the digest, so it is only possible when requested by tag.
+                let converted = match manifest_reference.reference() {
+                    Reference::Tag(_) => accepted.iter().find_map(|media_type| {
+                        types::convert_image_manifest(&manifest_json, media_type)
+                            .map(|converted| (media_type.clone(), converted))
+                    }),
+                    Reference::Digest(_) => None,
+                };
+
+                converted.ok_or(RegistryError::ManifestNotAcceptable(stored_media_type))?
+            }
+            _ => (stored_media_type, manifest_json),
+        };
+
+        let digest = ImageDigest::new(storage::Digest::from_contents(&manifest_json));
+
+        let builder = Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_LENGTH, manifest_json.len())
+            .header(CONTENT_TYPE, media_type)
+            .header("Docker-Content-Digest", digest.to_string());
+
+        Ok((builder, manifest_json))
+    }
+
+    /// Returns the digest of the manifest a tag points to, if the tag exists.
+    async fn tag_digest(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+    ) -> Result<Option<storage::Digest>, RegistryError> {
+        let manifest_reference = ManifestReference::new(location.clone(), Reference::new_tag(tag));
+
+        Ok(self
+            .storage
+            .get_manifest(&manifest_reference)
+            .await?
+            .map(|manifest_json| storage::Digest::from_contents(&manifest_json)))
+    }
+
+    /// Checks that a tag may be pointed to the given manifest, or removed if `None`.
+    ///
+    /// Immutable tags may only be created, or "changed" to the manifest they already point to.
+    async fn check_tag_mutable(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+        new_digest: Option<storage::Digest>,
+    ) -> Result<(), RegistryError> {
+        if !self.immutable_tags.is_immutable(location, tag) {
+            return Ok(());
+        }
+
+        match self.tag_digest(location, tag).await? {
+            Some(current) if Some(current) != new_digest => {
+                Err(RegistryError::TagImmutable(tag.to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns a response asking the client to supply valid credentials.
+    fn unauthorized_response(&self) -> Response {
+        let mut response = (
+            StatusCode::UNAUTHORIZED,
+            OciErrors::single(OciError::new(types::ErrorCode::Unauthorized)),
+        )
+            .into_response();
+        response.headers_mut().insert(
+            WWW_AUTHENTICATE,
+            format!("Basic realm=\"{}\"", self.realm)
+                .parse()
+                .expect("realm should be a valid header value"),
+        );
+        response
+    }
+
+    /// Returns the digests of all content referenced by a manifest that is missing from storage.
+    ///
+    /// Content stored with a size different from the one declared in the manifest counts as
+    /// missing.
+    async fn missing_references(&self, manifest: &Manifest) -> Result<Vec<String>, RegistryError> {
+        let mut missing = Vec::new();
+
+        for descriptor in manifest.blobs() {
+            // Clients never push these, they are downloaded from their original location.
+            if descriptor.is_external() {
+                continue;
+            }
+
+            let size = match descriptor.digest().parse::<ImageDigest>() {
+                Ok(blob) => self
+                    .storage
+                    .get_blob_metadata(blob.digest)
+                    .await?
+                    .map(|metadata| metadata.size()),
+                Err(_) => None,
+            };
+
+            if size != Some(descriptor.size()) {
+                missing.push(descriptor.digest().to_owned());
+            }
+        }
+
+        for descriptor in manifest.manifests() {
+            let size = match descriptor.digest().parse::<ImageDigest>() {
+                Ok(child) => self
+                    .storage
+                    .get_manifest_metadata(child.digest)
+                    .await?
+                    .map(|metadata| metadata.size()),
+                Err(_) => None,
+            };
+
+            if size != Some(descriptor.size()) {
+                missing.push(descriptor.digest().to_owned());
+            }
+        }
+
+        Ok(missing)
+    }
+
+    /// Checks whether any stored manifest references the given blob.
+    ///
+    /// Manifests linked to no image other than the one at `location` are only considered if
+    /// `include_own` is set.
+    async fn is_blob_referenced(
+        &self,
+        location: &ImageLocation,
+        blob: &ImageDigest,
+        include_own: bool,
+    ) -> Result<bool, RegistryError> {
+        let blob = blob.to_string();
+
+        let mut own_only = HashSet::new();
+        if !include_own {
+            own_only.extend(self.storage.list_image_manifests(location).await?);
+            for other in self.storage.list_repositories().await? {
+                if &other != location {
+                    for digest in self.storage.list_image_manifests(&other).await? {
+                        own_only.remove(&digest);
+                    }
+                }
+            }
+        }
+
+        for digest in self.storage.list_manifests().await? {
+            if own_only.contains(&digest) {
+                continue;
+            }
+
+            // Manifests are not tied to a location when retrieved by digest.
+            let manifest_reference =
+                ManifestReference::new(location.clone(), Reference::new_digest(digest));
+            let Some(manifest_json) = self.storage.get_manifest(&manifest_reference).await? else {
+                // Removed concurrently.
+                continue;
+            };
+
+            // A broken manifest elsewhere in storage is no fault of the request.
+            let manifest = match Manifest::from_slice(&manifest_json) {
+                Ok(manifest) => manifest,
+                Err(err) => {
+                    warn!(%digest, %err, "skipping unparsable manifest");
+                    continue;
+                }
+            };
+            if manifest
+                .blobs()
+                .any(|descriptor| descriptor.digest() == blob)
+            {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
 }
 
 /// Builder for a new instance of the container registry.
 ///
-/// Requires a storage to be set, either by calling [`Self::storage`] or constructing using
-/// [`Self::build_for_testing()`], which requires the `test-support` feature and will use
-/// a temporary directory.
+/// Requires a storage to be set, either by calling [`Self::storage`] or [`Self::storage_backend`],
+/// or constructing using [`Self::build_for_testing()`], which requires the `test-support` feature
+/// and will use a temporary directory.
 ///
 /// By default, no hooks are set up and the auth provider requires authentication, but does not
-/// grant access to anything.
+/// grant access to anything. Clients with write access may delete manifests, tags and blobs not
+/// referenced by any manifest.
 #[derive(Default)]
 pub struct ContainerRegistryBuilder {
     /// Storage to use.
     storage: Option<PathBuf>,
+    /// Custom storage backend to use instead of the storage path.
+    storage_backend: Option<Box<dyn RegistryStorage>>,
     /// Hooks to use.
     hooks: Option<Box<dyn RegistryHooks>>,
     /// Auth provider to use.
     auth_provider: Option<Arc<dyn AuthProvider>>,
+    /// Whether to allow deletion.
+    allow_deletion: Option<bool>,
+    /// Whether to protect referenced blobs from deletion.
+    protect_referenced_blobs: Option<bool>,
+    /// Time after which inactive uploads are removed.
+    upload_ttl: Option<Duration>,
+    /// Tags that may not be changed once pushed.
+    immutable_tags: Option<ImmutableTags>,
 }
 
 impl ContainerRegistryBuilder {
+    /// Sets whether clients with write access may delete manifests, tags and blobs.
+    ///
+    /// If disabled, deletion requests are answered with an `UNSUPPORTED` error.
+    pub fn allow_deletion(mut self, allow_deletion: bool) -> Self {
+        self.allow_deletion = Some(allow_deletion);
+        self
+    }
+
+    /// Sets whether blobs still referenced by a manifest of the same image are protected from
+    /// deletion, enabled by default.
+    ///
+    /// Blobs are shared between all images, thus those referenced by a manifest of any other image
+    /// are always protected. Checking requires scanning all stored manifests on every blob
+    /// deletion.
+    pub fn protect_referenced_blobs(mut self, protect_referenced_blobs: bool) -> Self {
+        self.protect_referenced_blobs = Some(protect_referenced_blobs);
+        self
+    }
+
+    /// Sets the time after which an upload session without any new data is considered abandoned.
+    ///
+    /// If set, a background task periodically removes abandoned uploads, any further requests
+    /// for them will fail with a `BLOB_UPLOAD_UNKNOWN` error. By default, uploads never expire.
+    pub fn upload_ttl(mut self, upload_ttl: Duration) -> Self {
+        self.upload_ttl = Some(upload_ttl);
+        self
+    }
+
+    /// Sets the policy for tags that cannot be changed once pushed.
+    ///
+    /// Pushing a different manifest to an immutable tag, or deleting it, is denied. By default, all
+    /// tags are mutable.
+    pub fn immutable_tags(mut self, immutable_tags: ImmutableTags) -> Self {
+        self.immutable_tags = Some(immutable_tags);
+        self
+    }
+
     /// Sets the auth provider for the new registry.
     pub fn auth_provider(mut self, auth_provider: Arc<dyn AuthProvider>) -> Self {
         self.auth_provider = Some(auth_provider);
@@ -271,27 +788,147 @@ impl ContainerRegistryBuilder {
         self
     }
 
+    /// Sets a custom storage backend for the new registry.
+    ///
+    /// Takes precedence over a storage path set through [`Self::storage`].
+    pub fn storage_backend(mut self, storage_backend: Box<dyn RegistryStorage>) -> Self {
+        self.storage_backend = Some(storage_backend);
+        self
+    }
+
     /// Constructs a new registry.
     ///
     /// # Panics
     ///
-    /// Will panic if not storage has been set through [`Self::storage`].
+    /// Will panic if no storage has been set through [`Self::storage`] or
+    /// [`Self::storage_backend`], or if an upload TTL has been set through [`Self::upload_ttl`] and
+    /// this function is not called from within a Tokio runtime.
     pub fn build(mut self) -> Result<Arc<ContainerRegistry>, FilesystemStorageError> {
-        let storage_path = self
-            .storage
-            .expect("attempted to construct registry with no storage path");
-        let storage = Box::new(FilesystemStorage::new(storage_path)?);
+        let storage = match self.storage_backend.take() {
+            Some(storage_backend) => storage_backend,
+            None => {
+                let storage_path = self
+                    .storage
+                    .take()
+                    .expect("attempted to construct registry with no storage path");
+                Box::new(FilesystemStorage::new(storage_path)?)
+            }
+        };
         let auth_provider = self
             .auth_provider
             .take()
             .unwrap_or_else(|| Arc::new(Permissions::NoAccess));
         let hooks = self.hooks.take().unwrap_or_else(|| Box::new(()));
-        Ok(Arc::new(ContainerRegistry {
+        let registry = Arc::new(ContainerRegistry {
             realm: "ContainerRegistry".to_string(),
             auth_provider,
             storage,
             hooks,
-        }))
+            allow_deletion: self.allow_deletion.unwrap_or(true),
+            protect_referenced_blobs: self.protect_referenced_blobs.unwrap_or(true),
+            immutable_tags: self.immutable_tags.take().unwrap_or_default(),
+        });
+
+        if let Some(upload_ttl) = self.upload_ttl {
+            tokio::spawn(sweep_uploads(Arc::downgrade(&registry), upload_ttl));
+        }
+
+        Ok(registry)
+    }
+}
+
+/// Periodically removes abandoned uploads, until the registry is dropped.
+async fn sweep_uploads(registry: Weak<ContainerRegistry>, upload_ttl: Duration) {
+    let mut interval = tokio::time::interval((upload_ttl / 4).max(MIN_UPLOAD_SWEEP_INTERVAL));
+
+    loop {
+        interval.tick().await;
+
+        let Some(registry) = registry.upgrade() else {
+            break;
+        };
+
+        match registry.storage.expire_uploads(upload_ttl).await {
+            Ok(expired) => {
+                for upload in expired {
+                    info!(%upload, "removed abandoned upload");
+                }
+            }
+            Err(err) => warn!(%err, "failed to remove abandoned uploads"),
+        }
+    }
+}
+
+/// Dispatches a request to the handler of the endpoint given by its path.
+///
+/// Parameters parsed from the path are passed on to the handler as request extensions.
+async fn dispatch(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Path(path): Path<String>,
+    mut request: Request,
+) -> Response {
+    let route = match path.parse::<RegistryRoute>() {
+        Ok(route) => route,
+        Err(err) => return err.into_response(),
+    };
+
+    let method = request.method().clone();
+    let extensions = request.extensions_mut();
+
+    match route {
+        RegistryRoute::Blob { location, digest } => {
+            extensions.insert(location);
+            extensions.insert(digest);
+            match method {
+                Method::HEAD => blob_check.call(request, registry).await,
+                Method::GET => blob_get.call(request, registry).await,
+                Method::DELETE => blob_delete.call(request, registry).await,
+                _ => RegistryError::MethodNotAllowed("GET, HEAD, DELETE").into_response(),
+            }
+        }
+        RegistryRoute::UploadNew { location } => {
+            extensions.insert(location);
+            match method {
+                Method::POST => upload_new.call(request, registry).await,
+                _ => RegistryError::MethodNotAllowed("POST").into_response(),
+            }
+        }
+        RegistryRoute::Upload { location, upload } => {
+            extensions.insert(location);
+            extensions.insert(upload);
+            match method {
+                Method::GET => upload_status.call(request, registry).await,
+                Method::PATCH => upload_add_chunk.call(request, registry).await,
+                Method::PUT => upload_finalize.call(request, registry).await,
+                Method::DELETE => upload_cancel.call(request, registry).await,
+                _ => RegistryError::MethodNotAllowed("GET, PATCH, PUT, DELETE").into_response(),
+            }
+        }
+        RegistryRoute::Manifest(manifest_reference) => {
+            extensions.insert(manifest_reference);
+            match method {
+                Method::HEAD => manifest_head.call(request, registry).await,
+                Method::GET => manifest_get.call(request, registry).await,
+                Method::PUT => manifest_put.call(request, registry).await,
+                Method::DELETE => manifest_delete.call(request, registry).await,
+                _ => RegistryError::MethodNotAllowed("GET, HEAD, PUT, DELETE").into_response(),
+            }
+        }
+        RegistryRoute::TagsList { location } => {
+            extensions.insert(location);
+            match method {
+                Method::GET => tags_list.call(request, registry).await,
+                _ => RegistryError::MethodNotAllowed("GET").into_response(),
+            }
+        }
+        RegistryRoute::Referrers { location, digest } => {
+            extensions.insert(location);
+            extensions.insert(digest);
+            match method {
+                Method::GET => referrers_list.call(request, registry).await,
+                _ => RegistryError::MethodNotAllowed("GET").into_response(),
+            }
+        }
     }
 }
 
@@ -321,17 +958,13 @@ async fn index_v2(
     }
 
     // Return `UNAUTHORIZED`, since we want the client to supply credentials.
-    Response::builder()
-        .status(StatusCode::UNAUTHORIZED)
-        .header("WWW-Authenticate", format!("Basic realm=\"{realm}\""))
-        .body(Body::empty())
-        .unwrap()
+    registry.unauthorized_response()
 }
 
 /// Returns metadata of a specific image blob.
 async fn blob_check(
     State(registry): State<Arc<ContainerRegistry>>,
-    Path((_, _, image)): Path<(String, String, ImageDigest)>,
+    Extension(image): Extension<ImageDigest>,
     creds: ValidCredentials,
 ) -> Result<Response, RegistryError> {
     registry
@@ -346,6 +979,7 @@ async fn blob_check(
             .header(CONTENT_LENGTH, metadata.size())
             .header("Docker-Content-Digest", image.to_string())
             .header(CONTENT_TYPE, "application/octet-stream")
+            .header(ACCEPT_RANGES, "bytes")
             .body(Body::empty())
             .unwrap())
     } else {
@@ -356,11 +990,50 @@ async fn blob_check(
     }
 }
 
+/// Parses a `Range` header of a blob download.
+///
+/// Only a single range in bytes is supported. Returns the inclusive range of bytes to send, or
+/// `None` if the range is malformed or cannot be satisfied for a blob of the given size.
+fn parse_range(value: &HeaderValue, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = value
+        .to_str()
+        .ok()?
+        .trim()
+        .strip_prefix("bytes=")?
+        .split_once('-')?;
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        // Suffix range, i.e. the last `n` bytes.
+        ("", suffix_len) => {
+            let suffix_len: u64 = suffix_len.parse().ok()?;
+            if suffix_len == 0 {
+                return None;
+            }
+            (size.saturating_sub(suffix_len), size.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, size.checked_sub(1)?),
+        (start, end) => {
+            let end: u64 = end.parse().ok()?;
+            (start.parse().ok()?, end.min(size.checked_sub(1)?))
+        }
+    };
+
+    if start > end {
+        return None;
+    }
+
+    Some((start, end))
+}
+
 /// Returns a specific image blob.
+///
+/// Supports retrieving a part of the blob through a `Range` header. If the storage backend offers a
+/// direct download URL, the client is redirected there instead.
 async fn blob_get(
     State(registry): State<Arc<ContainerRegistry>>,
-    Path((_, _, image)): Path<(String, String, ImageDigest)>,
+    Extension(image): Extension<ImageDigest>,
     creds: ValidCredentials,
+    request: Request,
 ) -> Result<Response, RegistryError> {
     registry
         .auth_provider
@@ -368,57 +1041,221 @@ async fn blob_get(
         .await
         .require_read()?;
 
-    // TODO: Get size for `Content-length` header.
+    let size = registry
+        .storage
+        .get_blob_metadata(image.digest)
+        .await?
+        .ok_or(RegistryError::NotFound)?
+        .size();
 
-    let reader = registry
+    if let Some(url) = registry.storage.get_blob_url(image.digest).await? {
+        return Ok(Response::builder()
+            .status(StatusCode::TEMPORARY_REDIRECT)
+            .header(LOCATION, url)
+            .header("Docker-Content-Digest", image.to_string())
+            .header(CONTENT_LENGTH, 0)
+            .body(Body::empty())?);
+    }
+
+    let range = match request.headers().get(RANGE) {
+        // Neither empty blobs nor multiple ranges are supported, both are served in full instead.
+        Some(value) if size == 0 || value.as_bytes().contains(&b',') => None,
+        Some(value) => match parse_range(value, size) {
+            Some(range) => Some(range),
+            None => {
+                return Ok(Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(CONTENT_RANGE, format!("bytes */{size}"))
+                    .body(Body::empty())?);
+            }
+        },
+        None => None,
+    };
+
+    let mut reader = registry
         .storage
         .get_blob_reader(image.digest)
         .await?
         .ok_or(RegistryError::NotFound)?;
 
-    let stream = ReaderStream::new(reader);
-    let body = Body::from_stream(stream);
+    let builder = Response::builder()
+        .header("Docker-Content-Digest", image.to_string())
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(ACCEPT_RANGES, "bytes");
+
+    let response = match range {
+        Some((start, end)) => {
+            reader
+                .seek(io::SeekFrom::Start(start))
+                .await
+                .map_err(RegistryError::LocalReadFailed)?;
+            let len = end - start + 1;
+
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_LENGTH, len)
+                .header(CONTENT_RANGE, format!("bytes {start}-{end}/{size}"))
+                .body(Body::from_stream(ReaderStream::new(reader.take(len))))
+        }
+        None => builder
+            .status(StatusCode::OK)
+            .header(CONTENT_LENGTH, size)
+            .body(Body::from_stream(ReaderStream::new(reader))),
+    };
+
+    Ok(response.expect("Building a streaming response with body works. qed"))
+}
+
+/// Deletes a blob.
+async fn blob_delete(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Extension(location): Extension<ImageLocation>,
+    Extension(digest): Extension<ImageDigest>,
+    creds: ValidCredentials,
+) -> Result<Response, RegistryError> {
+    registry
+        .auth_provider
+        .image_permissions(&creds, &location)
+        .await
+        .require_write()?;
+
+    if !registry.allow_deletion {
+        return Err(RegistryError::NotSupported {
+            feature: "deletion",
+            allow: "GET, HEAD",
+        });
+    }
+
+    // Blobs are shared, deleting one still in use by another image would break that image.
+    if registry
+        .is_blob_referenced(&location, &digest, registry.protect_referenced_blobs)
+        .await?
+    {
+        return Err(RegistryError::BlobReferenced);
+    }
+
+    if !registry.storage.delete_blob(digest.digest).await? {
+        return Err(RegistryError::NotFound);
+    }
+
+    info!(%digest, "blob deleted");
 
     Ok(Response::builder()
-        .status(StatusCode::OK)
-        .body(body)
-        .expect("Building a streaming response with body works. qed"))
+        .status(StatusCode::ACCEPTED)
+        .header(CONTENT_LENGTH, 0)
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Query parameters when initiating a new blob upload.
+#[derive(Debug, Deserialize)]
+struct UploadNewQuery {
+    /// Digest of the blob, if uploaded monolithically in a single request.
+    digest: Option<ImageDigest>,
+    /// Digest of a blob to mount from another repository.
+    mount: Option<ImageDigest>,
+    /// The repository to mount the blob from.
+    from: Option<String>,
 }
 
 /// Initiates a new blob upload.
+///
+/// If a digest is given, the request body contains the entire blob, which is stored immediately.
+///
+/// If a blob to mount from another repository is given and the client is allowed to read it there,
+/// no upload is necessary. Otherwise a regular upload is started.
 async fn upload_new(
     State(registry): State<Arc<ContainerRegistry>>,
-    Path(location): Path<ImageLocation>,
+    Extension(location): Extension<ImageLocation>,
+    Query(UploadNewQuery {
+        digest,
+        mount,
+        from,
+    }): Query<UploadNewQuery>,
     creds: ValidCredentials,
-) -> Result<UploadState, RegistryError> {
+    request: Request,
+) -> Result<Response, RegistryError> {
     registry
         .auth_provider
         .image_permissions(&creds, &location)
         .await
         .require_write()?;
 
+    if let (Some(mount), Some(from)) = (mount, from) {
+        if registry.can_mount_blob(&creds, mount, &from).await? {
+            info!(%mount, %from, %location, "blob mounted");
+            return mk_blob_created_response(&location, mount);
+        }
+    }
+
     // Initiate a new upload
     let upload = registry.storage.begin_new_upload().await?;
 
-    Ok(UploadState {
-        location,
-        completed: None,
-        upload,
-    })
+    match digest {
+        Some(digest) => {
+            let stored = async {
+                registry
+                    .append_to_upload(&location, upload, request)
+                    .await?;
+                registry
+                    .storage
+                    .finalize_upload(upload, digest.digest)
+                    .await?;
+                Ok(())
+            }
+            .await;
+
+            // The client never learns about the upload, thus cannot clean it up itself.
+            if let Err(err) = stored {
+                if let Err(err) = registry.storage.cancel_upload(upload).await {
+                    warn!(%upload, %err, "failed to remove failed monolithic upload");
+                }
+                return Err(err);
+            }
+
+            info!(%upload, %digest, "new image uploaded monolithically");
+            mk_blob_created_response(&location, digest)
+        }
+        None => Ok(UploadState {
+            location,
+            completed: None,
+            upload,
+        }
+        .into_response()),
+    }
+}
+
+/// Creates the response for a successfully stored blob.
+fn mk_blob_created_response(
+    location: &ImageLocation,
+    digest: ImageDigest,
+) -> Result<Response, RegistryError> {
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header("Docker-Content-Digest", digest.to_string())
+        .header(LOCATION, mk_blob_location(location, digest))
+        .header(CONTENT_LENGTH, 0)
+        .body(Body::empty())?)
+}
+
+/// Returns the URI for a specific blob.
+fn mk_blob_location(location: &ImageLocation, digest: ImageDigest) -> String {
+    format!("/v2/{location}/blobs/{digest}")
 }
 
 /// Returns the URI for a specific part of an upload.
 fn mk_upload_location(location: &ImageLocation, uuid: Uuid) -> String {
-    let repository = &location.repository();
-    let image = &location.image();
-    format!("/v2/{repository}/{image}/uploads/{uuid}")
+    format!("/v2/{location}/blobs/uploads/{uuid}")
 }
 
-/// Returns the URI for a specific part of an upload.
+/// Returns the URI for the tag listing of an image.
+fn mk_tags_list_location(location: &ImageLocation) -> String {
+    format!("/v2/{location}/tags/list")
+}
+
+/// Returns the URI for a specific manifest.
 fn mk_manifest_location(location: &ImageLocation, reference: &Reference) -> String {
-    let repository = &location.repository();
-    let image = &location.image();
-    format!("/v2/{repository}/{image}/manifests/{reference}")
+    format!("/v2/{location}/manifests/{reference}")
 }
 
 /// Image upload state.
@@ -431,31 +1268,49 @@ fn mk_manifest_location(location: &ImageLocation, reference: &Reference) -> Stri
 struct UploadState {
     /// The location of the image.
     location: ImageLocation,
-    /// The amount of bytes completed.
+    /// The total amount of bytes uploaded so far.
     completed: Option<u64>,
     /// The UUID for this specific upload part.
     upload: Uuid,
 }
 
-impl IntoResponse for UploadState {
-    fn into_response(self) -> Response {
-        let mut builder = Response::builder()
-            .header(LOCATION, mk_upload_location(&self.location, self.upload))
-            .header(CONTENT_LENGTH, 0)
-            .header("Docker-Upload-UUID", self.upload.to_string());
+impl UploadState {
+    /// Returns the headers communicating the upload state.
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            LOCATION,
+            HeaderValue::try_from(mk_upload_location(&self.location, self.upload))
+                .expect("upload location should be a valid header value"),
+        );
+        headers.insert(
+            "Docker-Upload-UUID",
+            HeaderValue::try_from(self.upload.to_string())
+                .expect("UUID should be a valid header value"),
+        );
 
         if let Some(completed) = self.completed {
-            builder = builder
-                .header(RANGE, format!("0-{}", completed))
-                .status(StatusCode::ACCEPTED)
-        } else {
-            builder = builder
-                .header(CONTENT_LENGTH, 0)
-                .status(StatusCode::ACCEPTED);
-            // The spec says to use `CREATED`, but only `ACCEPTED` works?
+            // The range is inclusive, an empty upload is reported as `0-0`.
+            headers.insert(
+                RANGE,
+                HeaderValue::try_from(format!("0-{}", completed.saturating_sub(1)))
+                    .expect("range should be a valid header value"),
+            );
         }
 
-        builder.body(Body::empty()).unwrap()
+        headers
+    }
+}
+
+impl IntoResponse for UploadState {
+    fn into_response(self) -> Response {
+        // The spec says to use `CREATED` for new uploads, but only `ACCEPTED` works?
+        (
+            StatusCode::ACCEPTED,
+            self.headers(),
+            [(CONTENT_LENGTH, "0")],
+        )
+            .into_response()
     }
 }
 
@@ -466,11 +1321,10 @@ struct UploadId {
     upload: Uuid,
 }
 
-#[derive(Debug)]
-
 /// An image hash.
 ///
 /// Currently only SHA256 hashes are supported.
+#[derive(Clone, Copy, Debug)]
 pub struct ImageDigest {
     /// The actual image digest.
     digest: storage::Digest,
@@ -558,13 +1412,86 @@ impl Display for ImageDigest {
     }
 }
 
+/// Returns the media types listed in the `Accept` headers of a request.
+///
+/// Returns `None` if the client accepts any media type, either by not sending an `Accept` header or
+/// through a wildcard. Parameters such as quality values are ignored.
+fn accepted_media_types(headers: &HeaderMap) -> Option<Vec<String>> {
+    let mut accepted = Vec::new();
+
+    for value in headers.get_all(ACCEPT) {
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+
+        for media_type in value.split(',') {
+            let media_type = media_type.split(';').next().unwrap_or_default().trim();
+            match media_type {
+                "" => {}
+                "*/*" | "application/*" => return None,
+                _ => accepted.push(media_type.to_owned()),
+            }
+        }
+    }
+
+    if accepted.is_empty() {
+        None
+    } else {
+        Some(accepted)
+    }
+}
+
+/// Parses a `Content-Range` header of an upload chunk.
+///
+/// Returns the inclusive range of bytes contained in the chunk. Besides the `<start>-<end>` form
+/// mandated by the spec, a `bytes` unit prefix and a `/<size>` suffix are tolerated.
+fn parse_content_range(value: &HeaderValue) -> Result<(u64, u64), RegistryError> {
+    let raw = value
+        .to_str()
+        .map_err(|_| RegistryError::ContentRangeMalformed)?
+        .trim();
+    let raw = raw
+        .strip_prefix("bytes")
+        .map(|rest| rest.trim_start_matches([' ', '=']))
+        .unwrap_or(raw);
+    let raw = raw
+        .split_once('/')
+        .map(|(range, _size)| range)
+        .unwrap_or(raw);
+
+    let (start, end) = raw
+        .split_once('-')
+        .ok_or(RegistryError::ContentRangeMalformed)?;
+    let start: u64 = start
+        .parse()
+        .map_err(|_| RegistryError::ContentRangeMalformed)?;
+    let end: u64 = end
+        .parse()
+        .map_err(|_| RegistryError::ContentRangeMalformed)?;
+
+    if end < start {
+        return Err(RegistryError::ContentRangeMalformed);
+    }
+
+    Ok((start, end))
+}
+
+/// Parses a `Content-Length` header.
+fn parse_content_length(value: &HeaderValue) -> Result<u64, RegistryError> {
+    value
+        .to_str()
+        .map_err(|err| RegistryError::ContentLengthMalformed(Box::new(err)))?
+        .parse()
+        .map_err(|err| RegistryError::ContentLengthMalformed(Box::new(err)))
+}
+
 /// Adds a chunk to an existing upload.
 async fn upload_add_chunk(
     State(registry): State<Arc<ContainerRegistry>>,
-    Path(location): Path<ImageLocation>,
-    Path(UploadId { upload }): Path<UploadId>,
+    Extension(location): Extension<ImageLocation>,
+    Extension(UploadId { upload }): Extension<UploadId>,
     creds: ValidCredentials,
-    request: axum::extract::Request,
+    request: Request,
 ) -> Result<UploadState, RegistryError> {
     registry
         .auth_provider
@@ -572,38 +1499,65 @@ async fn upload_add_chunk(
         .await
         .require_write()?;
 
-    // Check if we have a range - if so, its an unsupported feature, namely monolith uploads.
-    if request.headers().contains_key(RANGE) {
-        return Err(RegistryError::NotSupported(
-            "unsupported feature: chunked uploads",
-        ));
-    }
-
-    let mut writer = registry.storage.get_upload_writer(0, upload).await?;
-
-    // We'll get the entire file in one go, no range header == monolithic uploads.
-    let mut body = request.into_body().into_data_stream();
+    let completed = registry
+        .append_to_upload(&location, upload, request)
+        .await?;
 
-    let mut completed: u64 = 0;
-    while let Some(result) = body.next().await {
-        let chunk = result.map_err(RegistryError::IncomingReadFailed)?;
-        completed += chunk.len() as u64;
-        writer
-            .write_all(chunk.as_ref())
-            .await
-            .map_err(RegistryError::LocalWriteFailed)?;
-    }
+    Ok(UploadState {
+        location,
+        completed: Some(completed),
+        upload,
+    })
+}
 
-    writer
-        .flush()
+/// Reports the progress of an upload.
+async fn upload_status(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Extension(location): Extension<ImageLocation>,
+    Extension(UploadId { upload }): Extension<UploadId>,
+    creds: ValidCredentials,
+) -> Result<Response, RegistryError> {
+    registry
+        .auth_provider
+        .image_permissions(&creds, &location)
         .await
-        .map_err(RegistryError::LocalWriteFailed)?;
+        .require_write()?;
 
-    Ok(UploadState {
+    let completed = registry
+        .storage
+        .get_upload_size(upload)
+        .await?
+        .ok_or(RegistryError::BlobUploadUnknown)?;
+
+    let mut response = UploadState {
         location,
         completed: Some(completed),
         upload,
-    })
+    }
+    .into_response();
+    *response.status_mut() = StatusCode::NO_CONTENT;
+
+    Ok(response)
+}
+
+/// Cancels an upload, discarding all data uploaded so far.
+async fn upload_cancel(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Extension(location): Extension<ImageLocation>,
+    Extension(UploadId { upload }): Extension<UploadId>,
+    creds: ValidCredentials,
+) -> Result<StatusCode, RegistryError> {
+    registry
+        .auth_provider
+        .image_permissions(&creds, &location)
+        .await
+        .require_write()?;
+
+    if !registry.storage.cancel_upload(upload).await? {
+        return Err(RegistryError::BlobUploadUnknown);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
 }
 
 /// An image digest on a query string.
@@ -616,41 +1570,25 @@ struct DigestQuery {
 }
 
 /// Finishes an upload.
+///
+/// The request may contain a final chunk of data.
 async fn upload_finalize(
     State(registry): State<Arc<ContainerRegistry>>,
-    Path((repository, image, upload)): Path<(String, String, Uuid)>,
+    Extension(location): Extension<ImageLocation>,
+    Extension(UploadId { upload }): Extension<UploadId>,
     Query(DigestQuery { digest }): Query<DigestQuery>,
     creds: ValidCredentials,
-    request: axum::extract::Request,
+    request: Request,
 ) -> Result<Response<Body>, RegistryError> {
-    let location = ImageLocation::new(repository, image);
-
     registry
         .auth_provider
         .image_permissions(&creds, &location)
         .await
         .require_write()?;
 
-    // We do not support the final chunk in the `PUT` call, so ensure that's not the case.
-    match request.headers().get(CONTENT_LENGTH) {
-        Some(value) => {
-            let num_bytes: u64 = value
-                .to_str()
-                .map_err(|err| RegistryError::ContentLengthMalformed(Box::new(err)))?
-                .parse()
-                .map_err(|err| RegistryError::ContentLengthMalformed(Box::new(err)))?;
-            if num_bytes != 0 {
-                return Err(RegistryError::NotSupported(
-                    "missing content length not implemented",
-                ));
-            }
-
-            // 0 is the only acceptable value here.
-        }
-        None => {
-            // Omitting is fine, indicating no body.
-        }
-    }
+    registry
+        .append_to_upload(&location, upload, request)
+        .await?;
 
     registry
         .storage
@@ -658,19 +1596,16 @@ async fn upload_finalize(
         .await?;
 
     info!(%upload, %digest, "new image uploaded");
-    Ok(Response::builder()
-        .status(StatusCode::CREATED)
-        .header("Docker-Content-Digest", digest.to_string())
-        .header(LOCATION, mk_upload_location(&location, upload))
-        .body(Body::empty())?)
+    mk_blob_created_response(&location, digest)
 }
 
 /// Uploads a manifest.
 async fn manifest_put(
     State(registry): State<Arc<ContainerRegistry>>,
-    Path(manifest_reference): Path<ManifestReference>,
+    Extension(manifest_reference): Extension<ManifestReference>,
     creds: ValidCredentials,
-    image_manifest_json: String,
+    headers: HeaderMap,
+    image_manifest_json: Bytes,
 ) -> Result<Response<Body>, RegistryError> {
     registry
         .auth_provider
@@ -678,10 +1613,73 @@ async fn manifest_put(
         .await
         .require_write()?;
 
-    let digest = registry
+    let manifest =
+        Manifest::from_slice(&image_manifest_json).map_err(RegistryError::ParseManifest)?;
+
+    // The media type given by the client must agree with the manifest itself.
+    let media_type = match headers.get(CONTENT_TYPE) {
+        Some(content_type) => {
+            let content_type = content_type
+                .to_str()
+                .ok()
+                .and_then(|value| value.split(';').next())
+                .unwrap_or_default()
+                .trim();
+            if !manifest.is_compatible_media_type(content_type) {
+                return Err(RegistryError::ManifestMediaTypeMismatch {
+                    content_type: content_type.to_owned(),
+                    media_type: manifest.media_type().to_owned(),
+                });
+            }
+            content_type
+        }
+        None => manifest.media_type(),
+    };
+
+    if let Reference::Digest(expected) = manifest_reference.reference() {
+        if storage::Digest::from_contents(&image_manifest_json) != *expected {
+            return Err(RegistryError::DigestMismatch);
+        }
+    }
+
+    // Immutable tags are checked upfront to fail early, storage enforces them atomically.
+    let mut replace_tag = true;
+    if let Reference::Tag(tag) = manifest_reference.reference() {
+        registry
+            .check_tag_mutable(
+                manifest_reference.location(),
+                tag,
+                Some(storage::Digest::from_contents(&image_manifest_json)),
+            )
+            .await?;
+        replace_tag = !registry
+            .immutable_tags
+            .is_immutable(manifest_reference.location(), tag);
+    }
+
+    let missing = registry.missing_references(&manifest).await?;
+    if !missing.is_empty() {
+        return Err(RegistryError::ManifestBlobUnknown(missing));
+    }
+
+    let digest = match registry
         .storage
-        .put_manifest(&manifest_reference, image_manifest_json.as_bytes())
-        .await?;
+        .put_manifest(
+            &manifest_reference,
+            &image_manifest_json,
+            media_type,
+            replace_tag,
+        )
+        .await
+    {
+        Ok(digest) => digest,
+        Err(storage::Error::TagExists) => {
+            return Err(RegistryError::TagImmutable(
+                manifest_reference.reference().to_string(),
+            ))
+        }
+        Err(err) => return Err(err.into()),
+    };
 
     info!(%manifest_reference, %digest, "new manifest received");
     // Completed upload, call hook:
@@ -690,7 +1688,7 @@ async fn manifest_put(
         .on_manifest_uploaded(&manifest_reference)
         .await;
 
-    Ok(Response::builder()
+    let mut builder = Response::builder()
         .status(StatusCode::CREATED)
         .header(
             LOCATION,
@@ -703,36 +1701,293 @@ async fn manifest_put(
         .header(
             "Docker-Content-Digest",
             ImageDigest::new(digest).to_string(),
-        )
-        .body(Body::empty())
-        .unwrap())
+        );
+
+    // Signals support for the referrers API to the client.
+    if let Some(subject) = manifest.subject() {
+        builder = builder.header("OCI-Subject", subject.digest());
+    }
+
+    Ok(builder.body(Body::empty())?)
 }
 
 /// Retrieves a manifest.
 async fn manifest_get(
     State(registry): State<Arc<ContainerRegistry>>,
-    Path(manifest_reference): Path<ManifestReference>,
+    Extension(manifest_reference): Extension<ManifestReference>,
+    creds: ValidCredentials,
+    headers: HeaderMap,
+) -> Result<Response<Body>, RegistryError> {
+    let (builder, manifest_json) = registry
+        .manifest_response(&manifest_reference, &creds, &headers)
+        .await?;
+
+    Ok(builder.body(manifest_json.into()).unwrap())
+}
+
+/// Checks for the existence of a manifest.
+///
+/// Identical to [`manifest_get`], except no body is sent.
+async fn manifest_head(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Extension(manifest_reference): Extension<ManifestReference>,
+    creds: ValidCredentials,
+    headers: HeaderMap,
+) -> Result<Response<Body>, RegistryError> {
+    let (builder, _manifest_json) = registry
+        .manifest_response(&manifest_reference, &creds, &headers)
+        .await?;
+
+    Ok(builder.body(Body::empty()).unwrap())
+}
+
+/// Deletes a manifest or tag.
+///
+/// Deleting by tag only removes the tag, deleting by digest removes the manifest along with all
+/// tags of the image pointing to it.
+async fn manifest_delete(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Extension(manifest_reference): Extension<ManifestReference>,
     creds: ValidCredentials,
 ) -> Result<Response<Body>, RegistryError> {
     registry
         .auth_provider
         .image_permissions(&creds, manifest_reference.location())
         .await
+        .require_write()?;
+
+    if !registry.allow_deletion {
+        return Err(RegistryError::NotSupported {
+            feature: "deletion",
+            allow: "GET, HEAD, PUT",
+        });
+    }
+
+    let location = manifest_reference.location();
+    let deleted = match manifest_reference.reference() {
+        Reference::Tag(tag) => {
+            registry.check_tag_mutable(location, tag, None).await?;
+
+            // The tag may have been created since checking, thus immutable tags are never deleted.
+            !registry.immutable_tags.is_immutable(location, tag)
+                && registry.storage.delete_tag(location, tag).await?
+        }
+        Reference::Digest(digest) => {
+            // Deleting a manifest removes all tags pointing to it.
+            for tag in registry
+                .storage
+                .list_tags(location)
+                .await?
+                .unwrap_or_default()
+            {
+                if registry.immutable_tags.is_immutable(location, &tag)
+                    && registry.tag_digest(location, &tag).await? == Some(*digest)
+                {
+                    return Err(RegistryError::TagImmutable(tag));
+                }
+            }
+            registry.storage.delete_manifest(location, *digest).await?
+        }
+    };
+
+    if !deleted {
+        return Err(RegistryError::ManifestUnknown);
+    }
+
+    info!(%manifest_reference, "manifest deleted");
+
+    Ok(Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header(CONTENT_LENGTH, 0)
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Pagination parameters of listing endpoints.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct PaginationQuery {
+    /// Maximum number of entries to return.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<usize>,
+    /// Only entries lexically after this one are returned.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last: Option<String>,
+}
+
+impl PaginationQuery {
+    /// Applies pagination to a lexically sorted list of entries.
+    ///
+    /// Returns the selected page, along with the query for the next page, if there is one.
+    fn paginate(&self, mut entries: Vec<String>) -> (Vec<String>, Option<PaginationQuery>) {
+        if let Some(ref last) = self.last {
+            entries.retain(|entry| entry > last);
+        }
+
+        match self.n {
+            Some(n) if entries.len() > n => {
+                entries.truncate(n);
+                // With `n == 0` there is no meaningful next page.
+                let next = entries.last().map(|last| PaginationQuery {
+                    n: Some(n),
+                    last: Some(last.clone()),
+                });
+                (entries, next)
+            }
+            _ => (entries, None),
+        }
+    }
+
+    /// Formats an RFC 5988 `Link` header pointing at the page described by this query.
+    ///
+    /// Parameters are percent-encoded, as entries may contain characters such as `/` or `&`.
+    fn link_header(&self, path: &str) -> String {
+        let query = serde_urlencoded::to_string(self).expect("pagination query is serializable");
+
+        format!("<{path}?{query}>; rel=\"next\"")
+    }
+}
+
+/// Lists all repositories the client has read access to.
+async fn catalog(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Query(pagination): Query<PaginationQuery>,
+    creds: ValidCredentials,
+) -> Result<Response<Body>, RegistryError> {
+    let mut repositories = Vec::new();
+    for location in registry.storage.list_repositories().await? {
+        if registry
+            .auth_provider
+            .image_permissions(&creds, &location)
+            .await
+            .has_read_permission()
+        {
+            repositories.push(location.to_string());
+        }
+    }
+
+    let (repositories, next) = pagination.paginate(repositories);
+
+    let mut response = Json(Catalog { repositories }).into_response();
+
+    if let Some(next) = next {
+        response.headers_mut().insert(
+            LINK,
+            next.link_header("/v2/_catalog")
+                .parse()
+                .map_err(axum::http::Error::from)?,
+        );
+    }
+
+    Ok(response)
+}
+
+/// Lists the tags of an image.
+async fn tags_list(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Extension(location): Extension<ImageLocation>,
+    Query(pagination): Query<PaginationQuery>,
+    creds: ValidCredentials,
+) -> Result<Response<Body>, RegistryError> {
+    registry
+        .auth_provider
+        .image_permissions(&creds, &location)
+        .await
         .require_read()?;
 
-    let manifest_json = registry
+    let all_tags = registry
         .storage
-        .get_manifest(&manifest_reference)
+        .list_tags(&location)
         .await?
-        .ok_or(RegistryError::NotFound)?;
+        .ok_or(RegistryError::NameUnknown)?;
 
-    let manifest: ImageManifest =
-        serde_json::from_slice(&manifest_json).map_err(RegistryError::ParseManifest)?;
+    let (tags, next) = pagination.paginate(all_tags);
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .header(CONTENT_LENGTH, manifest_json.len())
-        .header(CONTENT_TYPE, manifest.media_type())
-        .body(manifest_json.into())
-        .unwrap())
+    let mut response = Json(TagList {
+        name: location.to_string(),
+        tags,
+    })
+    .into_response();
+
+    if let Some(next) = next {
+        response.headers_mut().insert(
+            LINK,
+            next.link_header(&mk_tags_list_location(&location))
+                .parse()
+                .map_err(axum::http::Error::from)?,
+        );
+    }
+
+    Ok(response)
+}
+
+/// Filter parameters of the referrers endpoint.
+#[derive(Debug, Deserialize)]
+struct ReferrersQuery {
+    /// Only list referrers of this artifact type.
+    #[serde(rename = "artifactType")]
+    artifact_type: Option<String>,
+}
+
+/// Lists all manifests of an image referring to the given manifest through their `subject`.
+async fn referrers_list(
+    State(registry): State<Arc<ContainerRegistry>>,
+    Extension(location): Extension<ImageLocation>,
+    Extension(subject): Extension<ImageDigest>,
+    Query(filter): Query<ReferrersQuery>,
+    creds: ValidCredentials,
+) -> Result<Response<Body>, RegistryError> {
+    registry
+        .auth_provider
+        .image_permissions(&creds, &location)
+        .await
+        .require_read()?;
+
+    let subject = subject.to_string();
+    let mut referrers = Vec::new();
+    for digest in registry.storage.list_image_manifests(&location).await? {
+        let manifest_reference =
+            ManifestReference::new(location.clone(), Reference::new_digest(digest));
+        let Some(manifest_json) = registry.storage.get_manifest(&manifest_reference).await? else {
+            // Removed concurrently.
+            continue;
+        };
+
+        // A single broken manifest must not hide the referrers of the whole image.
+        let manifest = match Manifest::from_slice(&manifest_json) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                warn!(%digest, %err, "skipping unparsable manifest");
+                continue;
+            }
+        };
+        if manifest.subject().map(|s| s.digest()) != Some(subject.as_str()) {
+            continue;
+        }
+
+        if let Some(ref artifact_type) = filter.artifact_type {
+            if manifest.artifact_type() != Some(artifact_type.as_str()) {
+                continue;
+            }
+        }
+
+        referrers.push(manifest.descriptor(
+            ImageDigest::new(digest).to_string(),
+            manifest_json.len() as u64,
+        ));
+    }
+
+    let index = ImageIndex::new(referrers);
+    let mut response = Json(&index).into_response();
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(types::OCI_IMAGE_INDEX),
+    );
+    if filter.artifact_type.is_some() {
+        response.headers_mut().insert(
+            "OCI-Filters-Applied",
+            HeaderValue::from_static("artifactType"),
+        );
+    }
+
+    Ok(response)
 }
@@ -0,0 +1,122 @@
+//! Policies restricting what clients may change.
+//!
+//! Policies apply regardless of permissions, i.e. even clients with full write access are bound by
+//! them.
+
+use super::storage::ImageLocation;
+
+/// Tags that cannot be moved to a different manifest once pushed.
+///
+/// Tags are matched by rules consisting of a glob for the repository name and a glob for the tag.
+/// In globs, `*` matches any number of characters (including `/`) and `?` matches any single
+/// character.
+///
+/// Re-pushing the manifest a tag already points to is always allowed.
+///
+/// ```
+/// use container_registry::policy::ImmutableTags;
+///
+/// // Release tags of all repositories, along with every tag below `prod/`.
+/// let immutable_tags = ImmutableTags::none()
+///     .rule("*", "v*")
+///     .rule("prod/*", "*");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ImmutableTags {
+    /// Rules as `(repository, tag)` glob pairs.
+    rules: Vec<(String, String)>,
+}
+
+impl ImmutableTags {
+    /// Creates a policy with no immutable tags.
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Creates a policy making every tag in every repository immutable.
+    pub fn all() -> Self {
+        Self::none().rule("*", "*")
+    }
+
+    /// Adds a rule making all tags matching `tag` in repositories matching `repository` immutable.
+    pub fn rule<R: Into<String>, T: Into<String>>(mut self, repository: R, tag: T) -> Self {
+        self.rules.push((repository.into(), tag.into()));
+        self
+    }
+
+    /// Checks whether a tag is immutable.
+    pub fn is_immutable(&self, location: &ImageLocation, tag: &str) -> bool {
+        self.rules.iter().any(|(repository, tag_pattern)| {
+            glob_matches(repository, location.name()) && glob_matches(tag_pattern, tag)
+        })
+    }
+}
+
+/// Matches `text` against a glob `pattern` supporting `*` and `?`.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Classic backtracking matcher, only the most recent `*` needs to be revisited.
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, star_t)) => {
+                    p = star + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_matches, ImmutableTags};
+    use crate::storage::ImageLocation;
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*", "team/service"));
+        assert!(glob_matches("v*", "v1.2.3"));
+        assert!(glob_matches("v?.*", "v1.2"));
+        assert!(glob_matches("team/*/api", "team/a/b/api"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(glob_matches("latest", "latest"));
+
+        assert!(!glob_matches("v*", "latest"));
+        assert!(!glob_matches("v?", "v10"));
+        assert!(!glob_matches("team/*/api", "team/a/web"));
+        assert!(!glob_matches("latest", "latest2"));
+        assert!(!glob_matches("", "a"));
+    }
+
+    #[test]
+    fn applies_rules() {
+        let policy = ImmutableTags::none().rule("*", "v*").rule("prod/*", "*");
+        let location = |name| ImageLocation::new(name).unwrap();
+
+        assert!(policy.is_immutable(&location("alpine"), "v1.2.3"));
+        assert!(policy.is_immutable(&location("prod/api"), "latest"));
+        assert!(!policy.is_immutable(&location("alpine"), "latest"));
+        assert!(!policy.is_immutable(&location("staging/api"), "latest"));
+
+        assert!(ImmutableTags::all().is_immutable(&location("a/b/c"), "anything"));
+        assert!(!ImmutableTags::none().is_immutable(&location("alpine"), "v1"));
+    }
+}
@@ -0,0 +1,210 @@
+//! Parsing of registry endpoint paths.
+//!
+//! Image names consist of an arbitrary number of `/`-separated components, which cannot be
+//! expressed as `axum` route patterns. Instead, everything below `/v2/` is routed to a single
+//! handler, which parses the path into a [`RegistryRoute`].
+//!
+//! Since name components may themselves be called `blobs`, `manifests` or `uploads`, paths are
+//! matched by their fixed endpoint suffix from the right, which is always unambiguous.
+
+use std::str::FromStr;
+
+use uuid::Uuid;
+
+use crate::{
+    storage::{ImageLocation, ManifestReference, Reference},
+    ImageDigest, RegistryError, UploadId,
+};
+
+/// A parsed registry endpoint path.
+#[derive(Debug)]
+pub(crate) enum RegistryRoute {
+    /// `<name>/blobs/<digest>`
+    Blob {
+        location: ImageLocation,
+        digest: ImageDigest,
+    },
+    /// `<name>/blobs/uploads/`
+    UploadNew { location: ImageLocation },
+    /// `<name>/blobs/uploads/<uuid>`
+    Upload {
+        location: ImageLocation,
+        upload: UploadId,
+    },
+    /// `<name>/manifests/<reference>`
+    Manifest(ManifestReference),
+    /// `<name>/tags/list`
+    TagsList { location: ImageLocation },
+    /// `<name>/referrers/<digest>`
+    Referrers {
+        location: ImageLocation,
+        digest: ImageDigest,
+    },
+}
+
+impl FromStr for RegistryRoute {
+    type Err = RegistryError;
+
+    /// Parses a path, relative to `/v2/`.
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        if let Some(name) = path.strip_suffix("/blobs/uploads/") {
+            return Ok(RegistryRoute::UploadNew {
+                location: name.parse()?,
+            });
+        }
+
+        if let Some(name) = path.strip_suffix("/tags/list") {
+            return Ok(RegistryRoute::TagsList {
+                location: name.parse()?,
+            });
+        }
+
+        let (rest, last) = path
+            .rsplit_once('/')
+            .ok_or(RegistryError::UnknownEndpoint)?;
+        let (name, kind) = rest
+            .rsplit_once('/')
+            .ok_or(RegistryError::UnknownEndpoint)?;
+
+        match kind {
+            "blobs" => Ok(RegistryRoute::Blob {
+                location: name.parse()?,
+                digest: last.parse()?,
+            }),
+            "referrers" => Ok(RegistryRoute::Referrers {
+                location: name.parse()?,
+                digest: last.parse()?,
+            }),
+            "manifests" => {
+                let location = name.parse()?;
+                let reference = Reference::from_str(last)?;
+                Ok(RegistryRoute::Manifest(ManifestReference::new(
+                    location, reference,
+                )))
+            }
+            "uploads" => {
+                let name = name
+                    .strip_suffix("/blobs")
+                    .ok_or(RegistryError::UnknownEndpoint)?;
+                let upload = Uuid::from_str(last).map_err(|_| RegistryError::BlobUploadUnknown)?;
+                Ok(RegistryRoute::Upload {
+                    location: name.parse()?,
+                    upload: UploadId { upload },
+                })
+            }
+            _ => Err(RegistryError::UnknownEndpoint),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RegistryRoute;
+    use crate::{storage::Reference, RegistryError};
+
+    const DIGEST: &str = "sha256:596a7d877b33569d199046aaf293ecf45026445be36de1818d50b4f1850762ad";
+
+    #[test]
+    fn parses_nested_names() {
+        match "team/project/service/manifests/latest".parse().unwrap() {
+            RegistryRoute::Manifest(manifest_reference) => {
+                assert_eq!(manifest_reference.location().name(), "team/project/service");
+                assert_eq!(manifest_reference.reference().as_tag(), Some("latest"));
+            }
+            other => panic!("unexpected route {other:?}"),
+        }
+
+        match "alpine/tags/list".parse().unwrap() {
+            RegistryRoute::TagsList { location } => assert_eq!(location.name(), "alpine"),
+            other => panic!("unexpected route {other:?}"),
+        }
+
+        match format!("a/b/referrers/{DIGEST}").parse().unwrap() {
+            RegistryRoute::Referrers { location, digest } => {
+                assert_eq!(location.name(), "a/b");
+                assert_eq!(digest.to_string(), DIGEST);
+            }
+            other => panic!("unexpected route {other:?}"),
+        }
+    }
+
+    #[test]
+    fn handles_names_resembling_endpoints() {
+        match format!("blobs/blobs/{DIGEST}").parse().unwrap() {
+            RegistryRoute::Blob { location, digest } => {
+                assert_eq!(location.name(), "blobs");
+                assert_eq!(digest.to_string(), DIGEST);
+            }
+            other => panic!("unexpected route {other:?}"),
+        }
+
+        match "a/uploads/blobs/uploads/".parse().unwrap() {
+            RegistryRoute::UploadNew { location } => assert_eq!(location.name(), "a/uploads"),
+            other => panic!("unexpected route {other:?}"),
+        }
+
+        match "a/blobs/blobs/uploads/6c0d1a37-2dd1-4dbc-8e6b-0e3c5b8f3f0f"
+            .parse()
+            .unwrap()
+        {
+            RegistryRoute::Upload { location, upload } => {
+                assert_eq!(location.name(), "a/blobs");
+                assert_eq!(
+                    upload.upload.to_string(),
+                    "6c0d1a37-2dd1-4dbc-8e6b-0e3c5b8f3f0f"
+                );
+            }
+            other => panic!("unexpected route {other:?}"),
+        }
+
+        match format!("manifests/tags/manifests/{DIGEST}")
+            .parse()
+            .unwrap()
+        {
+            RegistryRoute::Manifest(manifest_reference) => {
+                assert_eq!(manifest_reference.location().name(), "manifests/tags");
+                assert!(matches!(
+                    manifest_reference.reference(),
+                    Reference::Digest(_)
+                ));
+            }
+            other => panic!("unexpected route {other:?}"),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_paths() {
+        assert!(matches!(
+            "Invalid/manifests/latest".parse::<RegistryRoute>(),
+            Err(RegistryError::NameInvalid(_))
+        ));
+        assert!(matches!(
+            "a//b/manifests/latest".parse::<RegistryRoute>(),
+            Err(RegistryError::NameInvalid(_))
+        ));
+        assert!(matches!(
+            "../manifests/latest".parse::<RegistryRoute>(),
+            Err(RegistryError::NameInvalid(_))
+        ));
+        assert!(matches!(
+            "a/manifests/..".parse::<RegistryRoute>(),
+            Err(RegistryError::ReferenceInvalid(_))
+        ));
+        assert!(matches!(
+            "a/blobs/sha256:abc".parse::<RegistryRoute>(),
+            Err(RegistryError::DigestInvalid(_))
+        ));
+        assert!(matches!(
+            "a/uploads/6c0d1a37-2dd1-4dbc-8e6b-0e3c5b8f3f0f".parse::<RegistryRoute>(),
+            Err(RegistryError::UnknownEndpoint)
+        ));
+        assert!(matches!(
+            "a/something/else".parse::<RegistryRoute>(),
+            Err(RegistryError::UnknownEndpoint)
+        ));
+        assert!(matches!(
+            "a".parse::<RegistryRoute>(),
+            Err(RegistryError::UnknownEndpoint)
+        ));
+    }
+}
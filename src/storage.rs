@@ -1,25 +1,53 @@
 //! Storage backends.
 //!
-//! The `container_registry` crate has somewhat modular storage backends, but currently nothing but
-//! filesystem storage is supported. Contact the author if you'd like to see this change.
-// Note: This module is in worse shape, documentation wise, than the rest. Cleaning this up is the
-//       first step towards supporting custom implementations.
+//! By default, the registry stores all data on the filesystem, see
+//! [`ContainerRegistryBuilder::storage`](crate::ContainerRegistryBuilder::storage). Other backends
+//! can be plugged in through
+//! [`ContainerRegistryBuilder::storage_backend`](crate::ContainerRegistryBuilder::storage_backend),
+//! either the [`MemoryStorage`] shipped with this crate or custom implementations of
+//! [`RegistryStorage`].
+//!
+//! With the `test-support` feature enabled, implementations can be checked against the expected
+//! semantics using [`check_storage_conformance`].
+//!
+//! [`check_storage_conformance`]: crate::test_support::check_storage_conformance
 use std::{
     fmt::{self, Display},
     fs,
-    io::{self, Read},
+    io::{self, Read, Seek},
     path::{Path, PathBuf},
+    pin::Pin,
     str::FromStr,
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime},
 };
 
 use axum::{async_trait, http::StatusCode, response::IntoResponse};
+use futures::future::BoxFuture;
+use hex::FromHex;
 use serde::{Deserialize, Serialize};
 use sha2::Digest as Sha2Digest;
 use thiserror::Error;
-use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncSeekExt, AsyncWrite};
 use uuid::Uuid;
 
-use super::{types::ImageManifest, ImageDigest};
+use super::{
+    types::{ErrorCode, Manifest, OciError, OciErrors},
+    ImageDigest,
+};
+
+mod fsck;
+mod hasher;
+mod memory;
+#[cfg(feature = "s3")]
+mod s3;
+
+use hasher::ResumableSha256;
+
+pub use fsck::{IntegrityCheck, IntegrityReport, Issue, IssueKind};
+pub use memory::MemoryStorage;
+#[cfg(feature = "s3")]
+pub use s3::S3Storage;
 
 /// Length of a SHA256 hash in bytes.
 pub const SHA256_LEN: usize = 32;
@@ -53,30 +81,138 @@ impl Display for Digest {
     }
 }
 
-#[derive(Debug, Deserialize)]
-struct LayerManifest {
-    #[serde(rename = "camelCase")]
-    #[allow(dead_code)] // TODO
-    blob_sum: String,
+impl FromStr for Digest {
+    type Err = hex::FromHexError;
+
+    /// Parses a digest from its hex representation, without any algorithm prefix.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        <[u8; SHA256_LEN]>::from_hex(s).map(Self::new)
+    }
 }
 
+/// Maximum length of an image name.
+const MAX_NAME_LEN: usize = 255;
+
+/// Maximum length of a tag.
+const MAX_TAG_LEN: usize = 128;
+
 /// Location of a given image.
 ///
-/// In an open container registry, images are stored in what `container-registry` calls
-/// "repository" and "image" pairs. For example, the container image specified as
-/// `bitnami/nginx:latest` would have a repository of `bitnami`, image of `nginx` and tag (which
-/// is not part of [`ImageLocation`] of `latest`.
-#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+/// In an open container registry, images are stored under a name consisting of one or more
+/// `/`-separated path components. For example, the container image specified as
+/// `bitnami/nginx:latest` would have a name of `bitnami/nginx` and tag (which is not part of
+/// [`ImageLocation`]) of `latest`, while `alpine:latest` has a single component name of `alpine`.
+///
+/// Names are validated according to the OCI distribution specification, i.e. every component must
+/// match `[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*`.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct ImageLocation {
-    /// The repository part of the image location.
-    repository: String,
-    /// The image part of the image location.
-    image: String,
+    /// The full name of the image.
+    name: String,
 }
 
 impl Display for ImageLocation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}/{}", self.repository, self.image)
+        f.write_str(&self.name)
+    }
+}
+
+/// Error parsing an image location.
+#[derive(Debug, Error)]
+pub enum ImageLocationParseError {
+    /// The given name was empty.
+    #[error("empty name")]
+    Empty,
+    /// The given name exceeds the maximum length.
+    #[error("name too long")]
+    TooLong,
+    /// A path component of the given name was invalid.
+    #[error("invalid name component {0:?}")]
+    InvalidComponent(String),
+}
+
+/// Checks whether a single path component of a name is valid.
+fn is_valid_name_component(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    let is_alnum = |c: u8| c.is_ascii_lowercase() || c.is_ascii_digit();
+
+    let mut idx = 0;
+    loop {
+        // Every component and every part after a separator must start with a run of alnums.
+        let run_start = idx;
+        while idx < bytes.len() && is_alnum(bytes[idx]) {
+            idx += 1;
+        }
+        if idx == run_start {
+            return false;
+        }
+
+        if idx == bytes.len() {
+            return true;
+        }
+
+        // Separator: `.`, `_`, `__` or one or more `-`.
+        match bytes[idx] {
+            b'.' => idx += 1,
+            b'_' => {
+                idx += 1;
+                if bytes.get(idx) == Some(&b'_') {
+                    idx += 1;
+                }
+            }
+            b'-' => {
+                while bytes.get(idx) == Some(&b'-') {
+                    idx += 1;
+                }
+            }
+            _ => return false,
+        }
+    }
+}
+
+impl FromStr for ImageLocation {
+    type Err = ImageLocationParseError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        if name.is_empty() {
+            return Err(ImageLocationParseError::Empty);
+        }
+
+        if name.len() > MAX_NAME_LEN {
+            return Err(ImageLocationParseError::TooLong);
+        }
+
+        if let Some(invalid) = name
+            .split('/')
+            .find(|component| !is_valid_name_component(component))
+        {
+            return Err(ImageLocationParseError::InvalidComponent(
+                invalid.to_owned(),
+            ));
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageLocation {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let raw = <String>::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for ImageLocation {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.name.serialize(serializer)
     }
 }
 
@@ -86,7 +222,6 @@ impl Display for ImageLocation {
 /// [`ImageLocation`] portion of `bitnami/nginx` and a [`Reference::Tag`] `latest`.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ManifestReference {
-    #[serde(flatten)]
     location: ImageLocation,
     reference: Reference,
 }
@@ -118,21 +253,23 @@ impl ManifestReference {
 }
 
 impl ImageLocation {
-    /// Creates a new image location.
-    pub fn new(repository: String, image: String) -> Self {
-        Self { repository, image }
+    /// Creates a new image location from a name.
+    ///
+    /// Returns an error if the name is not a valid image name.
+    pub fn new<S: AsRef<str>>(name: S) -> Result<Self, ImageLocationParseError> {
+        name.as_ref().parse()
     }
 
-    /// Returns the repository portion of the given image location.
+    /// Returns the full name of the given image location, e.g. `bitnami/nginx`.
     #[inline(always)]
-    pub fn repository(&self) -> &str {
-        self.repository.as_ref()
+    pub fn name(&self) -> &str {
+        self.name.as_ref()
     }
 
-    /// Returns the image portion of the given image location.
+    /// Returns the `/`-separated path components of the name, e.g. `bitnami` and `nginx`.
     #[inline(always)]
-    pub fn image(&self) -> &str {
-        self.image.as_ref()
+    pub fn components(&self) -> impl Iterator<Item = &str> {
+        self.name.split('/')
     }
 }
 
@@ -145,17 +282,50 @@ pub enum Reference {
     Digest(Digest),
 }
 
+/// Error parsing a reference.
+#[derive(Debug, Error)]
+pub enum ReferenceParseError {
+    /// The given reference is neither a valid digest, nor a valid tag.
+    #[error("invalid tag {0:?}")]
+    InvalidTag(String),
+}
+
+/// Checks whether a tag is valid, i.e. matches `[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}`.
+fn is_valid_tag(tag: &str) -> bool {
+    let mut chars = tag.chars();
+
+    match chars.next() {
+        Some(first) if first.is_ascii_alphanumeric() || first == '_' => {}
+        _ => return false,
+    }
+
+    tag.len() <= MAX_TAG_LEN
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+}
+
+impl FromStr for Reference {
+    type Err = ReferenceParseError;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        if let Ok(digest) = ImageDigest::from_str(raw) {
+            return Ok(Self::Digest(digest.digest));
+        }
+
+        if !is_valid_tag(raw) {
+            return Err(ReferenceParseError::InvalidTag(raw.to_owned()));
+        }
+
+        Ok(Self::Tag(raw.to_owned()))
+    }
+}
+
 impl<'de> Deserialize<'de> for Reference {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        let raw = <&str>::deserialize(deserializer)?;
-
-        match ImageDigest::from_str(raw) {
-            Ok(digest) => Ok(Self::Digest(digest.digest)),
-            Err(_) => Ok(Self::Tag(raw.to_owned())),
-        }
+        let raw = <String>::deserialize(deserializer)?;
+        raw.parse().map_err(serde::de::Error::custom)
     }
 }
 
@@ -197,17 +367,21 @@ impl Display for Reference {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Reference::Tag(tag) => Display::fmt(tag, f),
-            Reference::Digest(digest) => Display::fmt(digest, f),
+            Reference::Digest(digest) => Display::fmt(&ImageDigest::new(*digest), f),
         }
     }
 }
 
 /// A storage error.
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum Error {
     /// Attempted to submit data to an upload that does not exist.
     #[error("given upload does not exist")]
-    UploadDoesNotExit,
+    UploadDoesNotExist,
+    /// Attempted to write to an upload past its current end.
+    #[error("upload offset beyond end of upload")]
+    InvalidUploadOffset,
     /// A content hash mismatched.
     #[error("digest did not match")]
     DigestMismatch,
@@ -221,71 +395,277 @@ pub enum Error {
     /// Invalid image manifest submitted.
     #[error("invalid image manifest")]
     InvalidManifest(#[source] serde_json::Error),
-    /// Attempted to store a manifest under a digest instead of a tag.
-    #[error("cannot store manifest under hash")]
-    NotATag,
+    /// A tag that may not be replaced already points at a different manifest.
+    #[error("tag already exists")]
+    TagExists,
 }
 
 impl IntoResponse for Error {
     #[inline]
     fn into_response(self) -> axum::response::Response {
-        match self {
-            Error::UploadDoesNotExit => StatusCode::NOT_FOUND.into_response(),
-            Error::InvalidManifest(_) | Error::NotATag => StatusCode::BAD_REQUEST.into_response(),
-            Error::DigestMismatch | Error::Io(_) | Error::BackgroundTaskPanicked(_) => {
-                StatusCode::INTERNAL_SERVER_ERROR.into_response()
-            }
-        }
+        let (status, error) = match self {
+            Error::UploadDoesNotExist => (
+                StatusCode::NOT_FOUND,
+                OciError::new(ErrorCode::BlobUploadUnknown),
+            ),
+            Error::InvalidUploadOffset => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                OciError::new(ErrorCode::BlobUploadInvalid),
+            ),
+            Error::DigestMismatch => (
+                StatusCode::BAD_REQUEST,
+                OciError::new(ErrorCode::DigestInvalid),
+            ),
+            Error::InvalidManifest(err) => (
+                StatusCode::BAD_REQUEST,
+                OciError::new(ErrorCode::ManifestInvalid).with_detail(err.to_string()),
+            ),
+            // Same as refusing to change an immutable tag in the registry itself.
+            Error::TagExists => (StatusCode::FORBIDDEN, OciError::new(ErrorCode::Denied)),
+            Error::Io(_) | Error::BackgroundTaskPanicked(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                OciError::new(ErrorCode::Unknown),
+            ),
+        };
+
+        (status, OciErrors::single(error)).into_response()
     }
 }
 
+/// Metadata of a stored blob or manifest.
 #[derive(Debug)]
-pub(crate) struct BlobMetadata {
-    #[allow(dead_code)] // TODO
+pub struct BlobMetadata {
+    /// Digest of the blob's contents.
     digest: Digest,
+    /// Size of the blob in bytes.
     size: u64,
+    /// Time the blob was last written.
+    modified: Option<SystemTime>,
 }
 
 impl BlobMetadata {
-    #[allow(dead_code)] // TODO
-    pub(crate) fn digest(&self) -> Digest {
+    /// Creates new blob metadata.
+    pub fn new(digest: Digest, size: u64) -> Self {
+        Self {
+            digest,
+            size,
+            modified: None,
+        }
+    }
+
+    /// Sets the time the blob was last written.
+    pub fn with_modified(mut self, modified: SystemTime) -> Self {
+        self.modified = Some(modified);
+        self
+    }
+
+    /// Returns the time the blob was last written, if known.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// Returns the digest of the blob's contents.
+    pub fn digest(&self) -> Digest {
         self.digest
     }
 
-    pub(crate) fn size(&self) -> u64 {
+    /// Returns the size of the blob in bytes.
+    pub fn size(&self) -> u64 {
         self.size
     }
 }
 
+/// A reader for blob contents, supporting seeking to serve partial content.
+///
+/// Implemented for every type satisfying the bounds, e.g. [`tokio::fs::File`] or
+/// [`std::io::Cursor`].
+pub trait BlobReader: AsyncRead + AsyncSeek + Send + Unpin {}
+
+impl<T> BlobReader for T where T: AsyncRead + AsyncSeek + Send + Unpin {}
+
+/// A storage backend for the registry.
+///
+/// A backend holds four kinds of data:
+///
+/// * **Uploads** are blobs still being written, identified by a random UUID. Once complete, they
+///   are verified against their digest and turned into a blob.
+/// * **Blobs** are immutable, content-addressed byte strings (layers and configs), shared between
+///   all images.
+/// * **Manifests** are content-addressed as well and shared between all images, along with the
+///   media type they were pushed as.
+/// * **Images**, identified by an [`ImageLocation`], reference manifests, either through a tag or
+///   untagged. A manifest exists as long as at least one image references it.
+///
+/// Blobs are not tied to images, the registry checks access and references itself.
+///
+/// Methods may be called concurrently. Absence of the requested item is not an error, but
+/// reported through `None` or `false` return values, as documented on each method. Backends report
+/// failures of their own, e.g. a lost connection, as [`Error::Io`].
+///
+/// These semantics are checked by
+/// [`test_support::check_storage_conformance`](crate::test_support::check_storage_conformance).
 #[async_trait]
-pub(crate) trait RegistryStorage: Send + Sync {
+pub trait RegistryStorage: Send + Sync {
+    /// Starts a new, empty upload.
+    ///
+    /// Returns a freshly generated ID, which must not clash with any existing upload.
     async fn begin_new_upload(&self) -> Result<Uuid, Error>;
 
-    async fn get_blob_reader(
-        &self,
-        digest: Digest,
-    ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error>;
+    /// Returns a seekable reader for the contents of a blob.
+    ///
+    /// Returns `None` if the blob does not exist.
+    async fn get_blob_reader(&self, digest: Digest) -> Result<Option<Box<dyn BlobReader>>, Error>;
 
+    /// Returns the metadata of a blob, including the time it was last written.
+    ///
+    /// Returns `None` if the blob does not exist.
     async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error>;
 
+    /// Lists the metadata of all stored blobs, in no particular order.
+    ///
+    /// Times blobs were last written must be included, blobs without one are never garbage
+    /// collected.
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error>;
+
+    /// Returns a URL clients may download a blob from directly.
+    ///
+    /// If a URL is returned, blob downloads are redirected there instead of being served through
+    /// [`Self::get_blob_reader`]. The registry checks that the blob exists beforehand. Returns
+    /// `None` by default.
+    async fn get_blob_url(&self, _digest: Digest) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    /// Removes a blob.
+    ///
+    /// Returns `false` if the blob did not exist.
+    async fn delete_blob(&self, digest: Digest) -> Result<bool, Error>;
+
+    /// Returns the number of bytes uploaded so far.
+    ///
+    /// Returns `None` if the upload does not exist.
+    async fn get_upload_size(&self, upload: Uuid) -> Result<Option<u64>, Error>;
+
+    /// Returns a writer appending to an upload at the given offset.
+    ///
+    /// Any data previously written at or past `start_at` is discarded. `start_at` must not exceed
+    /// the current size of the upload, otherwise [`Error::InvalidUploadOffset`] is returned.
+    /// Returns [`Error::UploadDoesNotExist`] if there is no such upload.
+    ///
+    /// Data written is part of the upload once the writer has been flushed or shut down.
     async fn get_upload_writer(
         &self,
         start_at: u64,
         upload: Uuid,
     ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error>;
 
+    /// Turns a completed upload into a blob.
+    ///
+    /// The contents of the upload are verified against `hash` first, returning
+    /// [`Error::DigestMismatch`] if they differ, in which case the upload is left untouched. On
+    /// success, the upload no longer exists. Finalizing an upload of an already existing blob
+    /// succeeds. Returns [`Error::UploadDoesNotExist`] if there is no such upload.
     async fn finalize_upload(&self, upload: Uuid, hash: Digest) -> Result<(), Error>;
 
+    /// Cancels an upload, discarding all data uploaded so far.
+    ///
+    /// Returns `false` if the upload did not exist.
+    async fn cancel_upload(&self, upload: Uuid) -> Result<bool, Error>;
+
+    /// Removes all uploads that have not been written to for longer than `max_age`.
+    ///
+    /// Returns the IDs of the removed uploads.
+    async fn expire_uploads(&self, max_age: Duration) -> Result<Vec<Uuid>, Error>;
+
+    /// Returns the raw contents of a manifest.
+    ///
+    /// Tags are resolved within the image of the reference. Manifests referenced by digest are
+    /// returned regardless of the image, access control is up to the registry. Returns `None` if
+    /// the manifest or tag does not exist.
     async fn get_manifest(
         &self,
         manifest_reference: &ManifestReference,
     ) -> Result<Option<Vec<u8>>, Error>;
 
+    /// Stores a manifest, along with the media type it was pushed as.
+    ///
+    /// The manifest is linked to the image of the reference, if the reference is a tag it is
+    /// created or moved to point at the manifest. Storing a manifest that already exists succeeds.
+    /// Returns the digest of the manifest.
+    ///
+    /// If `replace_tag` is `false`, a tag already pointing at a different manifest is left
+    /// untouched and [`Error::TagExists`] is returned instead. Checking and creating the tag must
+    /// happen in a single atomic step.
+    ///
+    /// Returns [`Error::InvalidManifest`] if the manifest cannot be parsed and
+    /// [`Error::DigestMismatch`] if it does not match a digest reference.
     async fn put_manifest(
         &self,
         manifest_reference: &ManifestReference,
         manifest: &[u8],
+        media_type: &str,
+        replace_tag: bool,
     ) -> Result<Digest, Error>;
+
+    /// Returns the media type a manifest was pushed as.
+    ///
+    /// Returns `None` if the manifest does not exist or was stored without a media type.
+    async fn get_manifest_media_type(&self, digest: Digest) -> Result<Option<String>, Error>;
+
+    /// Returns the metadata of a manifest, including the time it was last stored.
+    ///
+    /// Returns `None` if the manifest does not exist.
+    async fn get_manifest_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error>;
+
+    /// Lists the digests of all stored manifests, in no particular order.
+    async fn list_manifests(&self) -> Result<Vec<Digest>, Error>;
+
+    /// Lists the digests of all manifests of an image, tagged or not, sorted.
+    async fn list_image_manifests(&self, location: &ImageLocation) -> Result<Vec<Digest>, Error>;
+
+    /// Removes a tag from an image.
+    ///
+    /// The manifest the tag pointed to is left untouched. Returns `false` if there was no such tag.
+    async fn delete_tag(&self, location: &ImageLocation, tag: &str) -> Result<bool, Error>;
+
+    /// Removes a manifest from an image.
+    ///
+    /// Removes all tags of the image at `location` pointing to the manifest. The manifest itself is
+    /// removed once no other image references it anymore. Returns `false` if the manifest does not
+    /// exist or is not linked to the image, in which case nothing is removed.
+    async fn delete_manifest(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error>;
+
+    /// Removes an untagged manifest from an image.
+    ///
+    /// Unlike [`Self::delete_manifest`], tags are never removed. Returns `false` if the manifest is
+    /// not linked to the image or a tag of the image points to it, in which case nothing is
+    /// removed. The manifest itself is removed once no other image references it anymore.
+    async fn delete_untagged_manifest(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error>;
+
+    /// Removes a manifest not linked to any image.
+    ///
+    /// Older versions of the registry left such manifests behind when overwriting a tag. Returns
+    /// `false` if the manifest does not exist or an image references it, in which case nothing is
+    /// removed.
+    async fn delete_unlinked_manifest(&self, digest: Digest) -> Result<bool, Error>;
+
+    /// Lists all tags of an image, sorted lexically.
+    ///
+    /// Returns `None` if the image location is not known to the storage.
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Option<Vec<String>>, Error>;
+
+    /// Lists all image locations known to the storage, sorted lexically by name.
+    ///
+    /// An image is known once a manifest has been stored for it.
+    async fn list_repositories(&self) -> Result<Vec<ImageLocation>, Error>;
 }
 
 /// A filesystem backend error.
@@ -305,15 +685,30 @@ pub enum FilesystemStorageError {
         #[source]
         err: io::Error,
     },
+    /// Failed to migrate a tag from an older storage layout.
+    #[error("could not migrate tag {}", path.display())]
+    FailedToMigrateTag {
+        path: PathBuf,
+        #[source]
+        err: io::Error,
+    },
 }
 
+/// Name of the directory holding the actual tags of an image inside the tags tree.
+///
+/// Image name components cannot start with an underscore, thus this never clashes with nested
+/// image names.
+const TAGS_DIR_NAME: &str = "_tags";
+
+/// Name of the directory holding links to all manifests of an image, tagged or not.
+const MANIFESTS_DIR_NAME: &str = "_manifests";
+
 #[derive(Debug)]
 pub(crate) struct FilesystemStorage {
     uploads: PathBuf,
     blobs: PathBuf,
     manifests: PathBuf,
     tags: PathBuf,
-    rel_manifest_to_blobs: PathBuf,
 }
 
 impl FilesystemStorage {
@@ -326,13 +721,13 @@ impl FilesystemStorage {
             }
         })?;
 
-        let uploads = root.join("uploads");
-        let blobs = root.join("blobs");
-        let manifests = root.join("manifests");
-        let tags = root.join("tags");
-        let rel_manifest_to_blobs = PathBuf::from("../../../manifests");
-
-        for dir in [&uploads, &blobs, &manifests, &tags] {
+        let storage = Self::at(&root);
+        for dir in [
+            &storage.uploads,
+            &storage.blobs,
+            &storage.manifests,
+            &storage.tags,
+        ] {
             if !dir.exists() {
                 fs::create_dir(dir).map_err(|err| FilesystemStorageError::FailedToCreateDir {
                     path: dir.to_owned(),
@@ -341,14 +736,86 @@ impl FilesystemStorage {
             }
         }
 
-        Ok(FilesystemStorage {
-            uploads,
-            blobs,
-            manifests,
-            tags,
-            rel_manifest_to_blobs,
-        })
+        storage.migrate_legacy_tags()?;
+
+        Ok(storage)
+    }
+
+    /// Returns the storage rooted at `root`, without initializing or checking it.
+    fn at(root: &Path) -> Self {
+        FilesystemStorage {
+            uploads: root.join("uploads"),
+            blobs: root.join("blobs"),
+            manifests: root.join("manifests"),
+            tags: root.join("tags"),
+        }
     }
+
+    /// Migrates tags from the legacy `tags/<repository>/<image>/<tag>` layout.
+    ///
+    /// Older versions only supported two-component names and stored tags directly inside the
+    /// image's directory.
+    fn migrate_legacy_tags(&self) -> Result<(), FilesystemStorageError> {
+        let migration_err = |path: &Path| {
+            let path = path.to_owned();
+            move |err| FilesystemStorageError::FailedToMigrateTag { path, err }
+        };
+
+        for repository in fs::read_dir(&self.tags).map_err(migration_err(&self.tags))? {
+            let repository = repository.map_err(migration_err(&self.tags))?.path();
+            if !repository.is_dir() {
+                continue;
+            }
+
+            for image in fs::read_dir(&repository).map_err(migration_err(&repository))? {
+                let image = image.map_err(migration_err(&repository))?;
+                // Skips the `_tags` and `_manifests` directories of single-component images.
+                // Names cannot start with an underscore, so these are never legacy images.
+                if image.file_name().as_encoded_bytes().starts_with(b"_") {
+                    continue;
+                }
+                let image = image.path();
+                if !image.is_dir() {
+                    continue;
+                }
+
+                for entry in fs::read_dir(&image).map_err(migration_err(&image))? {
+                    let legacy_tag = entry.map_err(migration_err(&image))?.path();
+                    if !legacy_tag.is_symlink() {
+                        continue;
+                    }
+
+                    let tags_dir = image.join(TAGS_DIR_NAME);
+                    fs::create_dir_all(&tags_dir).map_err(migration_err(&tags_dir))?;
+
+                    // The tag moves one level deeper, so its relative target needs adjusting.
+                    let target = fs::read_link(&legacy_tag).map_err(migration_err(&legacy_tag))?;
+                    let tag = tags_dir.join(legacy_tag.file_name().expect("should have file name"));
+                    std::os::unix::fs::symlink(Path::new("..").join(&target), &tag)
+                        .map_err(migration_err(&tag))?;
+
+                    // Links the manifest to the image, like every manifest pushed since.
+                    if let Some(manifest_name) = target.file_name() {
+                        let manifests_dir = image.join(MANIFESTS_DIR_NAME);
+                        fs::create_dir_all(&manifests_dir)
+                            .map_err(migration_err(&manifests_dir))?;
+                        let link = manifests_dir.join(manifest_name);
+                        match std::os::unix::fs::symlink(Path::new("..").join(&target), &link) {
+                            Ok(()) => {}
+                            // Another tag pointed to the same manifest.
+                            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+                            Err(err) => return Err(migration_err(&link)(err)),
+                        }
+                    }
+
+                    fs::remove_file(&legacy_tag).map_err(migration_err(&legacy_tag))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn blob_path(&self, digest: Digest) -> PathBuf {
         self.blobs.join(format!("{}", digest))
     }
@@ -356,24 +823,317 @@ impl FilesystemStorage {
         self.uploads.join(format!("{}.partial", upload))
     }
 
+    /// Returns the path of the file storing the hash state of an upload.
+    ///
+    /// Stored next to the upload, its extension keeps it from being mistaken for one.
+    fn upload_hash_path(&self, upload: Uuid) -> PathBuf {
+        self.uploads.join(format!("{}.hash", upload))
+    }
+
+    /// Returns a hasher that has hashed the first `len` bytes of an upload.
+    ///
+    /// Resumes from the saved hash state of the upload, only reading data not covered by it.
+    async fn upload_hasher(&self, upload: Uuid, len: u64) -> Result<ResumableSha256, Error> {
+        let saved = match tokio::fs::read(self.upload_hash_path(upload)).await {
+            Ok(state) => ResumableSha256::from_bytes(&state),
+            // Uploads started by older versions have no hash state.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        // The saved state lags behind if writing a chunk failed, and is ahead if data has been
+        // discarded since.
+        let hasher = saved
+            .filter(|hasher| hasher.len() <= len)
+            .unwrap_or_default();
+        if hasher.len() == len {
+            return Ok(hasher);
+        }
+
+        hash_file_from(self.upload_path(upload), hasher, len).await
+    }
+
     fn manifest_path(&self, digest: Digest) -> PathBuf {
         self.manifests.join(format!("{}", digest))
     }
 
-    fn blob_rel_path(&self, digest: Digest) -> PathBuf {
-        self.rel_manifest_to_blobs.join(format!("{}", digest))
+    /// Returns the path of the file recording the media type of a manifest.
+    ///
+    /// Stored next to the manifest, its extension keeps it from being mistaken for one.
+    fn manifest_media_type_path(&self, digest: Digest) -> PathBuf {
+        self.manifests.join(format!("{}.media-type", digest))
+    }
+
+    /// Returns the path of a manifest, relative to the tags or manifests directory of an image.
+    fn manifest_rel_path(&self, location: &ImageLocation, digest: Digest) -> PathBuf {
+        // Each name component adds a level, as do the tags directory and the tags tree root.
+        let mut rel_path: PathBuf = location.components().map(|_| "..").collect();
+        rel_path.push("../..");
+        rel_path.push("manifests");
+        rel_path.push(digest.to_string());
+        rel_path
     }
 
     fn tag_path(&self, location: &ImageLocation, tag: &str) -> PathBuf {
-        self.tags
-            .join(location.repository())
-            .join(location.image())
-            .join(tag)
+        self.tags_dir(location).join(tag)
+    }
+
+    fn image_dir(&self, location: &ImageLocation) -> PathBuf {
+        let mut image_dir = self.tags.clone();
+        image_dir.extend(location.components());
+        image_dir
+    }
+
+    fn tags_dir(&self, location: &ImageLocation) -> PathBuf {
+        self.image_dir(location).join(TAGS_DIR_NAME)
+    }
+
+    fn manifest_link_path(&self, location: &ImageLocation, digest: Digest) -> PathBuf {
+        self.image_dir(location)
+            .join(MANIFESTS_DIR_NAME)
+            .join(digest.to_string())
     }
 
     fn temp_tag_path(&self) -> PathBuf {
         self.tags.join(Uuid::new_v4().to_string())
     }
+
+    /// Atomically creates or replaces a link to a manifest of an image.
+    ///
+    /// If `replace` is `false`, an existing link to a different manifest is kept and
+    /// [`Error::TagExists`] returned.
+    async fn link_manifest(
+        &self,
+        link: PathBuf,
+        location: &ImageLocation,
+        digest: Digest,
+        replace: bool,
+    ) -> Result<(), Error> {
+        let link_parent = link.parent().expect("should have parent");
+
+        if !link_parent.exists() {
+            tokio::fs::create_dir_all(link_parent)
+                .await
+                .map_err(Error::Io)?;
+        }
+
+        let target = self.manifest_rel_path(location, digest);
+
+        if !replace {
+            // Creating a symlink never replaces an existing file.
+            return match tokio::fs::symlink(&target, &link).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    match tokio::fs::read_link(&link).await {
+                        Ok(existing) if existing == target => Ok(()),
+                        // Also covers the link having been removed in the meantime.
+                        Ok(_) | Err(_) => Err(Error::TagExists),
+                    }
+                }
+                Err(e) => Err(Error::Io(e)),
+            };
+        }
+
+        let tmp_link = self.temp_tag_path();
+
+        tokio::fs::symlink(target, &tmp_link)
+            .await
+            .map_err(Error::Io)?;
+        tokio::fs::rename(tmp_link, link).await.map_err(Error::Io)?;
+
+        Ok(())
+    }
+
+    /// Returns whether an image references the manifest with the given digest.
+    async fn has_manifest(&self, location: &ImageLocation, digest: Digest) -> Result<bool, Error> {
+        Ok(self.manifest_link_path(location, digest).is_symlink()
+            || !self.tags_pointing_to(location, digest).await?.is_empty())
+    }
+
+    /// Returns the paths of all tags of an image pointing at the manifest with the given digest.
+    async fn tags_pointing_to(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let mut entries = match tokio::fs::read_dir(self.tags_dir(location)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        let manifest_name = digest.to_string();
+        let mut tags = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            let target = match tokio::fs::read_link(entry.path()).await {
+                Ok(target) => target,
+                // Not a symlink, thus not a tag.
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => continue,
+                Err(e) => return Err(Error::Io(e)),
+            };
+
+            if target.file_name() == Some(manifest_name.as_ref()) {
+                tags.push(entry.path());
+            }
+        }
+
+        Ok(tags)
+    }
+
+    /// Removes a manifest, unless an image still references it.
+    ///
+    /// Manifests are shared between all images, thus this is the last step of removing one from an
+    /// image. Returns whether the manifest has been removed.
+    async fn remove_unreferenced_manifest(&self, digest: Digest) -> Result<bool, Error> {
+        for other in self.list_repositories().await? {
+            if self.has_manifest(&other, digest).await? {
+                return Ok(false);
+            }
+        }
+
+        for path in [
+            self.manifest_path(digest),
+            self.manifest_media_type_path(digest),
+        ] {
+            match tokio::fs::remove_file(path).await {
+                Ok(()) => {}
+                // Someone else deleted it concurrently, which is fine.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// Calculates the digest of a file's contents.
+async fn hash_file(path: PathBuf) -> Result<Digest, Error> {
+    Ok(hash_file_from(path, ResumableSha256::new(), u64::MAX)
+        .await?
+        .finalize())
+}
+
+/// Continues hashing a file at the offset `hasher` has reached.
+///
+/// Stops once `hasher` has hashed `len` bytes in total, or at the end of the file.
+async fn hash_file_from(
+    path: PathBuf,
+    mut hasher: ResumableSha256,
+    len: u64,
+) -> Result<ResumableSha256, Error> {
+    // We offload hashing to a blocking thread.
+    tokio::task::spawn_blocking(move || {
+        let mut src = fs::File::open(path).map_err(Error::Io)?;
+        src.seek(io::SeekFrom::Start(hasher.len()))
+            .map_err(Error::Io)?;
+        let mut src = src.take(len.saturating_sub(hasher.len()));
+
+        // Uses `vec!` instead of `Box`, as initializing the latter blows the stack:
+        let mut buf = vec![0; BUFFER_SIZE];
+
+        loop {
+            let read = src.read(buf.as_mut()).map_err(Error::Io)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+        }
+
+        Ok(hasher)
+    })
+    .await
+    .map_err(Error::BackgroundTaskPanicked)?
+}
+
+/// Writes to an upload, hashing the data as it passes through.
+///
+/// The hash state is saved whenever the writer is flushed, thus finalizing the upload does not
+/// require reading it again.
+struct UploadWriter {
+    file: tokio::fs::File,
+    hasher: ResumableSha256,
+    hash_path: PathBuf,
+    /// Whether the hash state changed since it was last saved.
+    dirty: bool,
+    /// Saving of the hash state in progress.
+    saving: Option<BoxFuture<'static, io::Result<()>>>,
+}
+
+impl UploadWriter {
+    fn poll_save(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(saving) = self.saving.as_mut() {
+            let result = ready!(saving.as_mut().poll(cx));
+            self.saving = None;
+            result?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UploadWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_save(cx))?;
+
+        let written = ready!(Pin::new(&mut this.file).poll_write(cx, buf))?;
+        this.hasher.update(&buf[..written]);
+        this.dirty = true;
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_save(cx))?;
+
+        // The state must never cover data that has not been written yet.
+        ready!(Pin::new(&mut this.file).poll_flush(cx))?;
+
+        if this.dirty {
+            // Not replaced atomically, as a partially written state fails to restore and is
+            // then rebuilt from the data.
+            let state = this.hasher.to_bytes();
+            let hash_path = this.hash_path.clone();
+            this.saving = Some(Box::pin(tokio::fs::write(hash_path, state)));
+            this.dirty = false;
+        }
+
+        this.poll_save(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.file).poll_shutdown(cx)
+    }
+}
+
+/// Removes the hash state of an upload that no longer exists.
+async fn remove_upload_hash_state(path: PathBuf) -> Result<(), Error> {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        // Uploads started by older versions have no hash state.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Error::Io(e)),
+    }
+}
+
+/// Returns the metadata of a file storing a blob or manifest.
+async fn file_metadata(path: PathBuf, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) => Ok(Some(BlobMetadata {
+            digest,
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        })),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::Io(e)),
+    }
 }
 
 #[async_trait]
@@ -389,24 +1149,33 @@ impl RegistryStorage for FilesystemStorage {
     }
 
     async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
-        let blob_path = self.blob_path(digest);
+        file_metadata(self.blob_path(digest), digest).await
+    }
 
-        if !blob_path.exists() {
-            return Ok(None);
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        let mut entries = tokio::fs::read_dir(&self.blobs).await.map_err(Error::Io)?;
+
+        let mut blobs = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            // Anything not named by a digest was not created by us.
+            let Some(digest) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            else {
+                continue;
+            };
+
+            // The blob may have been deleted in the meantime.
+            if let Some(metadata) = file_metadata(entry.path(), digest).await? {
+                blobs.push(metadata);
+            }
         }
 
-        let metadata = tokio::fs::metadata(blob_path).await.map_err(Error::Io)?;
-
-        Ok(Some(BlobMetadata {
-            digest,
-            size: metadata.len(),
-        }))
+        Ok(blobs)
     }
 
-    async fn get_blob_reader(
-        &self,
-        digest: Digest,
-    ) -> Result<Option<Box<dyn AsyncRead + Send + Unpin>>, Error> {
+    async fn get_blob_reader(&self, digest: Digest) -> Result<Option<Box<dyn BlobReader>>, Error> {
         let blob_path = self.blob_path(digest);
 
         if !blob_path.exists() {
@@ -418,6 +1187,22 @@ impl RegistryStorage for FilesystemStorage {
         Ok(Some(Box::new(reader)))
     }
 
+    async fn delete_blob(&self, digest: Digest) -> Result<bool, Error> {
+        match tokio::fs::remove_file(self.blob_path(digest)).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
+    async fn get_upload_size(&self, upload: Uuid) -> Result<Option<u64>, Error> {
+        match tokio::fs::metadata(self.upload_path(upload)).await {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
     async fn get_upload_writer(
         &self,
         start_at: u64,
@@ -426,21 +1211,43 @@ impl RegistryStorage for FilesystemStorage {
         let location = self.upload_path(upload);
 
         if !location.exists() {
-            return Err(Error::UploadDoesNotExit);
+            return Err(Error::UploadDoesNotExist);
         }
 
         let mut file = tokio::fs::OpenOptions::new()
-            .append(true)
+            .write(true)
             .truncate(false)
             .open(location)
             .await
             .map_err(Error::Io)?;
 
+        let size = file.metadata().await.map_err(Error::Io)?.len();
+        if start_at > size {
+            return Err(Error::InvalidUploadOffset);
+        }
+
+        let hasher = self.upload_hasher(upload, start_at).await?;
+        let hash_path = self.upload_hash_path(upload);
+
+        // The state is saved before discarding any data. Otherwise a state covering discarded
+        // data would be taken for one covering whatever is written in its place.
+        tokio::fs::write(&hash_path, hasher.to_bytes())
+            .await
+            .map_err(Error::Io)?;
+
+        // Discard anything past the starting point, e.g. from a previously failed chunk.
+        file.set_len(start_at).await.map_err(Error::Io)?;
         file.seek(io::SeekFrom::Start(start_at))
             .await
             .map_err(Error::Io)?;
 
-        Ok(Box::new(file))
+        Ok(Box::new(UploadWriter {
+            file,
+            hasher,
+            hash_path,
+            dirty: false,
+            saving: None,
+        }))
     }
 
     async fn finalize_upload(&self, upload: Uuid, digest: Digest) -> Result<(), Error> {
@@ -449,35 +1256,16 @@ impl RegistryStorage for FilesystemStorage {
 
         let upload_path = self.upload_path(upload);
 
-        if !upload_path.exists() {
-            return Err(Error::UploadDoesNotExit);
-        }
-
-        // We offload hashing to a blocking thread.
-        let actual = {
-            let upload_path = upload_path.clone();
-            tokio::task::spawn_blocking::<_, Result<Digest, Error>>(move || {
-                let mut src = fs::File::open(upload_path).map_err(Error::Io)?;
-
-                // Uses `vec!` instead of `Box`, as initializing the latter blows the stack:
-                let mut buf = vec![0; BUFFER_SIZE];
-                let mut hasher = sha2::Sha256::new();
-
-                loop {
-                    let read = src.read(buf.as_mut()).map_err(Error::Io)?;
-                    if read == 0 {
-                        break;
-                    }
-                    hasher.update(&buf[..read]);
-                }
-
-                let actual = hasher.finalize();
-                Ok(Digest::new(actual.into()))
-            })
-        }
-        .await
-        .map_err(Error::BackgroundTaskPanicked)??;
+        let size = match tokio::fs::metadata(&upload_path).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::UploadDoesNotExist)
+            }
+            Err(e) => return Err(Error::Io(e)),
+        };
 
+        // Usually the saved hash state covers the whole upload, so nothing needs to be read.
+        let actual = self.upload_hasher(upload, size).await?.finalize();
         if actual != digest {
             return Err(Error::DigestMismatch);
         }
@@ -488,8 +1276,68 @@ impl RegistryStorage for FilesystemStorage {
             .await
             .map_err(Error::Io)?;
 
-        // All good.
-        Ok(())
+        remove_upload_hash_state(self.upload_hash_path(upload)).await
+    }
+
+    async fn cancel_upload(&self, upload: Uuid) -> Result<bool, Error> {
+        match tokio::fs::remove_file(self.upload_path(upload)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(Error::Io(e)),
+        }
+
+        remove_upload_hash_state(self.upload_hash_path(upload)).await?;
+
+        Ok(true)
+    }
+
+    async fn expire_uploads(&self, max_age: Duration) -> Result<Vec<Uuid>, Error> {
+        let now = SystemTime::now();
+        let mut expired = Vec::new();
+
+        let mut entries = tokio::fs::read_dir(&self.uploads)
+            .await
+            .map_err(Error::Io)?;
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            let file_name = entry.file_name();
+            let Some(upload) = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".partial"))
+                .and_then(|name| Uuid::parse_str(name).ok())
+            else {
+                continue;
+            };
+
+            // The modification time is updated on every write, thus reflects the last activity.
+            let modified = match entry.metadata().await.and_then(|m| m.modified()) {
+                Ok(modified) => modified,
+                // The upload may have been finalized or cancelled in the meantime.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(Error::Io(e)),
+            };
+            if now.duration_since(modified).unwrap_or_default() <= max_age {
+                continue;
+            }
+
+            if self.cancel_upload(upload).await? {
+                expired.push(upload);
+            }
+        }
+
+        Ok(expired)
+    }
+
+    async fn get_manifest_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        file_metadata(self.manifest_path(digest), digest).await
+    }
+
+    async fn get_manifest_media_type(&self, digest: Digest) -> Result<Option<String>, Error> {
+        match tokio::fs::read_to_string(self.manifest_media_type_path(digest)).await {
+            Ok(media_type) => Ok(Some(media_type)),
+            // Manifests stored by older versions have no media type recorded.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::Io(e)),
+        }
     }
 
     async fn get_manifest(
@@ -512,38 +1360,305 @@ impl RegistryStorage for FilesystemStorage {
         &self,
         manifest_reference: &ManifestReference,
         manifest: &[u8],
+        media_type: &str,
+        replace_tag: bool,
     ) -> Result<Digest, Error> {
-        // TODO: Validate all blobs are completely uploaded.
-        let _manifest: ImageManifest =
-            serde_json::from_slice(manifest).map_err(Error::InvalidManifest)?;
+        let _manifest = Manifest::from_slice(manifest).map_err(Error::InvalidManifest)?;
 
         let digest = Digest::from_contents(manifest);
+        let location = manifest_reference.location();
+
+        match manifest_reference.reference() {
+            Reference::Digest(expected) if *expected != digest => {
+                return Err(Error::DigestMismatch)
+            }
+            _ => {}
+        }
+
+        // The media type is written first, a manifest is never visible without it.
+        tokio::fs::write(self.manifest_media_type_path(digest), media_type)
+            .await
+            .map_err(Error::Io)?;
         let dest = self.manifest_path(digest);
         tokio::fs::write(dest, &manifest).await.map_err(Error::Io)?;
 
-        let tag = self.tag_path(
-            manifest_reference.location(),
-            manifest_reference
-                .reference()
-                .as_tag()
-                .ok_or(Error::NotATag)?,
-        );
+        // Every manifest is linked to the image, allowing untagged manifests to be tracked as well.
+        self.link_manifest(
+            self.manifest_link_path(location, digest),
+            location,
+            digest,
+            true,
+        )
+        .await?;
+
+        match manifest_reference.reference() {
+            Reference::Tag(tag) => {
+                self.link_manifest(self.tag_path(location, tag), location, digest, replace_tag)
+                    .await?;
+            }
+            Reference::Digest(_) => {
+                // Ensures the image is known even if it has no tags (yet).
+                tokio::fs::create_dir_all(self.tags_dir(location))
+                    .await
+                    .map_err(Error::Io)?;
+            }
+        }
 
-        let tag_parent = tag.parent().expect("should have parent");
+        Ok(digest)
+    }
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Option<Vec<String>>, Error> {
+        let mut entries = match tokio::fs::read_dir(self.tags_dir(location)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::Io(e)),
+        };
 
-        if !tag_parent.exists() {
-            tokio::fs::create_dir_all(tag_parent)
-                .await
-                .map_err(Error::Io)?;
+        let mut tags = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            // Tags are always valid UTF-8, anything else was not created by us.
+            if let Ok(tag) = entry.file_name().into_string() {
+                tags.push(tag);
+            }
         }
+        tags.sort();
 
-        let tmp_tag = self.temp_tag_path();
+        Ok(Some(tags))
+    }
+    async fn list_repositories(&self) -> Result<Vec<ImageLocation>, Error> {
+        let mut locations = Vec::new();
+
+        // Walk the tags tree, every directory containing a tags directory is an image.
+        let mut pending = vec![(self.tags.clone(), Vec::<String>::new())];
+        while let Some((dir, components)) = pending.pop() {
+            let mut entries = tokio::fs::read_dir(&dir).await.map_err(Error::Io)?;
+            while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+                // Skips temporary tags, which are stored as files in the root of the tags tree.
+                if !entry.file_type().await.map_err(Error::Io)?.is_dir() {
+                    continue;
+                }
+                let Ok(file_name) = entry.file_name().into_string() else {
+                    continue;
+                };
 
-        tokio::fs::symlink(self.blob_rel_path(digest), &tmp_tag)
+                if file_name == TAGS_DIR_NAME {
+                    if let Ok(location) = ImageLocation::new(components.join("/")) {
+                        locations.push(location);
+                    }
+                    continue;
+                }
+
+                let mut child_components = components.clone();
+                child_components.push(file_name);
+                pending.push((entry.path(), child_components));
+            }
+        }
+
+        locations.sort();
+
+        Ok(locations)
+    }
+    async fn list_manifests(&self) -> Result<Vec<Digest>, Error> {
+        let mut entries = tokio::fs::read_dir(&self.manifests)
             .await
             .map_err(Error::Io)?;
-        tokio::fs::rename(tmp_tag, tag).await.map_err(Error::Io)?;
 
-        Ok(digest)
+        let mut digests = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            // Anything not named by a digest was not created by us.
+            if let Some(digest) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            {
+                digests.push(digest);
+            }
+        }
+
+        Ok(digests)
+    }
+
+    async fn list_image_manifests(&self, location: &ImageLocation) -> Result<Vec<Digest>, Error> {
+        let mut digests = Vec::new();
+
+        // Manifests stored by older versions are only linked through tags.
+        let image_dir = self.image_dir(location);
+        for dir in [MANIFESTS_DIR_NAME, TAGS_DIR_NAME] {
+            let mut entries = match tokio::fs::read_dir(image_dir.join(dir)).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(Error::Io(e)),
+            };
+
+            while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+                let target = match tokio::fs::read_link(entry.path()).await {
+                    Ok(target) => target,
+                    // Not a symlink, thus not created by us.
+                    Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => continue,
+                    Err(e) => return Err(Error::Io(e)),
+                };
+
+                if let Some(digest) = target
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.parse().ok())
+                {
+                    digests.push(digest);
+                }
+            }
+        }
+
+        digests.sort();
+        digests.dedup();
+
+        Ok(digests)
+    }
+
+    async fn delete_tag(&self, location: &ImageLocation, tag: &str) -> Result<bool, Error> {
+        match tokio::fs::remove_file(self.tag_path(location, tag)).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(Error::Io(e)),
+        }
+    }
+
+    async fn delete_manifest(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        let manifest_path = self.manifest_path(digest);
+
+        // Manifests are shared, but only ever removed through an image linking them.
+        if !manifest_path.exists() || !self.has_manifest(location, digest).await? {
+            return Ok(false);
+        }
+
+        for tag in self.tags_pointing_to(location, digest).await? {
+            tokio::fs::remove_file(tag).await.map_err(Error::Io)?;
+        }
+
+        match tokio::fs::remove_file(self.manifest_link_path(location, digest)).await {
+            Ok(()) => {}
+            // Manifests stored by older versions are only linked through tags.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(Error::Io(e)),
+        }
+
+        self.remove_unreferenced_manifest(digest).await?;
+
+        Ok(true)
+    }
+
+    async fn delete_untagged_manifest(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        if !self.tags_pointing_to(location, digest).await?.is_empty() {
+            return Ok(false);
+        }
+
+        match tokio::fs::remove_file(self.manifest_link_path(location, digest)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(Error::Io(e)),
+        }
+
+        self.remove_unreferenced_manifest(digest).await?;
+
+        Ok(true)
+    }
+
+    async fn delete_unlinked_manifest(&self, digest: Digest) -> Result<bool, Error> {
+        if !self.manifest_path(digest).exists() {
+            return Ok(false);
+        }
+
+        self.remove_unreferenced_manifest(digest).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::{is_valid_tag, Digest, FilesystemStorage, ImageLocation, RegistryStorage};
+    use crate::test_support::check_storage_conformance;
+
+    #[tokio::test]
+    async fn filesystem_storage_conforms() {
+        let dir = tempdir::TempDir::new("container-registry-conformance")
+            .expect("could not create temporary directory");
+        let storage = FilesystemStorage::new(dir.path()).expect("could not create storage");
+
+        check_storage_conformance(&storage).await;
+    }
+
+    #[tokio::test]
+    async fn rewritten_uploads_are_hashed_correctly() {
+        let dir = tempdir::TempDir::new("container-registry-rewritten-upload")
+            .expect("could not create temporary directory");
+        let storage = FilesystemStorage::new(dir.path()).expect("could not create storage");
+
+        let upload = storage.begin_new_upload().await.unwrap();
+        let mut writer = storage.get_upload_writer(0, upload).await.unwrap();
+        writer.write_all(b"hello world").await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        // Data written in place of the discarded part reaches the upload, but the writer is
+        // dropped before saving its hash state.
+        drop(storage.get_upload_writer(6, upload).await.unwrap());
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(storage.upload_path(upload))
+            .unwrap()
+            .write_all(b"there")
+            .unwrap();
+
+        storage
+            .finalize_upload(upload, Digest::from_contents(b"hello there"))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn validates_image_names() {
+        for valid in [
+            "alpine",
+            "bitnami/nginx",
+            "team/project/service",
+            "a.b_c__d---e/0",
+            "x/y/z/w/v",
+        ] {
+            assert!(ImageLocation::new(valid).is_ok(), "{valid} should be valid");
+        }
+
+        for invalid in [
+            "", "/", "a/", "/a", "a//b", "Alpine", "a/../b", ".", "a..b", "a___b", "a._b", "-a",
+            "a-", "_a", "a b",
+        ] {
+            assert!(
+                ImageLocation::new(invalid).is_err(),
+                "{invalid} should be invalid"
+            );
+        }
+
+        assert!(ImageLocation::new("a".repeat(256)).is_err());
+    }
+
+    #[test]
+    fn validates_tags() {
+        for valid in ["latest", "v1.2.3", "_private", "1", "A-b_c.D"] {
+            assert!(is_valid_tag(valid), "{valid} should be valid");
+        }
+
+        for invalid in ["", ".", "..", "-a", ".hidden", "a/b", "a:b"] {
+            assert!(!is_valid_tag(invalid), "{invalid} should be invalid");
+        }
+
+        assert!(is_valid_tag(&"a".repeat(128)));
+        assert!(!is_valid_tag(&"a".repeat(129)));
     }
 }
@@ -0,0 +1,760 @@
+//! Integrity checks of filesystem storage.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::{
+    hash_file, Digest, Error, FilesystemStorage, ImageLocation, MANIFESTS_DIR_NAME, TAGS_DIR_NAME,
+};
+use crate::{types::Manifest, ImageDigest};
+
+/// Default time after which uploads are considered left behind.
+const DEFAULT_UPLOAD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Name of the directory offending files are moved to when repairing.
+const QUARANTINE_DIR_NAME: &str = "quarantine";
+
+/// An integrity check of a storage directory.
+///
+/// Disk corruption or crashes can leave a storage directory in an inconsistent state. The check
+/// finds
+///
+/// * blobs and manifests whose contents do not match the digest they are named by,
+/// * manifests referencing blobs or manifests that are missing or corrupt,
+/// * tags and manifest links of images that are not symlinks to an intact manifest, and
+/// * files left behind by interrupted operations, i.e. stale uploads, temporary tags and media
+///   types or upload hash states whose manifest or upload is gone.
+///
+/// In repair mode, offending files are moved into a `quarantine` directory inside the storage
+/// root instead of being deleted, allowing them to be inspected or restored. Manifests referencing
+/// missing content are only reported, as removing them would not bring the content back.
+///
+/// Checks can run alongside the registry, but may then report operations in progress as left
+/// behind. Repairs should only be made while the registry is stopped.
+///
+/// ```no_run
+/// use container_registry::storage::IntegrityCheck;
+///
+/// # async fn example() -> Result<(), container_registry::storage::Error> {
+/// let report = IntegrityCheck::new().repair(true).run("/var/lib/registry").await?;
+/// println!("{}", serde_json::to_string_pretty(&report).unwrap());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct IntegrityCheck {
+    /// Whether to quarantine offending files.
+    repair: bool,
+    /// Time without activity after which uploads are reported.
+    upload_ttl: Duration,
+}
+
+impl Default for IntegrityCheck {
+    fn default() -> Self {
+        Self {
+            repair: false,
+            upload_ttl: DEFAULT_UPLOAD_TTL,
+        }
+    }
+}
+
+impl IntegrityCheck {
+    /// Creates a new check, reporting uploads that received no data for a day.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether to move offending files into quarantine.
+    pub fn repair(mut self, repair: bool) -> Self {
+        self.repair = repair;
+        self
+    }
+
+    /// Sets the time without receiving data after which uploads are reported as left behind.
+    pub fn upload_ttl(mut self, upload_ttl: Duration) -> Self {
+        self.upload_ttl = upload_ttl;
+        self
+    }
+
+    /// Checks the storage directory at `root`.
+    ///
+    /// The directory is the one passed to [`ContainerRegistryBuilder::storage`]; it is neither
+    /// initialized nor migrated by the check.
+    ///
+    /// [`ContainerRegistryBuilder::storage`]: crate::ContainerRegistryBuilder::storage
+    pub async fn run<P: AsRef<Path>>(&self, root: P) -> Result<IntegrityReport, Error> {
+        let root = tokio::fs::canonicalize(root).await.map_err(Error::Io)?;
+
+        let quarantine = self.repair.then(|| {
+            let started = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            root.join(QUARANTINE_DIR_NAME)
+                .join(started.as_secs().to_string())
+        });
+
+        let mut checker = Checker {
+            storage: FilesystemStorage::at(&root),
+            root,
+            quarantine,
+            upload_ttl: self.upload_ttl,
+            blobs: HashSet::new(),
+            manifests: HashMap::new(),
+            report: IntegrityReport {
+                repaired: self.repair,
+                issues: Vec::new(),
+            },
+        };
+
+        checker.check_blobs().await?;
+        checker.check_manifests().await?;
+        checker.check_references().await?;
+        checker.check_tags().await?;
+        checker.check_uploads().await?;
+
+        Ok(checker.report)
+    }
+}
+
+/// The outcome of an integrity check.
+///
+/// Serializes to a machine-readable representation, e.g. as JSON:
+///
+/// ```json
+/// {
+///   "repaired": true,
+///   "issues": [
+///     {
+///       "kind": "corrupt_blob",
+///       "path": "blobs/2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+///       "detail": "contents have digest sha256:486ea46224d1bb4fb680f34f7c9ad96a8f24ec88be73ea8e5a6c65260e9cb8a7",
+///       "quarantined": "quarantine/1721212121/blobs/2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+///     }
+///   ]
+/// }
+/// ```
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct IntegrityReport {
+    /// Whether offending files have been quarantined.
+    repaired: bool,
+    /// All issues found.
+    issues: Vec<Issue>,
+}
+
+impl IntegrityReport {
+    /// Returns whether the check ran in repair mode.
+    pub fn is_repaired(&self) -> bool {
+        self.repaired
+    }
+
+    /// Returns whether no issues were found.
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Returns all issues found.
+    pub fn issues(&self) -> &[Issue] {
+        &self.issues
+    }
+}
+
+/// A single issue found by an integrity check.
+#[derive(Clone, Debug, Serialize)]
+pub struct Issue {
+    kind: IssueKind,
+    /// Path of the offending file, relative to the storage root.
+    path: PathBuf,
+    /// Human-readable description.
+    detail: String,
+    /// Path the file was moved to, relative to the storage root.
+    quarantined: Option<PathBuf>,
+}
+
+impl Issue {
+    /// Returns the kind of issue.
+    pub fn kind(&self) -> IssueKind {
+        self.kind
+    }
+
+    /// Returns the path of the offending file, relative to the storage root.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns a human-readable description of the issue.
+    pub fn detail(&self) -> &str {
+        &self.detail
+    }
+
+    /// Returns the path the file has been moved to when repairing, relative to the storage root.
+    pub fn quarantined(&self) -> Option<&Path> {
+        self.quarantined.as_deref()
+    }
+}
+
+/// The kind of an [`Issue`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// A file in `blobs/` whose contents do not match its name.
+    CorruptBlob,
+    /// A file in `manifests/` whose contents do not match its name or that is not a manifest.
+    CorruptManifest,
+    /// A manifest referencing a blob or manifest that is missing or corrupt. Never quarantined.
+    MissingReference,
+    /// A tag or manifest link that is not a symlink to a manifest.
+    BrokenLink,
+    /// A tag or manifest link to a manifest that is missing or corrupt.
+    DanglingLink,
+    /// An upload that has not received data within the upload TTL.
+    LeftoverUpload,
+    /// A temporary tag left behind by an interrupted push.
+    LeftoverTempTag,
+    /// A media type or upload hash state whose manifest or upload is missing.
+    OrphanedSidecar,
+}
+
+/// State of a single integrity check.
+struct Checker {
+    storage: FilesystemStorage,
+    root: PathBuf,
+    /// Directory to move offending files to, if repairing.
+    quarantine: Option<PathBuf>,
+    upload_ttl: Duration,
+    /// Intact blobs.
+    blobs: HashSet<Digest>,
+    /// Intact manifests, along with their paths.
+    manifests: HashMap<Digest, (PathBuf, Manifest)>,
+    report: IntegrityReport,
+}
+
+impl Checker {
+    /// Records an issue, quarantining the file if repairing.
+    async fn issue(&mut self, kind: IssueKind, path: PathBuf, detail: String) -> Result<(), Error> {
+        let quarantined = if kind != IssueKind::MissingReference {
+            self.quarantine(&path).await?
+        } else {
+            None
+        };
+
+        self.report.issues.push(Issue {
+            kind,
+            path: self.relative(&path),
+            detail,
+            quarantined,
+        });
+
+        Ok(())
+    }
+
+    /// Moves a file into quarantine if repairing, returning its new relative path.
+    async fn quarantine(&self, path: &Path) -> Result<Option<PathBuf>, Error> {
+        let Some(ref quarantine) = self.quarantine else {
+            return Ok(None);
+        };
+
+        let relative = self.relative(path);
+        let dest = quarantine.join(&relative);
+        tokio::fs::create_dir_all(dest.parent().expect("should have parent"))
+            .await
+            .map_err(Error::Io)?;
+        tokio::fs::rename(path, &dest).await.map_err(Error::Io)?;
+
+        Ok(Some(self.relative(&dest)))
+    }
+
+    fn relative(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.root).unwrap_or(path).to_owned()
+    }
+
+    async fn check_blobs(&mut self) -> Result<(), Error> {
+        let mut entries = tokio::fs::read_dir(&self.storage.blobs)
+            .await
+            .map_err(Error::Io)?;
+
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            let path = entry.path();
+            let Some(digest) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<Digest>().ok())
+            else {
+                let detail = "file name is not a digest".to_owned();
+                self.issue(IssueKind::CorruptBlob, path, detail).await?;
+                continue;
+            };
+
+            let actual = match hash_file(path.clone()).await {
+                Ok(actual) => actual,
+                // Deleted in the meantime.
+                Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+
+            if actual == digest {
+                self.blobs.insert(digest);
+            } else {
+                let detail = format!("contents have digest {}", ImageDigest::new(actual));
+                self.issue(IssueKind::CorruptBlob, path, detail).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn check_manifests(&mut self) -> Result<(), Error> {
+        let mut entries = tokio::fs::read_dir(&self.storage.manifests)
+            .await
+            .map_err(Error::Io)?;
+
+        let mut media_types = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            let path = entry.path();
+            let file_name = entry.file_name();
+
+            // Media types are checked once all manifests have been.
+            if path.extension().is_some_and(|ext| ext == "media-type") {
+                media_types.push(path);
+                continue;
+            }
+
+            let Some(digest) = file_name
+                .to_str()
+                .and_then(|name| name.parse::<Digest>().ok())
+            else {
+                let detail = "file name is not a digest".to_owned();
+                self.issue(IssueKind::CorruptManifest, path, detail).await?;
+                continue;
+            };
+
+            let manifest_json = match tokio::fs::read(&path).await {
+                Ok(manifest_json) => manifest_json,
+                // Deleted in the meantime.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(Error::Io(e)),
+            };
+
+            let actual = Digest::from_contents(&manifest_json);
+            let detail = if actual != digest {
+                format!("contents have digest {}", ImageDigest::new(actual))
+            } else {
+                match Manifest::from_slice(&manifest_json) {
+                    Ok(manifest) => {
+                        self.manifests.insert(digest, (path, manifest));
+                        continue;
+                    }
+                    Err(err) => format!("not a valid manifest: {err}"),
+                }
+            };
+
+            self.issue(IssueKind::CorruptManifest, path, detail).await?;
+
+            // The media type is useless without its manifest.
+            let media_type_path = self.storage.manifest_media_type_path(digest);
+            if media_type_path.exists() {
+                self.quarantine(&media_type_path).await?;
+            }
+        }
+
+        for path in media_types {
+            // Already quarantined along with a corrupt manifest.
+            if !path.exists() || path.with_extension("").exists() {
+                continue;
+            }
+
+            let detail = "manifest is missing".to_owned();
+            self.issue(IssueKind::OrphanedSidecar, path, detail).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that all content referenced by intact manifests is intact as well.
+    async fn check_references(&mut self) -> Result<(), Error> {
+        let mut missing = Vec::new();
+
+        for (path, manifest) in self.manifests.values() {
+            // External layers are never stored.
+            for descriptor in manifest
+                .blobs()
+                .filter(|descriptor| !descriptor.is_external())
+            {
+                let Ok(blob) = descriptor.digest().parse::<ImageDigest>() else {
+                    continue;
+                };
+                if !self.blobs.contains(&blob.digest()) {
+                    missing.push((path.clone(), format!("blob {blob} is missing or corrupt")));
+                }
+            }
+
+            for descriptor in manifest.manifests() {
+                let Ok(child) = descriptor.digest().parse::<ImageDigest>() else {
+                    continue;
+                };
+                if !self.manifests.contains_key(&child.digest()) {
+                    missing.push((
+                        path.clone(),
+                        format!("manifest {child} is missing or corrupt"),
+                    ));
+                }
+            }
+        }
+
+        // Reported in a stable order, as manifests are kept in a hash map.
+        missing.sort();
+        for (path, detail) in missing {
+            self.issue(IssueKind::MissingReference, path, detail)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn check_tags(&mut self) -> Result<(), Error> {
+        let mut pending = vec![(self.storage.tags.clone(), Vec::<String>::new())];
+
+        while let Some((dir, components)) = pending.pop() {
+            let mut entries = tokio::fs::read_dir(&dir).await.map_err(Error::Io)?;
+            while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+                let file_type = entry.file_type().await.map_err(Error::Io)?;
+                let Ok(file_name) = entry.file_name().into_string() else {
+                    continue;
+                };
+
+                // Temporary tags are created in the root of the tags tree, then moved into place.
+                if components.is_empty()
+                    && file_type.is_symlink()
+                    && Uuid::parse_str(&file_name).is_ok()
+                {
+                    let detail = "temporary tag left behind".to_owned();
+                    self.issue(IssueKind::LeftoverTempTag, entry.path(), detail)
+                        .await?;
+                    continue;
+                }
+
+                if !file_type.is_dir() {
+                    continue;
+                }
+
+                if file_name == TAGS_DIR_NAME || file_name == MANIFESTS_DIR_NAME {
+                    if let Ok(location) = ImageLocation::new(components.join("/")) {
+                        self.check_links(&location, entry.path()).await?;
+                    }
+                    continue;
+                }
+
+                let mut child_components = components.clone();
+                child_components.push(file_name);
+                pending.push((entry.path(), child_components));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the tags or manifest links in a directory of an image.
+    async fn check_links(&mut self, location: &ImageLocation, dir: PathBuf) -> Result<(), Error> {
+        let mut entries = tokio::fs::read_dir(&dir).await.map_err(Error::Io)?;
+
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            let path = entry.path();
+            let target = match tokio::fs::read_link(&path).await {
+                Ok(target) => target,
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                    let detail = "not a symlink".to_owned();
+                    self.issue(IssueKind::BrokenLink, path, detail).await?;
+                    continue;
+                }
+                Err(e) => return Err(Error::Io(e)),
+            };
+
+            let Some(digest) = target
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse::<Digest>().ok())
+            else {
+                let detail = format!("target {} is not a manifest", target.display());
+                self.issue(IssueKind::BrokenLink, path, detail).await?;
+                continue;
+            };
+
+            let detail = match tokio::fs::canonicalize(&path).await {
+                Ok(resolved) if resolved != self.storage.manifest_path(digest) => {
+                    let detail = format!(
+                        "target {} resolves outside of the manifests directory of {location}",
+                        target.display()
+                    );
+                    self.issue(IssueKind::BrokenLink, path, detail).await?;
+                    continue;
+                }
+                Ok(_) if self.manifests.contains_key(&digest) => continue,
+                Ok(_) => format!("manifest {} is corrupt", ImageDigest::new(digest)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    format!("manifest {} is missing", ImageDigest::new(digest))
+                }
+                Err(e) => return Err(Error::Io(e)),
+            };
+
+            self.issue(IssueKind::DanglingLink, path, detail).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn check_uploads(&mut self) -> Result<(), Error> {
+        let now = SystemTime::now();
+        let mut entries = tokio::fs::read_dir(&self.storage.uploads)
+            .await
+            .map_err(Error::Io)?;
+
+        let mut hashes = Vec::new();
+        while let Some(entry) = entries.next_entry().await.map_err(Error::Io)? {
+            let file_name = entry.file_name();
+            let Some(name) = file_name.to_str() else {
+                continue;
+            };
+
+            // Hash states are checked once all uploads have been.
+            if let Some(upload) = name
+                .strip_suffix(".hash")
+                .and_then(|name| Uuid::parse_str(name).ok())
+            {
+                hashes.push(upload);
+                continue;
+            }
+
+            // Anything else in the uploads directory was not created by us.
+            let Some(upload) = name
+                .strip_suffix(".partial")
+                .and_then(|name| Uuid::parse_str(name).ok())
+            else {
+                continue;
+            };
+
+            let modified = match entry.metadata().await.and_then(|m| m.modified()) {
+                Ok(modified) => modified,
+                // The upload may have been finalized or cancelled in the meantime.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(Error::Io(e)),
+            };
+            let idle = now.duration_since(modified).unwrap_or_default();
+            if idle <= self.upload_ttl {
+                continue;
+            }
+
+            let detail = format!("no data received for {}s", idle.as_secs());
+            self.issue(IssueKind::LeftoverUpload, entry.path(), detail)
+                .await?;
+
+            // The hash state is useless without its upload.
+            let hash_path = self.storage.upload_hash_path(upload);
+            if hash_path.exists() {
+                self.quarantine(&hash_path).await?;
+            }
+        }
+
+        for upload in hashes {
+            let hash_path = self.storage.upload_hash_path(upload);
+            // Already quarantined along with a left over upload.
+            if !hash_path.exists() || self.storage.upload_path(upload).exists() {
+                continue;
+            }
+
+            let detail = "upload is missing".to_owned();
+            self.issue(IssueKind::OrphanedSidecar, hash_path, detail)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use tokio::io::AsyncWriteExt;
+    use uuid::Uuid;
+
+    use super::{IntegrityCheck, IssueKind};
+    use crate::{
+        storage::{
+            Digest, FilesystemStorage, ImageLocation, ManifestReference, Reference, RegistryStorage,
+        },
+        types::OCI_IMAGE_MANIFEST,
+        ImageDigest,
+    };
+
+    /// Starts an upload containing the given data.
+    async fn create_upload(storage: &FilesystemStorage, contents: &[u8]) -> Uuid {
+        let upload = storage.begin_new_upload().await.unwrap();
+        let mut writer = storage.get_upload_writer(0, upload).await.unwrap();
+        writer.write_all(contents).await.unwrap();
+        writer.shutdown().await.unwrap();
+        upload
+    }
+
+    /// Stores a blob through an upload.
+    async fn create_blob(storage: &FilesystemStorage, contents: &[u8]) -> Digest {
+        let upload = create_upload(storage, contents).await;
+
+        let digest = Digest::from_contents(contents);
+        storage.finalize_upload(upload, digest).await.unwrap();
+        digest
+    }
+
+    /// Returns the kinds and paths of all issues, sorted.
+    fn issues(report: &super::IntegrityReport) -> Vec<(IssueKind, String)> {
+        let mut issues: Vec<_> = report
+            .issues()
+            .iter()
+            .map(|issue| (issue.kind(), issue.path().display().to_string()))
+            .collect();
+        issues.sort_by(|a, b| a.1.cmp(&b.1));
+        issues
+    }
+
+    #[tokio::test]
+    async fn finds_and_quarantines_damage() {
+        let dir = tempdir::TempDir::new("container-registry-fsck").unwrap();
+        let root = dir.path();
+        let storage = FilesystemStorage::new(root).unwrap();
+        let location = ImageLocation::new("fsck/sample").unwrap();
+
+        let config = create_blob(&storage, b"{}").await;
+        let layer = create_blob(&storage, b"layer").await;
+        let manifest = serde_json::to_vec(&serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_MANIFEST,
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "size": 2,
+                "digest": ImageDigest::new(config).to_string(),
+            },
+            "layers": [{
+                "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                "size": 5,
+                "digest": ImageDigest::new(layer).to_string(),
+            }],
+        }))
+        .unwrap();
+        let manifest_digest = storage
+            .put_manifest(
+                &ManifestReference::new(location.clone(), Reference::new_tag("latest")),
+                &manifest,
+                OCI_IMAGE_MANIFEST,
+                true,
+            )
+            .await
+            .unwrap();
+
+        let report = IntegrityCheck::new().run(root).await.unwrap();
+        assert!(report.is_clean(), "{report:?}");
+
+        // Damage the storage in every way detected.
+        std::fs::write(root.join("blobs").join(layer.to_string()), b"rotten").unwrap();
+        let bogus_manifest = Digest::from_contents(b"bogus");
+        std::fs::write(
+            root.join("manifests").join(bogus_manifest.to_string()),
+            b"bogus",
+        )
+        .unwrap();
+        let tags_dir = root.join("tags/fsck/sample/_tags");
+        std::os::unix::fs::symlink(
+            Path::new("../../../../manifests").join(Digest::from_contents(b"gone").to_string()),
+            tags_dir.join("dangling"),
+        )
+        .unwrap();
+        std::fs::write(tags_dir.join("plain"), b"not a link").unwrap();
+        let temp_tag = uuid::Uuid::new_v4().to_string();
+        std::os::unix::fs::symlink("nowhere", root.join("tags").join(&temp_tag)).unwrap();
+        let upload = create_upload(&storage, b"abandoned").await;
+        let upload_hash_path = storage.upload_hash_path(upload);
+        assert!(upload_hash_path.exists());
+        let orphaned_media_type = storage.manifest_media_type_path(Digest::from_contents(b"gone"));
+        std::fs::write(&orphaned_media_type, OCI_IMAGE_MANIFEST).unwrap();
+        let orphaned_upload = create_upload(&storage, b"vanished").await;
+        std::fs::remove_file(storage.upload_path(orphaned_upload)).unwrap();
+
+        let mut expected = vec![
+            (IssueKind::CorruptBlob, format!("blobs/{layer}")),
+            (
+                IssueKind::MissingReference,
+                format!("manifests/{manifest_digest}"),
+            ),
+            (
+                IssueKind::CorruptManifest,
+                format!("manifests/{bogus_manifest}"),
+            ),
+            (IssueKind::LeftoverTempTag, format!("tags/{temp_tag}")),
+            (
+                IssueKind::DanglingLink,
+                "tags/fsck/sample/_tags/dangling".to_owned(),
+            ),
+            (
+                IssueKind::BrokenLink,
+                "tags/fsck/sample/_tags/plain".to_owned(),
+            ),
+            (
+                IssueKind::LeftoverUpload,
+                format!("uploads/{upload}.partial"),
+            ),
+            (
+                IssueKind::OrphanedSidecar,
+                format!("manifests/{}.media-type", Digest::from_contents(b"gone")),
+            ),
+            (
+                IssueKind::OrphanedSidecar,
+                format!("uploads/{orphaned_upload}.hash"),
+            ),
+        ];
+        expected.sort_by(|a, b| a.1.cmp(&b.1));
+
+        let check = IntegrityCheck::new().upload_ttl(Duration::ZERO);
+        let report = check.clone().run(root).await.unwrap();
+        assert!(!report.is_repaired());
+        assert_eq!(issues(&report), expected);
+        assert!(report
+            .issues()
+            .iter()
+            .all(|issue| issue.quarantined().is_none()));
+
+        let report = check.clone().repair(true).run(root).await.unwrap();
+        assert!(report.is_repaired());
+        assert_eq!(issues(&report), expected);
+        for issue in report.issues() {
+            if issue.kind() == IssueKind::MissingReference {
+                assert!(issue.quarantined().is_none());
+                assert!(root.join(issue.path()).exists());
+            } else {
+                let quarantined = issue.quarantined().expect("should be quarantined");
+                assert!(quarantined.starts_with("quarantine"));
+                assert!(root.join(quarantined).symlink_metadata().is_ok());
+                assert!(root.join(issue.path()).symlink_metadata().is_err());
+            }
+        }
+
+        assert!(!upload_hash_path.exists());
+
+        // Only the manifest missing its layer remains.
+        let report = check.run(root).await.unwrap();
+        assert_eq!(
+            issues(&report),
+            vec![(
+                IssueKind::MissingReference,
+                format!("manifests/{manifest_digest}")
+            )]
+        );
+        assert_eq!(
+            report.issues()[0].detail(),
+            format!("blob {} is missing or corrupt", ImageDigest::new(layer))
+        );
+    }
+}
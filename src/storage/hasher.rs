@@ -0,0 +1,185 @@
+//! SHA-256 hashing that can be suspended and resumed.
+//!
+//! The hashers of the `sha2` crate cannot be saved, so uploads spanning multiple requests would
+//! have to be hashed again in full when finalizing them. [`ResumableSha256`] drives the SHA-256
+//! compression function directly, which allows its state to be stored alongside an upload.
+
+use sha2::{compress256, digest::generic_array::GenericArray};
+
+use super::{Digest, SHA256_LEN};
+
+/// Initial hash value of SHA-256, see FIPS 180-4, section 5.3.3.
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Size of the blocks the compression function operates on.
+const BLOCK_LEN: usize = 64;
+
+/// Size of the serialized state, excluding buffered data.
+const STATE_LEN: usize = 8 * 4 + 8;
+
+/// A SHA-256 hasher whose state can be serialized.
+#[derive(Clone, Debug)]
+pub(crate) struct ResumableSha256 {
+    state: [u32; 8],
+    /// Number of bytes hashed so far.
+    len: u64,
+    /// Data not yet filling a complete block.
+    buffer: Vec<u8>,
+}
+
+impl Default for ResumableSha256 {
+    fn default() -> Self {
+        Self {
+            state: INITIAL_STATE,
+            len: 0,
+            buffer: Vec::with_capacity(BLOCK_LEN),
+        }
+    }
+}
+
+impl ResumableSha256 {
+    /// Creates a hasher that has not hashed any data yet.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of bytes hashed so far.
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Hashes more data.
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+
+        if !self.buffer.is_empty() {
+            let missing = (BLOCK_LEN - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..missing]);
+            data = &data[missing..];
+
+            if self.buffer.len() < BLOCK_LEN {
+                return;
+            }
+            compress256(&mut self.state, &[*GenericArray::from_slice(&self.buffer)]);
+            self.buffer.clear();
+        }
+
+        let mut blocks = data.chunks_exact(BLOCK_LEN);
+        for block in &mut blocks {
+            compress256(&mut self.state, &[*GenericArray::from_slice(block)]);
+        }
+        self.buffer.extend_from_slice(blocks.remainder());
+    }
+
+    /// Returns the digest of all data hashed.
+    pub(crate) fn finalize(mut self) -> Digest {
+        let bit_len = self.len * 8;
+
+        // Padding consists of a single set bit, zeros and the length, see FIPS 180-4, 5.1.1.
+        self.buffer.push(0x80);
+        if self.buffer.len() > BLOCK_LEN - 8 {
+            self.buffer.resize(BLOCK_LEN, 0);
+            compress256(&mut self.state, &[*GenericArray::from_slice(&self.buffer)]);
+            self.buffer.clear();
+        }
+        self.buffer.resize(BLOCK_LEN - 8, 0);
+        self.buffer.extend_from_slice(&bit_len.to_be_bytes());
+        compress256(&mut self.state, &[*GenericArray::from_slice(&self.buffer)]);
+
+        let mut digest = [0; SHA256_LEN];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        Digest::new(digest)
+    }
+
+    /// Serializes the hasher, to be restored through [`Self::from_bytes`].
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(STATE_LEN + self.buffer.len());
+        for word in self.state {
+            bytes.extend_from_slice(&word.to_be_bytes());
+        }
+        bytes.extend_from_slice(&self.len.to_be_bytes());
+        bytes.extend_from_slice(&self.buffer);
+        bytes
+    }
+
+    /// Restores a hasher serialized through [`Self::to_bytes`].
+    ///
+    /// Returns `None` if `bytes` is not a valid serialized hasher.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (words, rest) = bytes.split_first_chunk::<32>()?;
+        let (len, buffer) = rest.split_first_chunk::<8>()?;
+        let len = u64::from_be_bytes(*len);
+
+        if buffer.len() as u64 != len % BLOCK_LEN as u64 {
+            return None;
+        }
+
+        let mut state = [0; 8];
+        for (word, bytes) in state.iter_mut().zip(words.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().expect("chunks have four bytes"));
+        }
+
+        let mut restored = Self {
+            state,
+            len,
+            buffer: Vec::with_capacity(BLOCK_LEN),
+        };
+        restored.buffer.extend_from_slice(buffer);
+        Some(restored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResumableSha256;
+    use crate::storage::Digest;
+
+    #[test]
+    fn matches_sha256() {
+        let data: Vec<u8> = (0..1000u32).map(|n| (n * 7 % 251) as u8).collect();
+
+        // Covers lengths around the block and padding boundaries.
+        for len in [0, 1, 55, 56, 57, 63, 64, 65, 119, 120, 128, 1000] {
+            let data = &data[..len];
+            let expected = Digest::from_contents(data);
+
+            let mut hasher = ResumableSha256::new();
+            hasher.update(data);
+            assert_eq!(hasher.len(), len as u64);
+            assert_eq!(hasher.finalize(), expected, "length {len}");
+
+            for split in [0, 1, 63, 64, 100]
+                .into_iter()
+                .filter(|&split| split <= len)
+            {
+                let mut hasher = ResumableSha256::new();
+                hasher.update(&data[..split]);
+
+                let mut hasher = ResumableSha256::from_bytes(&hasher.to_bytes())
+                    .expect("serialized hasher should be restorable");
+                hasher.update(&data[split..]);
+                assert_eq!(
+                    hasher.finalize(),
+                    expected,
+                    "length {len}, split at {split}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_state() {
+        let mut hasher = ResumableSha256::new();
+        hasher.update(b"abc");
+        let bytes = hasher.to_bytes();
+
+        assert!(ResumableSha256::from_bytes(&bytes).is_some());
+        assert!(ResumableSha256::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        assert!(ResumableSha256::from_bytes(&bytes[..20]).is_none());
+        assert!(ResumableSha256::from_bytes(b"").is_none());
+    }
+}
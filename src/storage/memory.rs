@@ -0,0 +1,461 @@
+//! In-memory storage backend.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::async_trait;
+use tokio::io::AsyncWrite;
+use uuid::Uuid;
+
+use super::{
+    BlobMetadata, BlobReader, Digest, Error, ImageLocation, ManifestReference, Reference,
+    RegistryStorage,
+};
+use crate::types::Manifest;
+
+/// A storage backend keeping all data in memory.
+///
+/// Nothing is persisted, all data is lost once the storage is dropped. Mainly useful for testing
+/// and ephemeral registries.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    /// The actual contents, shared with upload writers.
+    state: Arc<Mutex<State>>,
+}
+
+/// Contents of a [`MemoryStorage`].
+#[derive(Debug, Default)]
+struct State {
+    uploads: HashMap<Uuid, Upload>,
+    blobs: HashMap<Digest, StoredBlob>,
+    manifests: HashMap<Digest, StoredManifest>,
+    images: BTreeMap<ImageLocation, Image>,
+}
+
+impl State {
+    /// Removes a manifest, unless an image still references it.
+    /// Removes a manifest unless an image still links it, returning whether it has been removed.
+    fn remove_unreferenced_manifest(&mut self, digest: Digest) -> bool {
+        // Manifests are shared between all images, only remove it if no one else is using it.
+        if self
+            .images
+            .values()
+            .any(|image| image.manifests.contains(&digest))
+        {
+            return false;
+        }
+
+        self.manifests.remove(&digest).is_some()
+    }
+}
+
+/// An upload in progress.
+#[derive(Debug)]
+struct Upload {
+    data: Vec<u8>,
+    /// Time of the last write, used to expire abandoned uploads.
+    last_activity: Instant,
+}
+
+impl Upload {
+    fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            last_activity: Instant::now(),
+        }
+    }
+}
+
+/// A finished blob.
+#[derive(Debug)]
+struct StoredBlob {
+    data: Arc<[u8]>,
+    modified: SystemTime,
+}
+
+impl StoredBlob {
+    fn metadata(&self, digest: Digest) -> BlobMetadata {
+        BlobMetadata::new(digest, self.data.len() as u64).with_modified(self.modified)
+    }
+}
+
+/// A manifest, along with the media type it was pushed as.
+#[derive(Debug)]
+struct StoredManifest {
+    data: Vec<u8>,
+    media_type: String,
+    modified: SystemTime,
+}
+
+/// The manifests referenced by an image.
+#[derive(Debug, Default)]
+struct Image {
+    tags: BTreeMap<String, Digest>,
+    /// All manifests of the image, tagged or not.
+    manifests: BTreeSet<Digest>,
+}
+
+impl MemoryStorage {
+    /// Creates a new, empty storage.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        lock(&self.state)
+    }
+}
+
+/// Locks the state.
+///
+/// The lock is never held across an await point or while calling out, thus a poisoned lock only
+/// results from a bug inside this module.
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().expect("memory storage lock poisoned")
+}
+
+/// Writes to an upload, starting at a fixed offset.
+struct UploadWriter {
+    state: Arc<Mutex<State>>,
+    upload: Uuid,
+    position: usize,
+}
+
+impl AsyncWrite for UploadWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = lock(&self.state);
+        let Some(upload) = state.uploads.get_mut(&self.upload) else {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "upload no longer exists",
+            )));
+        };
+
+        let end = self.position + buf.len();
+        if upload.data.len() < end {
+            upload.data.resize(end, 0);
+        }
+        upload.data[self.position..end].copy_from_slice(buf);
+        upload.last_activity = Instant::now();
+        drop(state);
+
+        self.position = end;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl RegistryStorage for MemoryStorage {
+    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+        let upload = Uuid::new_v4();
+        self.state().uploads.insert(upload, Upload::new());
+
+        Ok(upload)
+    }
+
+    async fn get_blob_reader(&self, digest: Digest) -> Result<Option<Box<dyn BlobReader>>, Error> {
+        Ok(self
+            .state()
+            .blobs
+            .get(&digest)
+            .map(|blob| Box::new(io::Cursor::new(blob.data.clone())) as Box<dyn BlobReader>))
+    }
+
+    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        Ok(self
+            .state()
+            .blobs
+            .get(&digest)
+            .map(|blob| blob.metadata(digest)))
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        Ok(self
+            .state()
+            .blobs
+            .iter()
+            .map(|(&digest, blob)| blob.metadata(digest))
+            .collect())
+    }
+
+    async fn delete_blob(&self, digest: Digest) -> Result<bool, Error> {
+        Ok(self.state().blobs.remove(&digest).is_some())
+    }
+
+    async fn get_upload_size(&self, upload: Uuid) -> Result<Option<u64>, Error> {
+        Ok(self
+            .state()
+            .uploads
+            .get(&upload)
+            .map(|upload| upload.data.len() as u64))
+    }
+
+    async fn get_upload_writer(
+        &self,
+        start_at: u64,
+        upload: Uuid,
+    ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
+        let mut state = self.state();
+        let Some(data) = state.uploads.get_mut(&upload) else {
+            return Err(Error::UploadDoesNotExist);
+        };
+
+        let position = usize::try_from(start_at).map_err(|_| Error::InvalidUploadOffset)?;
+        if position > data.data.len() {
+            return Err(Error::InvalidUploadOffset);
+        }
+
+        // Discard anything past the starting point, e.g. from a previously failed chunk.
+        data.data.truncate(position);
+        data.last_activity = Instant::now();
+
+        Ok(Box::new(UploadWriter {
+            state: self.state.clone(),
+            upload,
+            position,
+        }))
+    }
+
+    async fn finalize_upload(&self, upload: Uuid, hash: Digest) -> Result<(), Error> {
+        // Hashing happens outside the lock, the upload is put back if it does not match.
+        let data = self
+            .state()
+            .uploads
+            .remove(&upload)
+            .ok_or(Error::UploadDoesNotExist)?;
+
+        if Digest::from_contents(&data.data) != hash {
+            self.state().uploads.insert(upload, data);
+            return Err(Error::DigestMismatch);
+        }
+
+        self.state().blobs.insert(
+            hash,
+            StoredBlob {
+                data: data.data.into(),
+                modified: SystemTime::now(),
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn cancel_upload(&self, upload: Uuid) -> Result<bool, Error> {
+        Ok(self.state().uploads.remove(&upload).is_some())
+    }
+
+    async fn expire_uploads(&self, max_age: Duration) -> Result<Vec<Uuid>, Error> {
+        let mut expired = Vec::new();
+
+        self.state().uploads.retain(|&upload, data| {
+            let keep = data.last_activity.elapsed() <= max_age;
+            if !keep {
+                expired.push(upload);
+            }
+            keep
+        });
+
+        Ok(expired)
+    }
+
+    async fn get_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let state = self.state();
+
+        let digest = match manifest_reference.reference() {
+            Reference::Tag(tag) => {
+                let Some(digest) = state
+                    .images
+                    .get(manifest_reference.location())
+                    .and_then(|image| image.tags.get(tag))
+                else {
+                    return Ok(None);
+                };
+                *digest
+            }
+            Reference::Digest(digest) => *digest,
+        };
+
+        Ok(state
+            .manifests
+            .get(&digest)
+            .map(|manifest| manifest.data.clone()))
+    }
+
+    async fn put_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+        manifest: &[u8],
+        media_type: &str,
+        replace_tag: bool,
+    ) -> Result<Digest, Error> {
+        let _manifest = Manifest::from_slice(manifest).map_err(Error::InvalidManifest)?;
+
+        let digest = Digest::from_contents(manifest);
+        if let Reference::Digest(expected) = manifest_reference.reference() {
+            if *expected != digest {
+                return Err(Error::DigestMismatch);
+            }
+        }
+
+        let mut state = self.state();
+        if let Reference::Tag(tag) = manifest_reference.reference() {
+            let current = state
+                .images
+                .get(manifest_reference.location())
+                .and_then(|image| image.tags.get(tag));
+            if !replace_tag && current.is_some_and(|current| *current != digest) {
+                return Err(Error::TagExists);
+            }
+        }
+
+        state.manifests.insert(
+            digest,
+            StoredManifest {
+                data: manifest.to_vec(),
+                media_type: media_type.to_owned(),
+                modified: SystemTime::now(),
+            },
+        );
+
+        let image = state
+            .images
+            .entry(manifest_reference.location().clone())
+            .or_default();
+        image.manifests.insert(digest);
+        if let Reference::Tag(tag) = manifest_reference.reference() {
+            image.tags.insert(tag.clone(), digest);
+        }
+
+        Ok(digest)
+    }
+
+    async fn get_manifest_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        Ok(self.state().manifests.get(&digest).map(|manifest| {
+            BlobMetadata::new(digest, manifest.data.len() as u64).with_modified(manifest.modified)
+        }))
+    }
+
+    async fn get_manifest_media_type(&self, digest: Digest) -> Result<Option<String>, Error> {
+        Ok(self
+            .state()
+            .manifests
+            .get(&digest)
+            .map(|manifest| manifest.media_type.clone()))
+    }
+
+    async fn list_manifests(&self) -> Result<Vec<Digest>, Error> {
+        Ok(self.state().manifests.keys().copied().collect())
+    }
+
+    async fn list_image_manifests(&self, location: &ImageLocation) -> Result<Vec<Digest>, Error> {
+        Ok(self
+            .state()
+            .images
+            .get(location)
+            .map(|image| image.manifests.iter().copied().collect())
+            .unwrap_or_default())
+    }
+
+    async fn delete_tag(&self, location: &ImageLocation, tag: &str) -> Result<bool, Error> {
+        Ok(self
+            .state()
+            .images
+            .get_mut(location)
+            .and_then(|image| image.tags.remove(tag))
+            .is_some())
+    }
+
+    async fn delete_manifest(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        let mut state = self.state();
+
+        if !state.manifests.contains_key(&digest) {
+            return Ok(false);
+        }
+
+        // Manifests are shared, but only ever removed through an image linking them.
+        let Some(image) = state
+            .images
+            .get_mut(location)
+            .filter(|image| image.manifests.contains(&digest))
+        else {
+            return Ok(false);
+        };
+        image.tags.retain(|_, tagged| *tagged != digest);
+        image.manifests.remove(&digest);
+
+        state.remove_unreferenced_manifest(digest);
+
+        Ok(true)
+    }
+
+    async fn delete_untagged_manifest(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        let mut state = self.state();
+
+        let Some(image) = state.images.get_mut(location) else {
+            return Ok(false);
+        };
+        if image.tags.values().any(|tagged| *tagged == digest) || !image.manifests.remove(&digest) {
+            return Ok(false);
+        }
+
+        state.remove_unreferenced_manifest(digest);
+
+        Ok(true)
+    }
+
+    async fn delete_unlinked_manifest(&self, digest: Digest) -> Result<bool, Error> {
+        Ok(self.state().remove_unreferenced_manifest(digest))
+    }
+
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Option<Vec<String>>, Error> {
+        Ok(self
+            .state()
+            .images
+            .get(location)
+            .map(|image| image.tags.keys().cloned().collect()))
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<ImageLocation>, Error> {
+        Ok(self.state().images.keys().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryStorage;
+    use crate::test_support::check_storage_conformance;
+
+    #[tokio::test]
+    async fn memory_storage_conforms() {
+        check_storage_conformance(&MemoryStorage::new()).await;
+    }
+}
@@ -0,0 +1,1254 @@
+//! S3-compatible object storage backend.
+//!
+//! Requires the `s3` feature to be enabled.
+//!
+//! Objects are laid out similar to the filesystem storage, below an optional key prefix:
+//!
+//! * `uploads/<uuid>/started` marks an upload as existing, while its data is stored in chunk
+//!   objects `uploads/<uuid>/chunks/<offset>`. The state of hashing the data written so far is
+//!   kept in `uploads/<uuid>/hash`, thus finalizing an upload does not require reading it again.
+//! * `blobs/<digest>` and `manifests/<digest>` hold blobs and manifests. The media type of a
+//!   manifest is stored as its content type.
+//! * `tags/<name>/_tags/<tag>` are small objects containing the digest of the tagged manifest,
+//!   while empty `tags/<name>/_manifests/<digest>` objects link all manifests to an image.
+//!
+//! Uploads are not stored as S3 multipart uploads, as the parts of those cannot be read back,
+//! which is necessary both to discard data when an upload is resumed at an earlier offset and to
+//! verify the digest. Instead, every chunk object except the last is kept at least as large as the
+//! minimum part size, allowing the final blob to be assembled from the chunks through server-side
+//! copies once the upload has been verified.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use aws_sdk_s3::{
+    presigning::PresigningConfig,
+    primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart, Object},
+    Client,
+};
+use axum::async_trait;
+use futures::future::BoxFuture;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use uuid::Uuid;
+
+use super::{
+    BlobMetadata, BlobReader, Digest, Error, ImageLocation, ManifestReference, Reference,
+    RegistryStorage, ResumableSha256, MANIFESTS_DIR_NAME, TAGS_DIR_NAME,
+};
+use crate::types::Manifest;
+
+#[cfg(test)]
+mod tests;
+
+/// Minimum size of every part but the last of an S3 multipart upload.
+const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+
+/// Size at which upload writers store the data buffered so far as a chunk object.
+const CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// A storage backend keeping all data in an S3-compatible bucket.
+///
+/// The registry itself keeps no state, thus any number of instances may share a bucket.
+///
+/// Immutable tags rely on the service honoring conditional writes (`If-None-Match: *`) to reject
+/// concurrent pushes of the same tag. Tags are checked before and after writing as well, but on
+/// services ignoring the header, two pushes racing for a new tag may both appear to succeed.
+///
+/// ```no_run
+/// # async fn example() {
+/// use container_registry::storage::S3Storage;
+///
+/// let config = aws_config::load_from_env().await;
+/// let storage = S3Storage::new(aws_sdk_s3::Client::new(&config), "my-bucket").prefix("registry");
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct S3Storage {
+    bucket: Bucket,
+    /// Lifetime of presigned blob URLs, if blob downloads should be redirected.
+    presign_blobs: Option<Duration>,
+}
+
+impl S3Storage {
+    /// Creates a new S3 storage using the given bucket.
+    ///
+    /// Most S3-compatible stand-ins (e.g. MinIO) require the client to use path-style addressing.
+    pub fn new<B: Into<String>>(client: Client, bucket: B) -> Self {
+        Self {
+            bucket: Bucket {
+                client,
+                name: bucket.into(),
+                prefix: String::new(),
+            },
+            presign_blobs: None,
+        }
+    }
+
+    /// Stores all objects below a key prefix, allowing the bucket to be shared.
+    pub fn prefix<P: Into<String>>(mut self, prefix: P) -> Self {
+        let mut prefix = prefix.into();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        self.bucket.prefix = prefix;
+        self
+    }
+
+    /// Redirects blob downloads to presigned URLs valid for the given duration.
+    ///
+    /// Clients then download blobs from the bucket directly instead of through the registry. The
+    /// duration may not exceed seven days.
+    pub fn presign_blobs(mut self, expires_in: Duration) -> Self {
+        self.presign_blobs = Some(expires_in);
+        self
+    }
+
+    fn blob_key(digest: Digest) -> String {
+        format!("blobs/{digest}")
+    }
+
+    fn manifest_key(digest: Digest) -> String {
+        format!("manifests/{digest}")
+    }
+
+    fn tags_prefix(location: &ImageLocation) -> String {
+        format!("tags/{location}/{TAGS_DIR_NAME}/")
+    }
+
+    fn tag_key(location: &ImageLocation, tag: &str) -> String {
+        format!("{}{tag}", Self::tags_prefix(location))
+    }
+
+    fn manifest_links_prefix(location: &ImageLocation) -> String {
+        format!("tags/{location}/{MANIFESTS_DIR_NAME}/")
+    }
+
+    fn manifest_link_key(location: &ImageLocation, digest: Digest) -> String {
+        format!("{}{digest}", Self::manifest_links_prefix(location))
+    }
+
+    /// Returns the chunks of an upload, sorted by offset, or `None` if the upload does not exist.
+    async fn upload_chunks(&self, upload: Uuid) -> Result<Option<Vec<Chunk>>, Error> {
+        let objects = self.bucket.list(&upload_prefix(upload)).await?;
+        if objects.is_empty() {
+            return Ok(None);
+        }
+
+        let chunks_prefix = chunks_prefix(upload);
+        let mut chunks: Vec<_> = objects
+            .iter()
+            .filter_map(|(key, object)| {
+                let offset = key.strip_prefix(&chunks_prefix)?.parse().ok()?;
+                Some(Chunk {
+                    offset,
+                    size: object.size().unwrap_or_default() as u64,
+                })
+            })
+            .collect();
+        chunks.sort_by_key(|chunk| chunk.offset);
+
+        Ok(Some(chunks))
+    }
+
+    /// Returns a hasher that has hashed the first `len` bytes of an upload.
+    ///
+    /// Resumes from the saved hash state of the upload, only reading data not covered by it.
+    async fn upload_hasher(
+        &self,
+        upload: Uuid,
+        chunks: &[Chunk],
+        len: u64,
+    ) -> Result<ResumableSha256, Error> {
+        let saved = self
+            .bucket
+            .get(&upload_hash_key(upload))
+            .await?
+            .and_then(|state| ResumableSha256::from_bytes(&state));
+
+        // The saved state lags behind if storing it failed, and is ahead if data has been
+        // discarded since.
+        let mut hasher = saved
+            .filter(|hasher| hasher.len() <= len)
+            .unwrap_or_default();
+
+        for chunk in chunks {
+            let start = hasher.len().max(chunk.offset);
+            let end = (chunk.offset + chunk.size).min(len);
+            if start >= end {
+                continue;
+            }
+
+            let range = format!("bytes={}-{}", start - chunk.offset, end - chunk.offset - 1);
+            let mut body = self
+                .bucket
+                .get_stream(&chunk_key(upload, chunk.offset), Some(range))
+                .await?
+                .ok_or(Error::UploadDoesNotExist)?;
+            while let Some(data) = body.try_next().await.map_err(s3_error)? {
+                hasher.update(&data);
+            }
+        }
+
+        Ok(hasher)
+    }
+
+    /// Removes a manifest, unless an image still links it. Returns whether it has been removed.
+    async fn remove_unreferenced_manifest(&self, digest: Digest) -> Result<bool, Error> {
+        // Manifests are shared between all images, only remove it if no one else is using it.
+        for other in self.list_repositories().await? {
+            if self
+                .bucket
+                .head(&Self::manifest_link_key(&other, digest))
+                .await?
+                .is_some()
+            {
+                return Ok(false);
+            }
+        }
+
+        self.bucket.delete(&Self::manifest_key(digest)).await?;
+
+        Ok(true)
+    }
+
+    /// Returns the digest a tag points to.
+    async fn tag_target(
+        &self,
+        location: &ImageLocation,
+        tag: &str,
+    ) -> Result<Option<Digest>, Error> {
+        let Some(target) = self.bucket.get(&Self::tag_key(location, tag)).await? else {
+            return Ok(None);
+        };
+
+        // Tags are always written by us, anything unparsable is treated as missing.
+        Ok(std::str::from_utf8(&target)
+            .ok()
+            .and_then(|target| target.parse().ok()))
+    }
+}
+
+/// A bucket, along with the prefix all keys are stored under.
+#[derive(Clone, Debug)]
+struct Bucket {
+    client: Client,
+    name: String,
+    prefix: String,
+}
+
+/// Metadata of an object, as returned by a `HEAD` request.
+#[derive(Debug)]
+struct ObjectInfo {
+    size: u64,
+    content_type: Option<String>,
+    modified: Option<SystemTime>,
+}
+
+impl ObjectInfo {
+    fn metadata(&self, digest: Digest) -> BlobMetadata {
+        let metadata = BlobMetadata::new(digest, self.size);
+        match self.modified {
+            Some(modified) => metadata.with_modified(modified),
+            None => metadata,
+        }
+    }
+}
+
+/// Converts an S3 client error into a storage error.
+fn s3_error<E>(err: E) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    Error::Io(io::Error::other(err))
+}
+
+impl Bucket {
+    fn key(&self, path: &str) -> String {
+        format!("{}{path}", self.prefix)
+    }
+
+    /// Returns the metadata of an object.
+    async fn head(&self, path: &str) -> Result<Option<ObjectInfo>, Error> {
+        match self
+            .client
+            .head_object()
+            .bucket(&self.name)
+            .key(self.key(path))
+            .send()
+            .await
+        {
+            Ok(output) => Ok(Some(ObjectInfo {
+                size: output.content_length().unwrap_or_default() as u64,
+                modified: output
+                    .last_modified()
+                    .and_then(|modified| SystemTime::try_from(*modified).ok()),
+                content_type: output.content_type,
+            })),
+            Err(err) if err.as_service_error().is_some_and(|err| err.is_not_found()) => Ok(None),
+            Err(err) => Err(s3_error(err)),
+        }
+    }
+
+    /// Returns the body of an object, starting at an optional byte range.
+    async fn get_stream(
+        &self,
+        path: &str,
+        range: Option<String>,
+    ) -> Result<Option<ByteStream>, Error> {
+        match self
+            .client
+            .get_object()
+            .bucket(&self.name)
+            .key(self.key(path))
+            .set_range(range)
+            .send()
+            .await
+        {
+            Ok(output) => Ok(Some(output.body)),
+            Err(err)
+                if err
+                    .as_service_error()
+                    .is_some_and(|err| err.is_no_such_key()) =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(s3_error(err)),
+        }
+    }
+
+    /// Returns the contents of a (small) object.
+    async fn get(&self, path: &str) -> Result<Option<Vec<u8>>, Error> {
+        let Some(body) = self.get_stream(path, None).await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(body.collect().await.map_err(s3_error)?.to_vec()))
+    }
+
+    async fn put(
+        &self,
+        path: &str,
+        data: Vec<u8>,
+        content_type: Option<&str>,
+    ) -> Result<(), Error> {
+        self.client
+            .put_object()
+            .bucket(&self.name)
+            .key(self.key(path))
+            .set_content_type(content_type.map(ToOwned::to_owned))
+            .body(data.into())
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        Ok(())
+    }
+
+    /// Creates an object, unless it exists already.
+    ///
+    /// Returns `false` if the object existed.
+    async fn put_new(&self, path: &str, data: Vec<u8>) -> Result<bool, Error> {
+        match self
+            .client
+            .put_object()
+            .bucket(&self.name)
+            .key(self.key(path))
+            .if_none_match("*")
+            .body(data.into())
+            .send()
+            .await
+        {
+            Ok(_) => Ok(true),
+            // Existing objects fail the precondition, concurrent writes may conflict instead.
+            Err(err)
+                if err
+                    .raw_response()
+                    .is_some_and(|response| matches!(response.status().as_u16(), 409 | 412)) =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(s3_error(err)),
+        }
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), Error> {
+        self.client
+            .delete_object()
+            .bucket(&self.name)
+            .key(self.key(path))
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        Ok(())
+    }
+
+    /// Copies an object inside the bucket.
+    async fn copy(&self, from: &str, to: &str) -> Result<(), Error> {
+        self.client
+            .copy_object()
+            .bucket(&self.name)
+            .key(self.key(to))
+            .copy_source(format!("{}/{}", self.name, self.key(from)))
+            .send()
+            .await
+            .map_err(s3_error)?;
+
+        Ok(())
+    }
+
+    /// Lists all objects below a prefix, returning their keys relative to the bucket prefix.
+    async fn list(&self, prefix: &str) -> Result<Vec<(String, Object)>, Error> {
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.name)
+            .prefix(self.key(prefix))
+            .into_paginator()
+            .send();
+
+        let mut objects = Vec::new();
+        while let Some(page) = pages.next().await {
+            for object in page.map_err(s3_error)?.contents.unwrap_or_default() {
+                if let Some(key) = object
+                    .key()
+                    .and_then(|key| key.strip_prefix(&self.prefix))
+                    .map(ToOwned::to_owned)
+                {
+                    objects.push((key, object));
+                }
+            }
+        }
+
+        Ok(objects)
+    }
+
+    /// Lists the names of all objects directly below a prefix.
+    async fn list_names(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        Ok(self
+            .list(prefix)
+            .await?
+            .into_iter()
+            .filter_map(|(key, _)| key.strip_prefix(prefix).map(ToOwned::to_owned))
+            .collect())
+    }
+
+    /// Lists the names of all "directories" directly below a prefix, i.e. the distinct next path
+    /// components of keys with further components.
+    async fn list_dirs(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let full_prefix = self.key(prefix);
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.name)
+            .prefix(&full_prefix)
+            .delimiter("/")
+            .into_paginator()
+            .send();
+
+        let mut dirs = Vec::new();
+        while let Some(page) = pages.next().await {
+            for common_prefix in page.map_err(s3_error)?.common_prefixes.unwrap_or_default() {
+                if let Some(dir) = common_prefix
+                    .prefix()
+                    .and_then(|dir| dir.strip_prefix(&full_prefix))
+                    .and_then(|dir| dir.strip_suffix('/'))
+                {
+                    dirs.push(dir.to_owned());
+                }
+            }
+        }
+
+        Ok(dirs)
+    }
+
+    /// Assembles an object from others through a server-side multipart copy.
+    ///
+    /// All sources but the last must be at least [`MIN_PART_SIZE`] large.
+    async fn concat(&self, sources: &[String], to: &str) -> Result<(), Error> {
+        let key = self.key(to);
+        let upload_id = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.name)
+            .key(&key)
+            .send()
+            .await
+            .map_err(s3_error)?
+            .upload_id
+            .ok_or_else(|| Error::Io(io::Error::other("missing multipart upload id")))?;
+
+        let mut parts = Vec::new();
+        for (part_number, source) in (1..).zip(sources) {
+            let result = self
+                .client
+                .upload_part_copy()
+                .bucket(&self.name)
+                .key(&key)
+                .upload_id(&upload_id)
+                .part_number(part_number)
+                .copy_source(format!("{}/{}", self.name, self.key(source)))
+                .send()
+                .await;
+
+            let e_tag = match result {
+                Ok(output) => output.copy_part_result.and_then(|result| result.e_tag),
+                Err(err) => {
+                    self.abort(&key, &upload_id).await;
+                    return Err(s3_error(err));
+                }
+            };
+
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(e_tag)
+                    .build(),
+            );
+        }
+
+        let result = self
+            .client
+            .complete_multipart_upload()
+            .bucket(&self.name)
+            .key(&key)
+            .upload_id(&upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await;
+
+        if let Err(err) = result {
+            self.abort(&key, &upload_id).await;
+            return Err(s3_error(err));
+        }
+
+        Ok(())
+    }
+
+    /// Aborts a multipart upload, the original error is more interesting than any failure here.
+    async fn abort(&self, key: &str, upload_id: &str) {
+        let _ = self
+            .client
+            .abort_multipart_upload()
+            .bucket(&self.name)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await;
+    }
+}
+
+/// A chunk of an upload.
+#[derive(Debug)]
+struct Chunk {
+    offset: u64,
+    size: u64,
+}
+
+fn upload_prefix(upload: Uuid) -> String {
+    format!("uploads/{upload}/")
+}
+
+fn upload_marker_key(upload: Uuid) -> String {
+    format!("uploads/{upload}/started")
+}
+
+fn chunks_prefix(upload: Uuid) -> String {
+    format!("uploads/{upload}/chunks/")
+}
+
+fn chunk_key(upload: Uuid, offset: u64) -> String {
+    // Zero-padded, thus chunks are listed in order.
+    format!("{}{offset:020}", chunks_prefix(upload))
+}
+
+fn upload_hash_key(upload: Uuid) -> String {
+    format!("uploads/{upload}/hash")
+}
+
+/// Writes to an upload, buffering data until it can be stored as a chunk.
+///
+/// The buffer always holds the contents of the last chunk, which is rewritten on every flush until
+/// it is large enough to start a new chunk. Data is hashed as it passes through, the hash state is
+/// stored after the chunks on every flush.
+struct UploadWriter {
+    bucket: Bucket,
+    upload: Uuid,
+    /// Offset of the chunk currently being written.
+    chunk_start: u64,
+    buffer: Vec<u8>,
+    /// Whether the buffer holds data not stored yet.
+    dirty: bool,
+    hasher: ResumableSha256,
+    /// Whether the hash state changed since it was last stored.
+    hash_dirty: bool,
+    /// A chunk or hash state being stored.
+    pending: Option<BoxFuture<'static, Result<(), Error>>>,
+}
+
+impl UploadWriter {
+    /// Stores the current buffer as a chunk in the background.
+    fn store_chunk(&mut self, data: Vec<u8>) {
+        let bucket = self.bucket.clone();
+        let key = chunk_key(self.upload, self.chunk_start);
+        self.pending = Some(Box::pin(async move { bucket.put(&key, data, None).await }));
+        self.dirty = false;
+    }
+
+    /// Stores the hash state in the background.
+    fn store_hash_state(&mut self) {
+        let bucket = self.bucket.clone();
+        let key = upload_hash_key(self.upload);
+        let state = self.hasher.to_bytes();
+        self.pending = Some(Box::pin(async move { bucket.put(&key, state, None).await }));
+        self.hash_dirty = false;
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(pending) = self.pending.as_mut() {
+            let result = ready!(pending.as_mut().poll(cx));
+            self.pending = None;
+            result.map_err(io::Error::other)?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UploadWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.poll_pending(cx))?;
+
+        self.buffer.extend_from_slice(buf);
+        self.hasher.update(buf);
+        self.dirty = true;
+        self.hash_dirty = true;
+
+        if self.buffer.len() >= CHUNK_SIZE {
+            let data = std::mem::take(&mut self.buffer);
+            let len = data.len() as u64;
+            self.store_chunk(data);
+            self.chunk_start += len;
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+
+        if self.dirty {
+            let data = self.buffer.clone();
+            self.store_chunk(data);
+            ready!(self.poll_pending(cx))?;
+        }
+
+        // The state must never cover data that has not been stored yet.
+        if self.hash_dirty {
+            self.store_hash_state();
+            ready!(self.poll_pending(cx))?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// State of a [`S3BlobReader`].
+enum ReaderState {
+    /// No request made for the current position yet.
+    Idle,
+    /// Requesting the blob, starting at the current position.
+    Requesting(BoxFuture<'static, Result<Option<ByteStream>, Error>>),
+    /// Reading the response body.
+    Reading(Pin<Box<dyn AsyncRead + Send>>),
+}
+
+/// Reads a blob, issuing a new ranged request after every seek.
+struct S3BlobReader {
+    bucket: Bucket,
+    key: String,
+    size: u64,
+    position: u64,
+    state: ReaderState,
+}
+
+impl AsyncRead for S3BlobReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            match self.state {
+                ReaderState::Idle => {
+                    if self.position >= self.size {
+                        return Poll::Ready(Ok(()));
+                    }
+
+                    let bucket = self.bucket.clone();
+                    let key = self.key.clone();
+                    let range = format!("bytes={}-", self.position);
+                    self.state = ReaderState::Requesting(Box::pin(async move {
+                        bucket.get_stream(&key, Some(range)).await
+                    }));
+                }
+                ReaderState::Requesting(ref mut request) => {
+                    let body = ready!(request.as_mut().poll(cx))
+                        .map_err(io::Error::other)?
+                        .ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::NotFound, "blob no longer exists")
+                        })?;
+                    self.state = ReaderState::Reading(Box::pin(body.into_async_read()));
+                }
+                ReaderState::Reading(ref mut body) => {
+                    let filled = buf.filled().len();
+                    ready!(body.as_mut().poll_read(cx, buf))?;
+                    let read = buf.filled().len() - filled;
+                    self.position += read as u64;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSeek for S3BlobReader {
+    fn start_seek(mut self: Pin<&mut Self>, position: io::SeekFrom) -> io::Result<()> {
+        let position = match position {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            io::SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek position"))?;
+
+        if position != self.position {
+            self.position = position;
+            self.state = ReaderState::Idle;
+        }
+
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+#[async_trait]
+impl RegistryStorage for S3Storage {
+    async fn begin_new_upload(&self) -> Result<Uuid, Error> {
+        let upload = Uuid::new_v4();
+        self.bucket
+            .put(&upload_marker_key(upload), Vec::new(), None)
+            .await?;
+
+        Ok(upload)
+    }
+
+    async fn get_blob_reader(&self, digest: Digest) -> Result<Option<Box<dyn BlobReader>>, Error> {
+        let key = Self::blob_key(digest);
+        let Some(info) = self.bucket.head(&key).await? else {
+            return Ok(None);
+        };
+
+        Ok(Some(Box::new(S3BlobReader {
+            bucket: self.bucket.clone(),
+            key,
+            size: info.size,
+            position: 0,
+            state: ReaderState::Idle,
+        })))
+    }
+
+    async fn get_blob_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        Ok(self
+            .bucket
+            .head(&Self::blob_key(digest))
+            .await?
+            .map(|info| info.metadata(digest)))
+    }
+
+    async fn list_blobs(&self) -> Result<Vec<BlobMetadata>, Error> {
+        Ok(self
+            .bucket
+            .list("blobs/")
+            .await?
+            .iter()
+            .filter_map(|(key, object)| {
+                let digest = key.strip_prefix("blobs/")?.parse().ok()?;
+                let info = ObjectInfo {
+                    size: object.size().unwrap_or_default() as u64,
+                    content_type: None,
+                    modified: object
+                        .last_modified()
+                        .and_then(|modified| SystemTime::try_from(*modified).ok()),
+                };
+                Some(info.metadata(digest))
+            })
+            .collect())
+    }
+
+    async fn get_blob_url(&self, digest: Digest) -> Result<Option<String>, Error> {
+        let Some(expires_in) = self.presign_blobs else {
+            return Ok(None);
+        };
+
+        let request = self
+            .bucket
+            .client
+            .get_object()
+            .bucket(&self.bucket.name)
+            .key(self.bucket.key(&Self::blob_key(digest)))
+            .presigned(PresigningConfig::expires_in(expires_in).map_err(s3_error)?)
+            .await
+            .map_err(s3_error)?;
+
+        Ok(Some(request.uri().to_owned()))
+    }
+
+    async fn delete_blob(&self, digest: Digest) -> Result<bool, Error> {
+        let key = Self::blob_key(digest);
+        if self.bucket.head(&key).await?.is_none() {
+            return Ok(false);
+        }
+
+        self.bucket.delete(&key).await?;
+        Ok(true)
+    }
+
+    async fn get_upload_size(&self, upload: Uuid) -> Result<Option<u64>, Error> {
+        Ok(self.upload_chunks(upload).await?.map(|chunks| {
+            chunks
+                .last()
+                .map(|chunk| chunk.offset + chunk.size)
+                .unwrap_or_default()
+        }))
+    }
+
+    async fn get_upload_writer(
+        &self,
+        start_at: u64,
+        upload: Uuid,
+    ) -> Result<Box<dyn AsyncWrite + Send + Unpin>, Error> {
+        let chunks = self
+            .upload_chunks(upload)
+            .await?
+            .ok_or(Error::UploadDoesNotExist)?;
+
+        let size = chunks
+            .last()
+            .map(|chunk| chunk.offset + chunk.size)
+            .unwrap_or_default();
+        if start_at > size {
+            return Err(Error::InvalidUploadOffset);
+        }
+
+        // Continue the chunk containing the starting point, unless it is complete already.
+        let (chunk_start, buffer) = match chunks.iter().rev().find(|chunk| chunk.offset <= start_at)
+        {
+            Some(chunk)
+                if start_at < chunk.offset + chunk.size
+                    || (chunk.size as usize) < MIN_PART_SIZE =>
+            {
+                let keep = start_at - chunk.offset;
+                let buffer = if keep == 0 {
+                    Vec::new()
+                } else {
+                    let range = format!("bytes=0-{}", keep - 1);
+                    let body = self
+                        .bucket
+                        .get_stream(&chunk_key(upload, chunk.offset), Some(range))
+                        .await?
+                        .ok_or(Error::UploadDoesNotExist)?;
+                    body.collect().await.map_err(s3_error)?.to_vec()
+                };
+
+                (chunk.offset, buffer)
+            }
+            _ => (start_at, Vec::new()),
+        };
+
+        let hasher = self.upload_hasher(upload, &chunks, start_at).await?;
+
+        // The state is stored before discarding any data. Otherwise a state covering discarded
+        // data would be taken for one covering whatever is written in its place.
+        self.bucket
+            .put(&upload_hash_key(upload), hasher.to_bytes(), None)
+            .await?;
+
+        // Discard anything past the starting point, e.g. from a previously failed chunk.
+        let truncated = start_at < size;
+        for chunk in &chunks {
+            if chunk.offset > chunk_start
+                || (truncated && chunk.offset == chunk_start && buffer.is_empty())
+            {
+                self.bucket.delete(&chunk_key(upload, chunk.offset)).await?;
+            }
+        }
+        if truncated && !buffer.is_empty() {
+            self.bucket
+                .put(&chunk_key(upload, chunk_start), buffer.clone(), None)
+                .await?;
+        }
+
+        // Rewriting the marker records the activity.
+        self.bucket
+            .put(&upload_marker_key(upload), Vec::new(), None)
+            .await?;
+
+        Ok(Box::new(UploadWriter {
+            bucket: self.bucket.clone(),
+            upload,
+            chunk_start,
+            buffer,
+            dirty: false,
+            hasher,
+            hash_dirty: false,
+            pending: None,
+        }))
+    }
+
+    async fn finalize_upload(&self, upload: Uuid, hash: Digest) -> Result<(), Error> {
+        let chunks = self
+            .upload_chunks(upload)
+            .await?
+            .ok_or(Error::UploadDoesNotExist)?;
+        let chunk_keys: Vec<_> = chunks
+            .iter()
+            .map(|chunk| chunk_key(upload, chunk.offset))
+            .collect();
+
+        // Usually the saved hash state covers the whole upload, so nothing needs to be read.
+        let size = chunks
+            .last()
+            .map(|chunk| chunk.offset + chunk.size)
+            .unwrap_or_default();
+        if self.upload_hasher(upload, &chunks, size).await?.finalize() != hash {
+            return Err(Error::DigestMismatch);
+        }
+
+        let blob_key = Self::blob_key(hash);
+        match chunk_keys.as_slice() {
+            [] => self.bucket.put(&blob_key, Vec::new(), None).await?,
+            [single] => self.bucket.copy(single, &blob_key).await?,
+            multiple => self.bucket.concat(multiple, &blob_key).await?,
+        }
+
+        self.cancel_upload(upload).await?;
+
+        Ok(())
+    }
+
+    async fn cancel_upload(&self, upload: Uuid) -> Result<bool, Error> {
+        let objects = self.bucket.list(&upload_prefix(upload)).await?;
+        if objects.is_empty() {
+            return Ok(false);
+        }
+
+        // The marker goes last, so the upload does not appear to be gone while data remains.
+        let marker = upload_marker_key(upload);
+        for (key, _) in objects.iter().filter(|(key, _)| *key != marker) {
+            self.bucket.delete(key).await?;
+        }
+        self.bucket.delete(&marker).await?;
+
+        Ok(true)
+    }
+
+    async fn expire_uploads(&self, max_age: Duration) -> Result<Vec<Uuid>, Error> {
+        let now = SystemTime::now();
+
+        // Every write touches an object of the upload, the most recent one reflects the activity.
+        let mut last_activity = std::collections::HashMap::<Uuid, SystemTime>::new();
+        for (key, object) in self.bucket.list("uploads/").await? {
+            let Some(upload) = key
+                .strip_prefix("uploads/")
+                .and_then(|rest| rest.split('/').next())
+                .and_then(|upload| Uuid::parse_str(upload).ok())
+            else {
+                continue;
+            };
+            let Some(modified) = object
+                .last_modified()
+                .and_then(|modified| SystemTime::try_from(*modified).ok())
+            else {
+                continue;
+            };
+
+            let entry = last_activity.entry(upload).or_insert(modified);
+            *entry = (*entry).max(modified);
+        }
+
+        let mut expired = Vec::new();
+        for (upload, modified) in last_activity {
+            if now.duration_since(modified).unwrap_or_default() <= max_age {
+                continue;
+            }
+
+            if self.cancel_upload(upload).await? {
+                expired.push(upload);
+            }
+        }
+
+        Ok(expired)
+    }
+
+    async fn get_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let digest = match manifest_reference.reference() {
+            Reference::Tag(tag) => {
+                let Some(digest) = self.tag_target(manifest_reference.location(), tag).await?
+                else {
+                    return Ok(None);
+                };
+                digest
+            }
+            Reference::Digest(digest) => *digest,
+        };
+
+        self.bucket.get(&Self::manifest_key(digest)).await
+    }
+
+    async fn put_manifest(
+        &self,
+        manifest_reference: &ManifestReference,
+        manifest: &[u8],
+        media_type: &str,
+        replace_tag: bool,
+    ) -> Result<Digest, Error> {
+        let _manifest = Manifest::from_slice(manifest).map_err(Error::InvalidManifest)?;
+
+        let digest = Digest::from_contents(manifest);
+        let location = manifest_reference.location();
+
+        if let Reference::Digest(expected) = manifest_reference.reference() {
+            if *expected != digest {
+                return Err(Error::DigestMismatch);
+            }
+        }
+
+        self.bucket
+            .put(
+                &Self::manifest_key(digest),
+                manifest.to_vec(),
+                Some(media_type),
+            )
+            .await?;
+        self.bucket
+            .put(&Self::manifest_link_key(location, digest), Vec::new(), None)
+            .await?;
+
+        if let Reference::Tag(tag) = manifest_reference.reference() {
+            let key = Self::tag_key(location, tag);
+            let target = digest.to_string().into_bytes();
+
+            if replace_tag {
+                self.bucket.put(&key, target, None).await?;
+            } else {
+                // Services ignoring conditional writes would overwrite the tag, thus it is checked
+                // beforehand. Reading it back catches concurrent pushes that overwrote ours.
+                match self.tag_target(location, tag).await? {
+                    Some(existing) if existing != digest => return Err(Error::TagExists),
+                    Some(_) => {}
+                    None => {
+                        self.bucket.put_new(&key, target).await?;
+                        if self.tag_target(location, tag).await? != Some(digest) {
+                            return Err(Error::TagExists);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(digest)
+    }
+
+    async fn get_manifest_metadata(&self, digest: Digest) -> Result<Option<BlobMetadata>, Error> {
+        Ok(self
+            .bucket
+            .head(&Self::manifest_key(digest))
+            .await?
+            .map(|info| info.metadata(digest)))
+    }
+
+    async fn get_manifest_media_type(&self, digest: Digest) -> Result<Option<String>, Error> {
+        Ok(self
+            .bucket
+            .head(&Self::manifest_key(digest))
+            .await?
+            .and_then(|info| info.content_type))
+    }
+
+    async fn list_manifests(&self) -> Result<Vec<Digest>, Error> {
+        Ok(self
+            .bucket
+            .list_names("manifests/")
+            .await?
+            .iter()
+            .filter_map(|name| name.parse().ok())
+            .collect())
+    }
+
+    async fn list_image_manifests(&self, location: &ImageLocation) -> Result<Vec<Digest>, Error> {
+        let mut digests: Vec<Digest> = self
+            .bucket
+            .list_names(&Self::manifest_links_prefix(location))
+            .await?
+            .iter()
+            .filter_map(|name| name.parse().ok())
+            .collect();
+        digests.sort();
+
+        Ok(digests)
+    }
+
+    async fn delete_tag(&self, location: &ImageLocation, tag: &str) -> Result<bool, Error> {
+        let key = Self::tag_key(location, tag);
+        if self.bucket.head(&key).await?.is_none() {
+            return Ok(false);
+        }
+
+        self.bucket.delete(&key).await?;
+        Ok(true)
+    }
+
+    async fn delete_manifest(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        let manifest_key = Self::manifest_key(digest);
+        if self.bucket.head(&manifest_key).await?.is_none() {
+            return Ok(false);
+        }
+
+        let mut tags = Vec::new();
+        for tag in self.bucket.list_names(&Self::tags_prefix(location)).await? {
+            if self.tag_target(location, &tag).await? == Some(digest) {
+                tags.push(tag);
+            }
+        }
+
+        // Manifests are shared, but only ever removed through an image linking them.
+        let link_key = Self::manifest_link_key(location, digest);
+        if tags.is_empty() && self.bucket.head(&link_key).await?.is_none() {
+            return Ok(false);
+        }
+
+        for tag in tags {
+            self.bucket.delete(&Self::tag_key(location, &tag)).await?;
+        }
+        self.bucket.delete(&link_key).await?;
+
+        self.remove_unreferenced_manifest(digest).await?;
+
+        Ok(true)
+    }
+
+    async fn delete_untagged_manifest(
+        &self,
+        location: &ImageLocation,
+        digest: Digest,
+    ) -> Result<bool, Error> {
+        for tag in self.bucket.list_names(&Self::tags_prefix(location)).await? {
+            if self.tag_target(location, &tag).await? == Some(digest) {
+                return Ok(false);
+            }
+        }
+
+        let link_key = Self::manifest_link_key(location, digest);
+        if self.bucket.head(&link_key).await?.is_none() {
+            return Ok(false);
+        }
+        self.bucket.delete(&link_key).await?;
+
+        self.remove_unreferenced_manifest(digest).await?;
+
+        Ok(true)
+    }
+
+    async fn delete_unlinked_manifest(&self, digest: Digest) -> Result<bool, Error> {
+        if self
+            .bucket
+            .head(&Self::manifest_key(digest))
+            .await?
+            .is_none()
+        {
+            return Ok(false);
+        }
+
+        self.remove_unreferenced_manifest(digest).await
+    }
+
+    async fn list_tags(&self, location: &ImageLocation) -> Result<Option<Vec<String>>, Error> {
+        let mut tags = self.bucket.list_names(&Self::tags_prefix(location)).await?;
+
+        if tags.is_empty()
+            && self
+                .bucket
+                .list(&Self::manifest_links_prefix(location))
+                .await?
+                .is_empty()
+        {
+            return Ok(None);
+        }
+        tags.sort();
+
+        Ok(Some(tags))
+    }
+
+    async fn list_repositories(&self) -> Result<Vec<ImageLocation>, Error> {
+        let mut locations = Vec::new();
+
+        // Walks the tags tree directory by directory, instead of listing every tag stored.
+        let mut pending = vec![Vec::<String>::new()];
+        while let Some(components) = pending.pop() {
+            let mut prefix = "tags/".to_owned();
+            for component in &components {
+                prefix.push_str(component);
+                prefix.push('/');
+            }
+
+            let mut is_image = false;
+            for dir in self.bucket.list_dirs(&prefix).await? {
+                // Name components cannot start with an underscore, thus these are never images.
+                if dir == TAGS_DIR_NAME || dir == MANIFESTS_DIR_NAME {
+                    is_image = true;
+                    continue;
+                }
+
+                let mut child_components = components.clone();
+                child_components.push(dir);
+                pending.push(child_components);
+            }
+
+            if is_image {
+                if let Ok(location) = ImageLocation::new(components.join("/")) {
+                    locations.push(location);
+                }
+            }
+        }
+        locations.sort();
+
+        Ok(locations)
+    }
+}
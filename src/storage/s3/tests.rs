@@ -0,0 +1,609 @@
+//! Tests of the S3 backend against a minimal in-process stand-in for S3.
+//!
+//! The stand-in implements just the subset of the S3 API used by the backend, including the
+//! minimum part size restriction of multipart uploads. It ignores authentication entirely.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use aws_sdk_s3::{
+    config::{
+        BehaviorVersion, Credentials, Region, RequestChecksumCalculation,
+        ResponseChecksumValidation,
+    },
+    primitives::{DateTime, DateTimeFormat},
+    Client,
+};
+use axum::{
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, Query, State},
+    http::{
+        header::{
+            CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LAST_MODIFIED,
+            LOCATION, RANGE,
+        },
+        HeaderMap, Method, Request, StatusCode, Uri,
+    },
+    response::{IntoResponse, Response},
+    Router,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tower::ServiceExt;
+
+use super::{S3Storage, MIN_PART_SIZE};
+use crate::{
+    storage::{Digest, Error, ImageLocation, ManifestReference, Reference, RegistryStorage},
+    test_support::check_storage_conformance,
+    types::OCI_IMAGE_MANIFEST,
+    ContainerRegistry, ImageDigest,
+};
+
+/// An object stored in the stand-in.
+struct StoredObject {
+    data: Vec<u8>,
+    content_type: Option<String>,
+    modified: SystemTime,
+}
+
+impl StoredObject {
+    fn new(data: Vec<u8>, content_type: Option<String>) -> Self {
+        Self {
+            data,
+            content_type,
+            modified: SystemTime::now(),
+        }
+    }
+}
+
+/// State of the S3 stand-in, for a single bucket.
+#[derive(Default)]
+struct StandIn {
+    objects: BTreeMap<String, StoredObject>,
+    /// Multipart uploads by ID, along with their key and parts.
+    multipart: HashMap<String, (String, BTreeMap<i32, Vec<u8>>)>,
+    /// Number of multipart uploads completed so far.
+    completed_multipart: usize,
+    /// Keys of all objects read so far.
+    read_keys: Vec<String>,
+    /// Whether to ignore `If-None-Match`, like some S3-compatible services do.
+    ignore_conditional_writes: bool,
+}
+
+type SharedStandIn = Arc<Mutex<StandIn>>;
+
+fn xml_response(status: StatusCode, body: String) -> Response {
+    (status, [(CONTENT_TYPE, "application/xml")], body).into_response()
+}
+
+fn error_response(status: StatusCode, code: &str) -> Response {
+    xml_response(
+        status,
+        format!("<Error><Code>{code}</Code><Message>{code}</Message></Error>"),
+    )
+}
+
+fn timestamp(time: SystemTime, format: DateTimeFormat) -> String {
+    DateTime::from(time).fmt(format).expect("valid timestamp")
+}
+
+fn percent_decode(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match (bytes[idx], raw.get(idx + 1..idx + 3)) {
+            (b'%', Some(hex)) => {
+                decoded.push(u8::from_str_radix(hex, 16).expect("invalid percent encoding"));
+                idx += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                idx += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).expect("keys should be valid UTF-8")
+}
+
+/// Parses a `bytes=<start>-[<end>]` range.
+fn parse_range(headers: &HeaderMap, len: usize) -> Option<(usize, usize)> {
+    let range = headers.get(RANGE)?.to_str().ok()?.strip_prefix("bytes=")?;
+    let (start, end) = range.split_once('-')?;
+    let end = match end {
+        "" => len - 1,
+        end => end.parse::<usize>().ok()?.min(len - 1),
+    };
+    Some((start.parse().ok()?, end))
+}
+
+/// Handles every request to the stand-in.
+async fn handle(
+    State(state): State<SharedStandIn>,
+    method: Method,
+    uri: Uri,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    // Path-style addressing, the bucket name is not checked.
+    let key = uri
+        .path()
+        .trim_start_matches('/')
+        .split_once('/')
+        .map(|(_, key)| percent_decode(key))
+        .unwrap_or_default();
+
+    let mut state = state.lock().unwrap();
+
+    match method {
+        Method::GET if key.is_empty() => {
+            let prefix = query.get("prefix").map(String::as_str).unwrap_or_default();
+            let delimiter = query.get("delimiter").map(String::as_str);
+
+            let mut contents = String::new();
+            let mut common_prefixes = std::collections::BTreeSet::new();
+            for (key, object) in state
+                .objects
+                .range(prefix.to_owned()..)
+                .take_while(|(key, _)| key.starts_with(prefix))
+            {
+                // Keys continuing past the delimiter are rolled up into a common prefix.
+                if let Some(end) = delimiter.and_then(|delimiter| {
+                    let rest = &key[prefix.len()..];
+                    rest.find(delimiter).map(|idx| idx + delimiter.len())
+                }) {
+                    common_prefixes.insert(&key[..prefix.len() + end]);
+                    continue;
+                }
+
+                contents.push_str(&format!(
+                    "<Contents><Key>{key}</Key><LastModified>{}</LastModified>\
+                     <Size>{}</Size></Contents>",
+                    timestamp(object.modified, DateTimeFormat::DateTime),
+                    object.data.len()
+                ));
+            }
+            for common_prefix in common_prefixes {
+                contents.push_str(&format!(
+                    "<CommonPrefixes><Prefix>{common_prefix}</Prefix></CommonPrefixes>"
+                ));
+            }
+
+            xml_response(
+                StatusCode::OK,
+                format!(
+                    "<ListBucketResult><IsTruncated>false</IsTruncated>{contents}</ListBucketResult>"
+                ),
+            )
+        }
+        Method::GET | Method::HEAD => {
+            if method == Method::GET {
+                state.read_keys.push(key.clone());
+            }
+
+            let Some(object) = state.objects.get(&key) else {
+                return if method == Method::HEAD {
+                    StatusCode::NOT_FOUND.into_response()
+                } else {
+                    error_response(StatusCode::NOT_FOUND, "NoSuchKey")
+                };
+            };
+
+            let mut response = Response::builder()
+                .header(
+                    LAST_MODIFIED,
+                    timestamp(object.modified, DateTimeFormat::HttpDate),
+                )
+                .header(
+                    CONTENT_TYPE,
+                    object
+                        .content_type
+                        .as_deref()
+                        .unwrap_or("binary/octet-stream"),
+                );
+
+            let data = match parse_range(&headers, object.data.len()) {
+                Some((start, end)) => {
+                    response = response.status(StatusCode::PARTIAL_CONTENT).header(
+                        CONTENT_RANGE,
+                        format!("bytes {start}-{end}/{}", object.data.len()),
+                    );
+                    object.data[start..=end].to_vec()
+                }
+                None => object.data.clone(),
+            };
+
+            response = response.header(CONTENT_LENGTH, data.len());
+            let body = if method == Method::HEAD {
+                Body::empty()
+            } else {
+                Body::from(data)
+            };
+            response.body(body).unwrap()
+        }
+        Method::PUT => {
+            let data = match headers.get("x-amz-copy-source") {
+                Some(source) => {
+                    let source = percent_decode(source.to_str().unwrap());
+                    let (_, source_key) = source.trim_start_matches('/').split_once('/').unwrap();
+                    match state.objects.get(source_key) {
+                        Some(object) => object.data.clone(),
+                        None => return error_response(StatusCode::NOT_FOUND, "NoSuchKey"),
+                    }
+                }
+                None => body.to_vec(),
+            };
+            let copied = headers.contains_key("x-amz-copy-source");
+
+            if let (Some(upload_id), Some(part_number)) =
+                (query.get("uploadId"), query.get("partNumber"))
+            {
+                let Some((_, parts)) = state.multipart.get_mut(upload_id) else {
+                    return error_response(StatusCode::NOT_FOUND, "NoSuchUpload");
+                };
+                parts.insert(part_number.parse().unwrap(), data);
+
+                return if copied {
+                    xml_response(
+                        StatusCode::OK,
+                        format!("<CopyPartResult><ETag>\"{part_number}\"</ETag></CopyPartResult>"),
+                    )
+                } else {
+                    (StatusCode::OK, [(ETAG, format!("\"{part_number}\""))]).into_response()
+                };
+            }
+
+            // Conditional writes only support refusing to overwrite existing objects.
+            if headers.get(IF_NONE_MATCH).is_some_and(|value| value == "*")
+                && !state.ignore_conditional_writes
+                && state.objects.contains_key(&key)
+            {
+                return error_response(StatusCode::PRECONDITION_FAILED, "PreconditionFailed");
+            }
+
+            let content_type = headers
+                .get(CONTENT_TYPE)
+                .filter(|_| !copied)
+                .map(|value| value.to_str().unwrap().to_owned());
+            state
+                .objects
+                .insert(key, StoredObject::new(data, content_type));
+
+            if copied {
+                xml_response(
+                    StatusCode::OK,
+                    "<CopyObjectResult><ETag>\"copy\"</ETag></CopyObjectResult>".to_owned(),
+                )
+            } else {
+                (StatusCode::OK, [(ETAG, "\"put\"")]).into_response()
+            }
+        }
+        Method::POST if query.contains_key("uploads") => {
+            let upload_id = uuid::Uuid::new_v4().to_string();
+            state
+                .multipart
+                .insert(upload_id.clone(), (key.clone(), BTreeMap::new()));
+            xml_response(
+                StatusCode::OK,
+                format!(
+                    "<InitiateMultipartUploadResult><Key>{key}</Key>\
+                     <UploadId>{upload_id}</UploadId></InitiateMultipartUploadResult>"
+                ),
+            )
+        }
+        Method::POST if query.contains_key("uploadId") => {
+            let Some((key, mut parts)) = state.multipart.remove(&query["uploadId"]) else {
+                return error_response(StatusCode::NOT_FOUND, "NoSuchUpload");
+            };
+
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            let part_numbers: Vec<i32> = body
+                .split("<PartNumber>")
+                .skip(1)
+                .map(|rest| rest.split_once("</PartNumber>").unwrap().0.parse().unwrap())
+                .collect();
+
+            let mut data = Vec::new();
+            for (idx, part_number) in part_numbers.iter().enumerate() {
+                let Some(part) = parts.remove(part_number) else {
+                    return error_response(StatusCode::BAD_REQUEST, "InvalidPart");
+                };
+                if idx + 1 < part_numbers.len() && part.len() < MIN_PART_SIZE {
+                    return error_response(StatusCode::BAD_REQUEST, "EntityTooSmall");
+                }
+                data.extend(part);
+            }
+
+            state
+                .objects
+                .insert(key.clone(), StoredObject::new(data, None));
+            state.completed_multipart += 1;
+            xml_response(
+                StatusCode::OK,
+                format!(
+                    "<CompleteMultipartUploadResult><Key>{key}</Key><ETag>\"multipart\"</ETag>\
+                     </CompleteMultipartUploadResult>"
+                ),
+            )
+        }
+        Method::DELETE if query.contains_key("uploadId") => {
+            state.multipart.remove(&query["uploadId"]);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Method::DELETE => {
+            state.objects.remove(&key);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        _ => error_response(StatusCode::NOT_IMPLEMENTED, "NotImplemented"),
+    }
+}
+
+/// Launches the stand-in in the background, returning its state and a client connected to it.
+async fn launch_stand_in() -> (SharedStandIn, Client) {
+    let state = SharedStandIn::default();
+    let app = Router::new()
+        .fallback(handle)
+        .layer(DefaultBodyLimit::disable())
+        .with_state(state.clone());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("could not bind stand-in");
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let config = aws_sdk_s3::Config::builder()
+        .behavior_version(BehaviorVersion::latest())
+        .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+        .region(Region::new("us-east-1"))
+        .endpoint_url(format!("http://{addr}"))
+        .force_path_style(true)
+        .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+        .response_checksum_validation(ResponseChecksumValidation::WhenRequired)
+        .build();
+
+    (state, Client::from_conf(config))
+}
+
+/// Writes `data` to an upload, starting at `start_at`.
+async fn write_upload(storage: &S3Storage, upload: uuid::Uuid, start_at: usize, data: &[u8]) {
+    let mut writer = storage
+        .get_upload_writer(start_at as u64, upload)
+        .await
+        .unwrap();
+    writer.write_all(data).await.unwrap();
+    writer.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn s3_storage_conforms() {
+    let (_state, client) = launch_stand_in().await;
+
+    check_storage_conformance(&S3Storage::new(client, "registry").prefix("conformance")).await;
+}
+
+#[tokio::test]
+async fn large_uploads_are_assembled_from_chunks() {
+    let (state, client) = launch_stand_in().await;
+    let storage = S3Storage::new(client, "registry");
+
+    const MIB: usize = 1024 * 1024;
+    let contents: Vec<u8> = (0..17 * MIB).map(|idx| (idx % 251) as u8).collect();
+
+    // Small writes continue the last chunk, large ones start new chunks, and resuming at an
+    // earlier offset truncates a chunk.
+    let upload = storage.begin_new_upload().await.unwrap();
+    write_upload(&storage, upload, 0, &contents[..6 * MIB]).await;
+    write_upload(&storage, upload, 6 * MIB, &contents[6 * MIB..9 * MIB]).await;
+    write_upload(&storage, upload, 9 * MIB, &[0; 7 * MIB]).await;
+    assert_eq!(
+        storage.get_upload_size(upload).await.unwrap(),
+        Some(16 * MIB as u64)
+    );
+    write_upload(&storage, upload, 9 * MIB, &contents[9 * MIB..]).await;
+    assert_eq!(
+        storage.get_upload_size(upload).await.unwrap(),
+        Some(17 * MIB as u64)
+    );
+
+    // The hash state covers the whole upload, no chunk is read back.
+    state.lock().unwrap().read_keys.clear();
+    let digest = Digest::from_contents(&contents);
+    storage.finalize_upload(upload, digest).await.unwrap();
+    assert!(!state
+        .lock()
+        .unwrap()
+        .read_keys
+        .iter()
+        .any(|key| key.contains("/chunks/")));
+    assert_eq!(state.lock().unwrap().completed_multipart, 1);
+    assert!(!state
+        .lock()
+        .unwrap()
+        .objects
+        .keys()
+        .any(|key| key.starts_with("uploads/")));
+
+    let mut reader = storage.get_blob_reader(digest).await.unwrap().unwrap();
+    let mut read_back = Vec::new();
+    reader.read_to_end(&mut read_back).await.unwrap();
+    assert!(read_back == contents);
+
+    reader
+        .seek(std::io::SeekFrom::Start(12 * MIB as u64))
+        .await
+        .unwrap();
+    let mut part = vec![0; MIB];
+    reader.read_exact(&mut part).await.unwrap();
+    assert!(part == contents[12 * MIB..13 * MIB]);
+}
+
+#[tokio::test]
+async fn nested_repositories_are_listed() {
+    let (_state, client) = launch_stand_in().await;
+    let storage = S3Storage::new(client, "registry");
+
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_IMAGE_MANIFEST,
+        "config": {
+            "mediaType": "application/vnd.oci.empty.v1+json",
+            "size": 2,
+            "digest": ImageDigest::new(Digest::from_contents(b"{}")).to_string(),
+        },
+        "layers": [],
+    })
+    .to_string()
+    .into_bytes();
+    let digest = Digest::from_contents(&manifest);
+
+    let names = ["team", "team/app", "team/app/worker", "other/app"];
+    for name in names {
+        let reference = ManifestReference::new(
+            ImageLocation::new(name).unwrap(),
+            Reference::new_tag("latest"),
+        );
+        storage
+            .put_manifest(&reference, &manifest, OCI_IMAGE_MANIFEST, true)
+            .await
+            .unwrap();
+    }
+
+    let mut expected: Vec<_> = names
+        .iter()
+        .map(|name| ImageLocation::new(*name).unwrap())
+        .collect();
+    expected.sort();
+    assert_eq!(storage.list_repositories().await.unwrap(), expected);
+
+    // The manifest is kept until the last image linking it is gone.
+    for (idx, name) in names.iter().enumerate() {
+        let location = ImageLocation::new(*name).unwrap();
+        assert!(storage.delete_manifest(&location, digest).await.unwrap());
+        assert_eq!(
+            storage
+                .get_manifest_metadata(digest)
+                .await
+                .unwrap()
+                .is_some(),
+            idx + 1 < names.len()
+        );
+    }
+}
+
+#[tokio::test]
+async fn immutable_tags_survive_ignored_conditional_writes() {
+    let (state, client) = launch_stand_in().await;
+    let storage = S3Storage::new(client, "registry");
+    state.lock().unwrap().ignore_conditional_writes = true;
+
+    let manifest = |name: &str| {
+        serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_MANIFEST,
+            "config": {
+                "mediaType": "application/vnd.oci.empty.v1+json",
+                "size": 2,
+                "digest": ImageDigest::new(Digest::from_contents(b"{}")).to_string(),
+            },
+            "layers": [],
+            "annotations": {"org.example.name": name},
+        })
+        .to_string()
+        .into_bytes()
+    };
+    let reference = ManifestReference::new(
+        ImageLocation::new("immutable/app").unwrap(),
+        Reference::new_tag("v1"),
+    );
+
+    let first = manifest("first");
+    storage
+        .put_manifest(&reference, &first, OCI_IMAGE_MANIFEST, false)
+        .await
+        .unwrap();
+    storage
+        .put_manifest(&reference, &first, OCI_IMAGE_MANIFEST, false)
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        storage
+            .put_manifest(&reference, &manifest("second"), OCI_IMAGE_MANIFEST, false)
+            .await,
+        Err(Error::TagExists)
+    ));
+    assert_eq!(storage.get_manifest(&reference).await.unwrap(), Some(first));
+}
+
+#[tokio::test]
+async fn blob_downloads_can_be_redirected() {
+    let (_state, client) = launch_stand_in().await;
+    let storage = S3Storage::new(client, "registry").presign_blobs(Duration::from_secs(60));
+
+    let upload = storage.begin_new_upload().await.unwrap();
+    write_upload(&storage, upload, 0, b"presigned").await;
+    let digest = Digest::from_contents(b"presigned");
+    storage.finalize_upload(upload, digest).await.unwrap();
+
+    let ctx = ContainerRegistry::builder()
+        .storage_backend(Box::new(storage))
+        .build_for_testing();
+
+    let response = ctx
+        .make_service()
+        .oneshot(
+            Request::builder()
+                .uri(format!(
+                    "/v2/tests/sample/blobs/{}",
+                    ImageDigest::new(digest)
+                ))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+    let location = response.headers()[LOCATION].to_str().unwrap();
+    assert!(location.contains(&format!("/registry/blobs/{digest}?")));
+    assert!(location.contains("X-Amz-Signature="));
+
+    let response = ctx
+        .make_service()
+        .oneshot(
+            Request::builder()
+                .uri(format!(
+                    "/v2/tests/sample/blobs/{}",
+                    ImageDigest::new(Digest::from_contents(b"missing"))
+                ))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+/// Runs the conformance tests against an actual S3-compatible service, e.g. a local MinIO.
+///
+/// The bucket is given through `CONTAINER_REGISTRY_S3_TEST_BUCKET`, everything else is configured
+/// through the usual AWS environment variables. Objects are stored below a random prefix.
+#[tokio::test]
+#[ignore]
+async fn external_s3_storage_conforms() {
+    let bucket = std::env::var("CONTAINER_REGISTRY_S3_TEST_BUCKET")
+        .expect("CONTAINER_REGISTRY_S3_TEST_BUCKET must be set");
+    let config = aws_config::load_from_env().await;
+    let client = Client::from_conf(
+        aws_sdk_s3::config::Builder::from(&config)
+            .force_path_style(true)
+            .build(),
+    );
+
+    let storage = S3Storage::new(client, bucket).prefix(uuid::Uuid::new_v4().to_string());
+    check_storage_conformance(&storage).await;
+}
@@ -24,15 +24,26 @@
 //! // To launch the app and potentially use `app.call`:
 //! // let app = service.ready().await.expect("could not launch service");
 //! ```
-use std::{net::SocketAddr, sync::Arc, thread};
+//!
+//! ## Checking storage backends
+//!
+//! Custom implementations of [`RegistryStorage`] can be checked for the semantics the registry
+//! relies on using [`check_storage_conformance`].
+use std::{net::SocketAddr, sync::Arc, thread, time::Duration};
 
 use axum::{body::Body, routing::RouterIntoService};
-use tokio::runtime::Runtime;
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    runtime::Runtime,
+};
 use tower_http::trace::TraceLayer;
+use uuid::Uuid;
 
 use super::{
     auth::{self, Permissions},
-    ContainerRegistry, ContainerRegistryBuilder,
+    storage::{Digest, Error, ImageLocation, ManifestReference, Reference, RegistryStorage},
+    types::OCI_IMAGE_MANIFEST,
+    ContainerRegistry, ContainerRegistryBuilder, ImageDigest,
 };
 
 /// A context of a container registry instantiated for testing.
@@ -153,14 +164,16 @@ impl ContainerRegistryBuilder {
     ///
     /// * If no auth provider has been set, a default one granting **full write access** to any
     ///   user, including anonymous ones.
-    /// * If no storage path has been set, creates a temporary directory for the registry, which
-    ///   will be cleaned up if `TestingContainerRegistry` is dropped.
+    /// * If neither a storage path nor a storage backend has been set, creates a temporary
+    ///   directory for the registry, which will be cleaned up if `TestingContainerRegistry` is
+    ///   dropped. Pass a [`MemoryStorage`](crate::storage::MemoryStorage) to
+    ///   [`Self::storage_backend`] to keep all data in memory instead.
     ///
     /// # Panics
     ///
     /// Will panic if filesystem operations when setting up storage fail.
     pub fn build_for_testing(mut self) -> TestingContainerRegistry {
-        let temp_storage = if self.storage.is_none() {
+        let temp_storage = if self.storage.is_none() && self.storage_backend.is_none() {
             let temp_storage = tempdir::TempDir::new("container-registry-for-testing").expect(
                 "could not create temporary directory to host testing container registry instance",
             );
@@ -187,3 +200,463 @@ impl ContainerRegistryBuilder {
         }
     }
 }
+
+/// Checks a storage backend for conformance with the semantics documented on [`RegistryStorage`].
+///
+/// The storage must be empty initially and is left containing test data. Panics on the first
+/// violation found.
+///
+/// ```ignore
+/// #[tokio::test]
+/// async fn my_storage_conforms() {
+///     let storage = MyStorage::connect("...").await;
+///     container_registry::test_support::check_storage_conformance(&storage).await;
+/// }
+/// ```
+pub async fn check_storage_conformance(storage: &dyn RegistryStorage) {
+    assert!(
+        storage.list_repositories().await.unwrap().is_empty(),
+        "storage must be empty initially"
+    );
+    assert!(
+        storage.list_manifests().await.unwrap().is_empty(),
+        "storage must be empty initially"
+    );
+
+    check_uploads(storage).await;
+    check_upload_cancellation(storage).await;
+    check_blobs(storage).await;
+    check_manifests(storage).await;
+    check_manifest_deletion(storage).await;
+
+    let repositories = storage.list_repositories().await.unwrap();
+    assert!(
+        repositories.windows(2).all(|pair| pair[0] < pair[1]),
+        "repositories must be sorted"
+    );
+}
+
+/// Writes `data` to an upload, starting at `start_at`.
+async fn write_upload(storage: &dyn RegistryStorage, upload: Uuid, start_at: u64, data: &[u8]) {
+    let mut writer = storage.get_upload_writer(start_at, upload).await.unwrap();
+    writer.write_all(data).await.unwrap();
+    writer.shutdown().await.unwrap();
+}
+
+/// Creates a blob with the given contents through an upload.
+async fn create_blob(storage: &dyn RegistryStorage, contents: &[u8]) -> Digest {
+    let upload = storage.begin_new_upload().await.unwrap();
+    write_upload(storage, upload, 0, contents).await;
+
+    let digest = Digest::from_contents(contents);
+    storage.finalize_upload(upload, digest).await.unwrap();
+    digest
+}
+
+async fn check_uploads(storage: &dyn RegistryStorage) {
+    let upload = storage.begin_new_upload().await.unwrap();
+    assert_ne!(
+        upload,
+        storage.begin_new_upload().await.unwrap(),
+        "upload IDs must be unique"
+    );
+    assert_eq!(storage.get_upload_size(upload).await.unwrap(), Some(0));
+
+    write_upload(storage, upload, 0, b"hello ").await;
+    write_upload(storage, upload, 6, b"world").await;
+    assert_eq!(storage.get_upload_size(upload).await.unwrap(), Some(11));
+
+    // Rewriting discards everything past the offset.
+    write_upload(storage, upload, 6, b"you").await;
+    assert_eq!(storage.get_upload_size(upload).await.unwrap(), Some(9));
+
+    assert!(matches!(
+        storage.get_upload_writer(10, upload).await,
+        Err(Error::InvalidUploadOffset)
+    ));
+
+    let digest = Digest::from_contents(b"hello you");
+    assert!(matches!(
+        storage
+            .finalize_upload(upload, Digest::from_contents(b"hello world"))
+            .await,
+        Err(Error::DigestMismatch)
+    ));
+    assert_eq!(
+        storage.get_upload_size(upload).await.unwrap(),
+        Some(9),
+        "a failed finalization must leave the upload untouched"
+    );
+    assert!(storage.get_blob_metadata(digest).await.unwrap().is_none());
+
+    storage.finalize_upload(upload, digest).await.unwrap();
+    assert_eq!(storage.get_upload_size(upload).await.unwrap(), None);
+
+    let metadata = storage.get_blob_metadata(digest).await.unwrap().unwrap();
+    assert_eq!(metadata.digest(), digest);
+    assert_eq!(metadata.size(), 9);
+    assert!(
+        metadata.modified().is_some(),
+        "blob metadata must include the time the blob was written"
+    );
+
+    assert!(matches!(
+        storage.get_upload_writer(0, upload).await,
+        Err(Error::UploadDoesNotExist)
+    ));
+    assert!(matches!(
+        storage.finalize_upload(upload, digest).await,
+        Err(Error::UploadDoesNotExist)
+    ));
+
+    let unknown = Uuid::new_v4();
+    assert_eq!(storage.get_upload_size(unknown).await.unwrap(), None);
+    assert!(matches!(
+        storage.get_upload_writer(0, unknown).await,
+        Err(Error::UploadDoesNotExist)
+    ));
+}
+
+async fn check_upload_cancellation(storage: &dyn RegistryStorage) {
+    let upload = storage.begin_new_upload().await.unwrap();
+    write_upload(storage, upload, 0, b"cancelled").await;
+
+    assert!(storage.cancel_upload(upload).await.unwrap());
+    assert_eq!(storage.get_upload_size(upload).await.unwrap(), None);
+    assert!(!storage.cancel_upload(upload).await.unwrap());
+
+    let upload = storage.begin_new_upload().await.unwrap();
+    assert!(
+        !storage
+            .expire_uploads(Duration::from_secs(3600))
+            .await
+            .unwrap()
+            .contains(&upload),
+        "recent uploads must not expire"
+    );
+    assert_eq!(storage.get_upload_size(upload).await.unwrap(), Some(0));
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(storage
+        .expire_uploads(Duration::from_millis(10))
+        .await
+        .unwrap()
+        .contains(&upload));
+    assert_eq!(storage.get_upload_size(upload).await.unwrap(), None);
+}
+
+async fn check_blobs(storage: &dyn RegistryStorage) {
+    let contents = b"0123456789";
+    let digest = create_blob(storage, contents).await;
+
+    let mut reader = storage.get_blob_reader(digest).await.unwrap().unwrap();
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, contents);
+
+    reader.seek(std::io::SeekFrom::Start(7)).await.unwrap();
+    buf.clear();
+    reader.read_to_end(&mut buf).await.unwrap();
+    assert_eq!(buf, b"789", "blob readers must be seekable");
+    drop(reader);
+
+    // Storing the same contents again is fine.
+    assert_eq!(create_blob(storage, contents).await, digest);
+
+    let listed = storage
+        .list_blobs()
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|blob| blob.digest() == digest)
+        .expect("stored blobs must be listed");
+    assert_eq!(listed.size(), contents.len() as u64);
+    assert!(listed.modified().is_some());
+
+    assert!(storage.delete_blob(digest).await.unwrap());
+    assert!(storage.get_blob_metadata(digest).await.unwrap().is_none());
+    assert!(storage.get_blob_reader(digest).await.unwrap().is_none());
+    assert!(!storage.delete_blob(digest).await.unwrap());
+    assert!(!storage
+        .list_blobs()
+        .await
+        .unwrap()
+        .iter()
+        .any(|blob| blob.digest() == digest));
+}
+
+/// Creates a distinct, valid image manifest.
+fn conformance_manifest(layer: &str) -> Vec<u8> {
+    let descriptor = |media_type: &str, contents: &str| {
+        serde_json::json!({
+            "mediaType": media_type,
+            "size": contents.len(),
+            "digest": ImageDigest::new(Digest::from_contents(contents.as_bytes())).to_string(),
+        })
+    };
+
+    serde_json::to_vec(&serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_IMAGE_MANIFEST,
+        "config": descriptor("application/vnd.oci.image.config.v1+json", "{}"),
+        "layers": [descriptor("application/vnd.oci.image.layer.v1.tar", layer)],
+    }))
+    .expect("manifest should serialize")
+}
+
+/// Creates a reference to a manifest.
+fn manifest_ref(name: &str, reference: Reference) -> ManifestReference {
+    ManifestReference::new(
+        ImageLocation::new(name).expect("name should be valid"),
+        reference,
+    )
+}
+
+async fn check_manifests(storage: &dyn RegistryStorage) {
+    let location = ImageLocation::new("conformance/manifests").unwrap();
+    let latest = manifest_ref(location.name(), Reference::new_tag("latest"));
+
+    assert!(storage.list_tags(&location).await.unwrap().is_none());
+    assert!(storage.get_manifest(&latest).await.unwrap().is_none());
+
+    let first = conformance_manifest("first");
+    let first_digest = storage
+        .put_manifest(&latest, &first, OCI_IMAGE_MANIFEST, true)
+        .await
+        .unwrap();
+    assert_eq!(first_digest, Digest::from_contents(&first));
+
+    assert_eq!(
+        storage.get_manifest(&latest).await.unwrap(),
+        Some(first.clone())
+    );
+    let by_digest = manifest_ref(location.name(), Reference::new_digest(first_digest));
+    assert_eq!(
+        storage.get_manifest(&by_digest).await.unwrap(),
+        Some(first.clone())
+    );
+    let elsewhere = manifest_ref("conformance/other", Reference::new_digest(first_digest));
+    assert_eq!(
+        storage.get_manifest(&elsewhere).await.unwrap(),
+        Some(first.clone()),
+        "manifests are shared between images"
+    );
+    assert_eq!(
+        storage.get_manifest_media_type(first_digest).await.unwrap(),
+        Some(OCI_IMAGE_MANIFEST.to_owned())
+    );
+    let metadata = storage
+        .get_manifest_metadata(first_digest)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(metadata.size(), first.len() as u64);
+    assert!(metadata.modified().is_some());
+    assert_eq!(
+        storage.list_tags(&location).await.unwrap(),
+        Some(vec!["latest".to_owned()])
+    );
+    assert!(storage
+        .list_repositories()
+        .await
+        .unwrap()
+        .contains(&location));
+    assert!(storage
+        .list_manifests()
+        .await
+        .unwrap()
+        .contains(&first_digest));
+
+    // Storing the same manifest again is fine.
+    assert_eq!(
+        storage
+            .put_manifest(&latest, &first, OCI_IMAGE_MANIFEST, true)
+            .await
+            .unwrap(),
+        first_digest
+    );
+
+    assert!(matches!(
+        storage
+            .put_manifest(&latest, b"not a manifest", OCI_IMAGE_MANIFEST, true)
+            .await,
+        Err(Error::InvalidManifest(_))
+    ));
+    let second = conformance_manifest("second");
+    assert!(matches!(
+        storage
+            .put_manifest(&by_digest, &second, OCI_IMAGE_MANIFEST, true)
+            .await,
+        Err(Error::DigestMismatch)
+    ));
+
+    // Pushing by digest does not create a tag, but links the manifest to the image.
+    let second_digest = Digest::from_contents(&second);
+    let second_ref = manifest_ref(location.name(), Reference::new_digest(second_digest));
+    assert_eq!(
+        storage
+            .put_manifest(&second_ref, &second, OCI_IMAGE_MANIFEST, true)
+            .await
+            .unwrap(),
+        second_digest
+    );
+    assert_eq!(
+        storage.list_tags(&location).await.unwrap(),
+        Some(vec!["latest".to_owned()])
+    );
+    let mut expected = vec![first_digest, second_digest];
+    expected.sort();
+    assert_eq!(
+        storage.list_image_manifests(&location).await.unwrap(),
+        expected
+    );
+
+    // Moving a tag keeps the previous manifest linked to the image.
+    storage
+        .put_manifest(&latest, &second, OCI_IMAGE_MANIFEST, true)
+        .await
+        .unwrap();
+    assert_eq!(
+        storage.get_manifest(&latest).await.unwrap(),
+        Some(second.clone())
+    );
+    assert_eq!(
+        storage.list_image_manifests(&location).await.unwrap(),
+        expected
+    );
+
+    // Tags that may not be replaced can only be created or pointed at their current manifest.
+    assert!(matches!(
+        storage
+            .put_manifest(&latest, &first, OCI_IMAGE_MANIFEST, false)
+            .await,
+        Err(Error::TagExists)
+    ));
+    storage
+        .put_manifest(&latest, &second, OCI_IMAGE_MANIFEST, false)
+        .await
+        .unwrap();
+    assert_eq!(storage.get_manifest(&latest).await.unwrap(), Some(second));
+
+    let stable = manifest_ref(location.name(), Reference::new_tag("stable"));
+    storage
+        .put_manifest(&stable, &first, OCI_IMAGE_MANIFEST, false)
+        .await
+        .unwrap();
+    assert_eq!(
+        storage.list_tags(&location).await.unwrap(),
+        Some(vec!["latest".to_owned(), "stable".to_owned()]),
+        "tags must be sorted"
+    );
+
+    assert!(storage.delete_tag(&location, "stable").await.unwrap());
+    assert!(!storage.delete_tag(&location, "stable").await.unwrap());
+    assert!(storage.get_manifest(&stable).await.unwrap().is_none());
+    assert_eq!(
+        storage.get_manifest(&by_digest).await.unwrap(),
+        Some(first),
+        "deleting a tag must keep its manifest"
+    );
+}
+
+async fn check_manifest_deletion(storage: &dyn RegistryStorage) {
+    let first = ImageLocation::new("conformance/shared-a").unwrap();
+    let second = ImageLocation::new("conformance/shared-b").unwrap();
+
+    let manifest = conformance_manifest("shared");
+    let digest = Digest::from_contents(&manifest);
+    for location in [&first, &second] {
+        let reference = manifest_ref(location.name(), Reference::new_tag("v1"));
+        storage
+            .put_manifest(&reference, &manifest, OCI_IMAGE_MANIFEST, true)
+            .await
+            .unwrap();
+    }
+
+    let by_digest = manifest_ref(first.name(), Reference::new_digest(digest));
+    assert!(
+        !storage
+            .delete_untagged_manifest(&first, digest)
+            .await
+            .unwrap(),
+        "tagged manifests must not be removed as untagged"
+    );
+    assert_eq!(
+        storage.list_tags(&first).await.unwrap(),
+        Some(vec!["v1".to_owned()])
+    );
+
+    let unrelated = ImageLocation::new("conformance/shared-c").unwrap();
+    assert!(
+        !storage.delete_manifest(&unrelated, digest).await.unwrap(),
+        "manifests can only be deleted from images linking them"
+    );
+    assert_eq!(
+        storage.list_tags(&second).await.unwrap(),
+        Some(vec!["v1".to_owned()])
+    );
+
+    assert!(storage.delete_manifest(&first, digest).await.unwrap());
+    assert_eq!(
+        storage.list_tags(&first).await.unwrap().unwrap_or_default(),
+        Vec::<String>::new(),
+        "deleting a manifest must remove its tags"
+    );
+    assert!(!storage
+        .list_image_manifests(&first)
+        .await
+        .unwrap()
+        .contains(&digest));
+    assert_eq!(
+        storage.get_manifest(&by_digest).await.unwrap(),
+        Some(manifest),
+        "manifests must be kept while referenced by another image"
+    );
+
+    assert!(storage.delete_manifest(&second, digest).await.unwrap());
+    assert!(storage.get_manifest(&by_digest).await.unwrap().is_none());
+    assert!(storage
+        .get_manifest_media_type(digest)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(storage
+        .get_manifest_metadata(digest)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(!storage.list_manifests().await.unwrap().contains(&digest));
+    assert!(!storage.delete_manifest(&second, digest).await.unwrap());
+
+    // Untagged manifests can be removed without touching any tags.
+    let manifest = conformance_manifest("untagged");
+    let digest = Digest::from_contents(&manifest);
+    let reference = manifest_ref(first.name(), Reference::new_tag("v2"));
+    storage
+        .put_manifest(&reference, &manifest, OCI_IMAGE_MANIFEST, true)
+        .await
+        .unwrap();
+    assert!(!storage
+        .delete_untagged_manifest(&unrelated, digest)
+        .await
+        .unwrap());
+    assert!(storage.delete_tag(&first, "v2").await.unwrap());
+    assert!(
+        !storage.delete_unlinked_manifest(digest).await.unwrap(),
+        "manifests linked to an image must not be removed as unlinked"
+    );
+    assert!(storage
+        .delete_untagged_manifest(&first, digest)
+        .await
+        .unwrap());
+    assert!(!storage
+        .delete_untagged_manifest(&first, digest)
+        .await
+        .unwrap());
+    assert!(storage
+        .get_manifest(&manifest_ref(first.name(), Reference::new_digest(digest)))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(!storage.delete_unlinked_manifest(digest).await.unwrap());
+}
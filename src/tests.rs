@@ -1,11 +1,16 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use axum::{
+    async_trait,
     body::Body,
     http::{
-        header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, LOCATION},
+        header::{
+            ACCEPT, ACCEPT_RANGES, ALLOW, AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE,
+            CONTENT_TYPE, LINK, LOCATION, RANGE,
+        },
         Request, StatusCode,
     },
+    routing::RouterIntoService,
 };
 use base64::Engine;
 use http_body_util::BodyExt;
@@ -14,13 +19,18 @@ use tokio::io::AsyncWriteExt;
 use tower::{util::ServiceExt, Service};
 
 use crate::{
-    auth::Anonymous,
+    auth::{Anonymous, AuthProvider, Permissions, Unverified, ValidCredentials},
+    gc::{GarbageCollectionReport, GarbageCollector},
+    policy::ImmutableTags,
     storage::{ImageLocation, ManifestReference, Reference},
     test_support::TestingContainerRegistry,
     ImageDigest,
 };
 
-use super::{storage::Digest, ContainerRegistry};
+use super::{
+    storage::{Digest, FilesystemStorage},
+    ContainerRegistry,
+};
 
 /// Constructs a basic auth header with the [`TEST_PASSWORD`].
 fn basic_auth() -> String {
@@ -159,17 +169,25 @@ async fn allows_anon_access_if_configured() {
 // Fixtures.
 const RAW_IMAGE: &[u8] =
     include_bytes!("../fixtures/596a7d877b33569d199046aaf293ecf45026445be36de1818d50b4f1850762ad");
+const RAW_CONFIG: &[u8] =
+    include_bytes!("../fixtures/5eb81a7e8d89c7e9a04c6c8d340a319b68e5f0f7b6c0e90656a014dd351d3386");
 const RAW_MANIFEST: &[u8] =
-    include_bytes!("../fixtures/9ce67038e4f1297a0b1ce23be1b768ce3649fe9bd496ba8efe9ec1676d153430");
+    include_bytes!("../fixtures/5efbc21136acc56ad80395eda5c2bf03334e28ea4cc5d488a1e3ab7c8e417c4d");
+const RAW_MANIFEST_MEDIA_TYPE: &str = "application/vnd.docker.distribution.manifest.v2+json";
 
 const IMAGE_DIGEST: ImageDigest = ImageDigest::new(Digest::new([
     0x59, 0x6a, 0x7d, 0x87, 0x7b, 0x33, 0x56, 0x9d, 0x19, 0x90, 0x46, 0xaa, 0xf2, 0x93, 0xec, 0xf4,
     0x50, 0x26, 0x44, 0x5b, 0xe3, 0x6d, 0xe1, 0x81, 0x8d, 0x50, 0xb4, 0xf1, 0x85, 0x07, 0x62, 0xad,
 ]));
 
+const CONFIG_DIGEST: ImageDigest = ImageDigest::new(Digest::new([
+    0x5e, 0xb8, 0x1a, 0x7e, 0x8d, 0x89, 0xc7, 0xe9, 0xa0, 0x4c, 0x6c, 0x8d, 0x34, 0x0a, 0x31, 0x9b,
+    0x68, 0xe5, 0xf0, 0xf7, 0xb6, 0xc0, 0xe9, 0x06, 0x56, 0xa0, 0x14, 0xdd, 0x35, 0x1d, 0x33, 0x86,
+]));
+
 const MANIFEST_DIGEST: ImageDigest = ImageDigest::new(Digest::new([
-    0x9c, 0xe6, 0x70, 0x38, 0xe4, 0xf1, 0x29, 0x7a, 0x0b, 0x1c, 0xe2, 0x3b, 0xe1, 0xb7, 0x68, 0xce,
-    0x36, 0x49, 0xfe, 0x9b, 0xd4, 0x96, 0xba, 0x8e, 0xfe, 0x9e, 0xc1, 0x67, 0x6d, 0x15, 0x34, 0x30,
+    0x5e, 0xfb, 0xc2, 0x11, 0x36, 0xac, 0xc5, 0x6a, 0xd8, 0x03, 0x95, 0xed, 0xa5, 0xc2, 0xbf, 0x03,
+    0x33, 0x4e, 0x28, 0xea, 0x4c, 0xc5, 0xd4, 0x88, 0xa1, 0xe3, 0xab, 0x7c, 0x8e, 0x41, 0x7c, 0x4d,
 ]));
 
 #[tokio::test]
@@ -206,7 +224,7 @@ async fn chunked_upload() {
     let mut sent = 0;
     for chunk in RAW_IMAGE.chunks(32) {
         assert!(!chunk.is_empty());
-        let range = format!("{sent}-{}", chunk.len() - 1);
+        let range = format!("{sent}-{}", sent + chunk.len() - 1);
         sent += chunk.len();
 
         let response = app
@@ -226,7 +244,7 @@ async fn chunked_upload() {
         assert_eq!(response.status(), StatusCode::ACCEPTED);
     }
 
-    // Step 3: PUT without (!) final body -- we do not support putting the final piece in `PUT`.
+    // Step 3: PUT without final body.
     let response = app
         .call(
             Request::builder()
@@ -274,7 +292,9 @@ async fn chunked_upload() {
         IMAGE_DIGEST.to_string()
     );
 
-    // Step 5: Upload the manifest
+    // Step 5: Upload the manifest, after its config has been uploaded as well.
+    insert_blob(&ctx, RAW_CONFIG).await;
+
     let manifest_by_tag_location = "/v2/tests/sample/manifests/latest";
 
     let response = app
@@ -305,7 +325,7 @@ async fn chunked_upload() {
         ctx.registry
             .storage
             .get_manifest(&ManifestReference::new(
-                ImageLocation::new("tests".to_owned(), "sample".to_owned()),
+                ImageLocation::new("tests/sample").unwrap(),
                 Reference::new_tag("latest"),
             ))
             .await
@@ -318,7 +338,7 @@ async fn chunked_upload() {
         ctx.registry
             .storage
             .get_manifest(&ManifestReference::new(
-                ImageLocation::new("tests".to_owned(), "sample".to_owned()),
+                ImageLocation::new("tests/sample").unwrap(),
                 Reference::new_digest(MANIFEST_DIGEST.digest),
             ))
             .await
@@ -328,244 +348,2698 @@ async fn chunked_upload() {
     );
 }
 
-/// Similar to `chunked_upload`, but uses no credentials to log in.
 #[tokio::test]
-async fn anonymous_upload() {
-    let ctx = ContainerRegistry::builder().build_for_testing();
-
+async fn chunked_upload_validates_ranges() {
+    let ctx = registry_with_test_password();
     let mut service = ctx.make_service();
     let app = service.ready().await.expect("could not launch service");
 
-    // Step 1: POST for new blob upload.
     let response = app
         .call(
             Request::builder()
                 .method("POST")
+                .header(AUTHORIZATION, basic_auth())
                 .uri("/v2/tests/sample/blobs/uploads/")
                 .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
-
     assert_eq!(response.status(), StatusCode::ACCEPTED);
-
-    let put_location = response
+    let upload_location = response
         .headers()
         .get(LOCATION)
-        .expect("expected location header for blob upload")
+        .unwrap()
         .to_str()
         .unwrap()
         .to_owned();
 
-    // Step 2: PATCH blobs.
-    let mut sent = 0;
-    for chunk in RAW_IMAGE.chunks(32) {
-        assert!(!chunk.is_empty());
-        let range = format!("{sent}-{}", chunk.len() - 1);
-        sent += chunk.len();
+    // Reports an empty upload initially.
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(&upload_location)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(response.headers().get(RANGE).unwrap(), "0-0");
+
+    let (first, second) = RAW_IMAGE.split_at(64);
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("PATCH")
+                .header(AUTHORIZATION, basic_auth())
+                .header(CONTENT_LENGTH, first.len())
+                .header(CONTENT_RANGE, format!("0-{}", first.len() - 1))
+                .uri(&upload_location)
+                .body(Body::from(first))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(response.headers().get(RANGE).unwrap(), "0-63");
+
+    // Retrying the first chunk is out of order now.
+    let response = app
+        .call(
+            Request::builder()
+                .method("PATCH")
+                .header(AUTHORIZATION, basic_auth())
+                .header(CONTENT_LENGTH, first.len())
+                .header(CONTENT_RANGE, format!("0-{}", first.len() - 1))
+                .uri(&upload_location)
+                .body(Body::from(first))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers().get(LOCATION).unwrap(), &upload_location);
+    assert_eq!(response.headers().get(RANGE).unwrap(), "0-63");
+
+    // Skipping ahead is as well.
+    let response = app
+        .call(
+            Request::builder()
+                .method("PATCH")
+                .header(AUTHORIZATION, basic_auth())
+                .header(CONTENT_LENGTH, second.len())
+                .header(CONTENT_RANGE, format!("65-{}", 65 + second.len() - 1))
+                .uri(&upload_location)
+                .body(Body::from(second))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+    // Progress is unaffected by rejected chunks.
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(&upload_location)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(response.headers().get(RANGE).unwrap(), "0-63");
+
+    // A range not matching the content length is rejected.
+    let response = app
+        .call(
+            Request::builder()
+                .method("PATCH")
+                .header(AUTHORIZATION, basic_auth())
+                .header(CONTENT_LENGTH, second.len())
+                .header(CONTENT_RANGE, "64-64")
+                .uri(&upload_location)
+                .body(Body::from(second))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 
+    // So is a body not matching the range, without leaving any of its data behind.
+    for (range_end, body) in [(64 + 9, second), (RAW_IMAGE.len(), &second[..10])] {
         let response = app
             .call(
                 Request::builder()
                     .method("PATCH")
-                    .header(CONTENT_LENGTH, chunk.len())
-                    .header(CONTENT_RANGE, range)
-                    .uri(&put_location)
-                    .body(Body::from(chunk))
+                    .header(AUTHORIZATION, basic_auth())
+                    .header(CONTENT_RANGE, format!("64-{range_end}"))
+                    .uri(&upload_location)
+                    .body(Body::from(body))
                     .unwrap(),
             )
             .await
             .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 
-        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, basic_auth())
+                    .uri(&upload_location)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.headers().get(RANGE).unwrap(), "0-63");
     }
 
-    // Step 3: PUT without (!) final body -- we do not support putting the final piece in `PUT`.
     let response = app
         .call(
             Request::builder()
-                .method("PUT")
-                .uri(put_location + "?digest=" + IMAGE_DIGEST.to_string().as_str())
-                .body(Body::empty())
+                .method("PATCH")
+                .header(AUTHORIZATION, basic_auth())
+                .header(CONTENT_LENGTH, second.len())
+                .header(CONTENT_RANGE, format!("64-{}", RAW_IMAGE.len() - 1))
+                .uri(&upload_location)
+                .body(Body::from(second))
                 .unwrap(),
         )
         .await
         .unwrap();
-    assert_eq!(response.status(), StatusCode::CREATED);
-
-    // Check the blob is available after.
-    let blob_location = format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST);
-    assert!(&ctx
-        .registry
-        .storage
-        .get_blob_reader(IMAGE_DIGEST.digest)
-        .await
-        .expect("could not access stored blob")
-        .is_some());
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(
+        response.headers().get(RANGE).unwrap(),
+        format!("0-{}", RAW_IMAGE.len() - 1).as_str()
+    );
 
-    // Step 4: Client verifies existence of blob through `HEAD` request.
     let response = app
         .call(
             Request::builder()
-                .method("HEAD")
-                .uri(blob_location)
+                .method("PUT")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(upload_location + "?digest=" + IMAGE_DIGEST.to_string().as_str())
                 .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
 
-    assert_eq!(response.status(), StatusCode::OK);
-    assert_eq!(
-        response
-            .headers()
-            .get("Docker-Content-Digest")
-            .unwrap()
-            .to_str()
-            .unwrap(),
-        IMAGE_DIGEST.to_string()
-    );
-
-    // Step 5: Upload the manifest
-    let manifest_by_tag_location = "/v2/tests/sample/manifests/latest";
-
+    // Status of unknown uploads.
     let response = app
         .call(
             Request::builder()
-                .method("PUT")
-                .uri(manifest_by_tag_location)
-                .body(Body::from(RAW_MANIFEST))
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/tests/sample/blobs/uploads/6c0d1a37-2dd1-4dbc-8e6b-0e3c5b8f3f0f")
+                .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
-
-    assert_eq!(response.status(), StatusCode::CREATED);
-    assert_eq!(
-        response
-            .headers()
-            .get("Docker-Content-Digest")
-            .unwrap()
-            .to_str()
-            .unwrap(),
-        MANIFEST_DIGEST.to_string()
-    );
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
 }
 
 #[tokio::test]
-async fn image_download() {
+async fn monolithic_upload_via_post() {
     let ctx = registry_with_test_password();
     let mut service = ctx.make_service();
     let app = service.ready().await.expect("could not launch service");
 
-    let manifest_ref_by_tag = ManifestReference::new(
-        ImageLocation::new("tests".to_owned(), "sample".to_owned()),
-        Reference::new_tag("latest"),
-    );
-
-    let manifest_by_tag_location = "/v2/tests/sample/manifests/latest";
-    let manifest_by_digest_location = format!("/v2/tests/sample/manifests/{}", MANIFEST_DIGEST);
-
-    // Insert blob data.
-    let upload = ctx
-        .registry
-        .storage
-        .begin_new_upload()
-        .await
-        .expect("could not start upload");
-    let mut writer = ctx
-        .registry
-        .storage
-        .get_upload_writer(0, upload)
-        .await
-        .expect("could not create upload writer");
-    writer
-        .write_all(RAW_IMAGE)
-        .await
-        .expect("failed to write image blob");
-    ctx.registry
-        .storage
-        .finalize_upload(upload, IMAGE_DIGEST.digest)
-        .await
-        .expect("failed to finalize upload");
-
-    // Insert manifest data.
-    ctx.registry
-        .storage
-        .put_manifest(&manifest_ref_by_tag, RAW_MANIFEST)
-        .await
-        .expect("failed to store manifest");
-
-    // Retrieve manifest via HTTP, both by tag and by digest.
     let response = app
         .call(
             Request::builder()
-                .method("GET")
+                .method("POST")
                 .header(AUTHORIZATION, basic_auth())
-                .uri(manifest_by_tag_location)
-                .body(Body::empty())
+                .header(CONTENT_LENGTH, RAW_IMAGE.len())
+                .uri(format!(
+                    "/v2/tests/sample/blobs/uploads/?digest={}",
+                    IMAGE_DIGEST
+                ))
+                .body(Body::from(RAW_IMAGE))
                 .unwrap(),
         )
         .await
         .unwrap();
-    assert_eq!(response.status(), StatusCode::OK);
-    let response_body = collect_body(response.into_body()).await;
-
-    assert_eq!(response_body, RAW_MANIFEST);
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(
+        response.headers().get(LOCATION).unwrap(),
+        format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST).as_str()
+    );
+    assert_eq!(
+        response.headers().get("Docker-Content-Digest").unwrap(),
+        IMAGE_DIGEST.to_string().as_str()
+    );
 
     let response = app
         .call(
             Request::builder()
                 .method("GET")
                 .header(AUTHORIZATION, basic_auth())
-                .uri(manifest_by_digest_location)
+                .uri(format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST))
                 .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
     assert_eq!(response.status(), StatusCode::OK);
-    let response_body = collect_body(response.into_body()).await;
-
-    assert_eq!(response_body, RAW_MANIFEST);
+    assert_eq!(collect_body(response.into_body()).await, RAW_IMAGE);
 
-    // Download blob.
+    // Content not matching the digest is not stored.
     let response = app
         .call(
             Request::builder()
-                .method("GET")
+                .method("POST")
                 .header(AUTHORIZATION, basic_auth())
-                .uri(format!("/v2/testing/sample/blobs/{}", IMAGE_DIGEST))
-                .body(Body::empty())
+                .uri(format!(
+                    "/v2/tests/sample/blobs/uploads/?digest={}",
+                    MANIFEST_DIGEST
+                ))
+                .body(Body::from(RAW_IMAGE))
                 .unwrap(),
         )
         .await
         .unwrap();
+    assert!(!response.status().is_success());
+    assert!(ctx
+        .registry
+        .storage
+        .get_blob_metadata(MANIFEST_DIGEST.digest)
+        .await
+        .unwrap()
+        .is_none());
 
-    assert_eq!(response.status(), StatusCode::OK);
-    let response_body = collect_body(response.into_body()).await;
-    assert_eq!(response_body, RAW_IMAGE);
+    // Nor is the upload it was stored in kept around.
+    assert!(ctx
+        .registry
+        .storage
+        .expire_uploads(Duration::ZERO)
+        .await
+        .unwrap()
+        .is_empty());
 }
 
 #[tokio::test]
-async fn missing_manifest_returns_404() {
+async fn upload_with_final_chunk_in_put() {
     let ctx = registry_with_test_password();
     let mut service = ctx.make_service();
     let app = service.ready().await.expect("could not launch service");
 
+    let (first, second) = RAW_IMAGE.split_at(50);
+
     let response = app
         .call(
             Request::builder()
-                .method("GET")
+                .method("POST")
                 .header(AUTHORIZATION, basic_auth())
-                .uri("/v2/doesnot/exist/manifests/latest")
+                .uri("/v2/tests/sample/blobs/uploads/")
                 .body(Body::empty())
                 .unwrap(),
         )
         .await
         .unwrap();
-    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let upload_location = response
+        .headers()
+        .get(LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("PATCH")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(&upload_location)
+                .body(Body::from(first))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("PUT")
+                .header(AUTHORIZATION, basic_auth())
+                .header(CONTENT_LENGTH, second.len())
+                .header(CONTENT_RANGE, format!("50-{}", RAW_IMAGE.len() - 1))
+                .uri(upload_location + "?digest=" + IMAGE_DIGEST.to_string().as_str())
+                .body(Body::from(second))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // A single `PUT` containing all data works as well.
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("POST")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/tests/sample/blobs/uploads/")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let upload_location = response
+        .headers()
+        .get(LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("PUT")
+                .header(AUTHORIZATION, basic_auth())
+                .header(CONTENT_LENGTH, RAW_IMAGE.len())
+                .uri(upload_location + "?digest=" + IMAGE_DIGEST.to_string().as_str())
+                .body(Body::from(RAW_IMAGE))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(collect_body(response.into_body()).await, RAW_IMAGE);
+}
+
+/// Similar to `chunked_upload`, but uses no credentials to log in.
+/// Starts a new upload on `tests/sample`, returning its location.
+async fn begin_upload(app: &mut RouterIntoService<Body>) -> String {
+    let response = app
+        .call(
+            Request::builder()
+                .method("POST")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/tests/sample/blobs/uploads/")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    response
+        .headers()
+        .get(LOCATION)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned()
+}
+
+/// Asserts that a request for the upload at `upload_location` fails with `BLOB_UPLOAD_UNKNOWN`.
+async fn assert_upload_unknown(app: &mut RouterIntoService<Body>, upload_location: &str) {
+    let response = app
+        .call(
+            Request::builder()
+                .method("PATCH")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(upload_location)
+                .body(Body::from(RAW_IMAGE))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let errors: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(errors["errors"][0]["code"], "BLOB_UPLOAD_UNKNOWN");
+}
+
+#[tokio::test]
+async fn upload_cancellation() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    let upload_location = begin_upload(app).await;
+
+    let cancel = || {
+        Request::builder()
+            .method("DELETE")
+            .header(AUTHORIZATION, basic_auth())
+            .uri(&upload_location)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app.call(cancel()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    assert_upload_unknown(app, &upload_location).await;
+
+    let response = app.call(cancel()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn abandoned_uploads_expire() {
+    let ctx = ContainerRegistry::builder()
+        .auth_provider(Arc::new(Secret::new(TEST_PASSWORD.to_owned())))
+        .upload_ttl(Duration::from_millis(200))
+        .build_for_testing();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    let upload_location = begin_upload(app).await;
+
+    tokio::time::sleep(Duration::from_millis(600)).await;
+
+    assert_upload_unknown(app, &upload_location).await;
+}
+
+#[tokio::test]
+async fn anonymous_upload() {
+    let ctx = ContainerRegistry::builder().build_for_testing();
+
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    // Step 1: POST for new blob upload.
+    let response = app
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/v2/tests/sample/blobs/uploads/")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    let put_location = response
+        .headers()
+        .get(LOCATION)
+        .expect("expected location header for blob upload")
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    // Step 2: PATCH blobs.
+    let mut sent = 0;
+    for chunk in RAW_IMAGE.chunks(32) {
+        assert!(!chunk.is_empty());
+        let range = format!("{sent}-{}", sent + chunk.len() - 1);
+        sent += chunk.len();
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PATCH")
+                    .header(CONTENT_LENGTH, chunk.len())
+                    .header(CONTENT_RANGE, range)
+                    .uri(&put_location)
+                    .body(Body::from(chunk))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+
+    // Step 3: PUT without final body.
+    let response = app
+        .call(
+            Request::builder()
+                .method("PUT")
+                .uri(put_location + "?digest=" + IMAGE_DIGEST.to_string().as_str())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // Check the blob is available after.
+    let blob_location = format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST);
+    assert!(&ctx
+        .registry
+        .storage
+        .get_blob_reader(IMAGE_DIGEST.digest)
+        .await
+        .expect("could not access stored blob")
+        .is_some());
+
+    // Step 4: Client verifies existence of blob through `HEAD` request.
+    let response = app
+        .call(
+            Request::builder()
+                .method("HEAD")
+                .uri(blob_location)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response
+            .headers()
+            .get("Docker-Content-Digest")
+            .unwrap()
+            .to_str()
+            .unwrap(),
+        IMAGE_DIGEST.to_string()
+    );
+
+    // Step 5: Upload the manifest, after its config has been uploaded as well.
+    insert_blob(&ctx, RAW_CONFIG).await;
+
+    let manifest_by_tag_location = "/v2/tests/sample/manifests/latest";
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("PUT")
+                .uri(manifest_by_tag_location)
+                .body(Body::from(RAW_MANIFEST))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(
+        response
+            .headers()
+            .get("Docker-Content-Digest")
+            .unwrap()
+            .to_str()
+            .unwrap(),
+        MANIFEST_DIGEST.to_string()
+    );
+}
+
+#[tokio::test]
+async fn image_download() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    let manifest_ref_by_tag = ManifestReference::new(
+        ImageLocation::new("tests/sample").unwrap(),
+        Reference::new_tag("latest"),
+    );
+
+    let manifest_by_tag_location = "/v2/tests/sample/manifests/latest";
+    let manifest_by_digest_location = format!("/v2/tests/sample/manifests/{}", MANIFEST_DIGEST);
+
+    // Insert blob data.
+    let upload = ctx
+        .registry
+        .storage
+        .begin_new_upload()
+        .await
+        .expect("could not start upload");
+    let mut writer = ctx
+        .registry
+        .storage
+        .get_upload_writer(0, upload)
+        .await
+        .expect("could not create upload writer");
+    writer
+        .write_all(RAW_IMAGE)
+        .await
+        .expect("failed to write image blob");
+    ctx.registry
+        .storage
+        .finalize_upload(upload, IMAGE_DIGEST.digest)
+        .await
+        .expect("failed to finalize upload");
+
+    // Insert manifest data.
+    ctx.registry
+        .storage
+        .put_manifest(
+            &manifest_ref_by_tag,
+            RAW_MANIFEST,
+            RAW_MANIFEST_MEDIA_TYPE,
+            true,
+        )
+        .await
+        .expect("failed to store manifest");
+
+    // Retrieve manifest via HTTP, both by tag and by digest.
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(manifest_by_tag_location)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response_body = collect_body(response.into_body()).await;
+
+    assert_eq!(response_body, RAW_MANIFEST);
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(manifest_by_digest_location)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response_body = collect_body(response.into_body()).await;
+
+    assert_eq!(response_body, RAW_MANIFEST);
+
+    // Download blob.
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(format!("/v2/testing/sample/blobs/{}", IMAGE_DIGEST))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let response_body = collect_body(response.into_body()).await;
+    assert_eq!(response_body, RAW_IMAGE);
+}
+
+#[tokio::test]
+async fn manifest_head_and_get_headers() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    insert_sample_image(&ctx, &sample_location(), &["latest"]).await;
+
+    for method in ["HEAD", "GET"] {
+        let response = app
+            .call(
+                Request::builder()
+                    .method(method)
+                    .header(AUTHORIZATION, basic_auth())
+                    .uri("/v2/tests/sample/manifests/latest")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let headers = response.headers();
+        assert_eq!(
+            headers.get("Docker-Content-Digest").unwrap(),
+            MANIFEST_DIGEST.to_string().as_str()
+        );
+        assert_eq!(
+            headers.get(CONTENT_LENGTH).unwrap(),
+            RAW_MANIFEST.len().to_string().as_str()
+        );
+        assert_eq!(
+            headers.get(CONTENT_TYPE).unwrap(),
+            "application/vnd.docker.distribution.manifest.v2+json"
+        );
+
+        let response_body = collect_body(response.into_body()).await;
+        if method == "HEAD" {
+            assert!(response_body.is_empty());
+        } else {
+            assert_eq!(response_body, RAW_MANIFEST);
+        }
+    }
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("HEAD")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/tests/sample/manifests/doesnotexist")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+/// Returns an index of the given media type referencing the sample manifest.
+fn sample_index(media_type: &str) -> String {
+    serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": media_type,
+        "manifests": [{
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "size": RAW_MANIFEST.len(),
+            "digest": MANIFEST_DIGEST.to_string(),
+            "platform": {"architecture": "amd64", "os": "linux"}
+        }]
+    })
+    .to_string()
+}
+
+#[tokio::test]
+async fn image_indexes() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    insert_sample_image(&ctx, &sample_location(), &["latest"]).await;
+
+    for media_type in [
+        "application/vnd.oci.image.index.v1+json",
+        "application/vnd.docker.distribution.manifest.list.v2+json",
+    ] {
+        let index = sample_index(media_type);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, basic_auth())
+                    .header(CONTENT_TYPE, media_type)
+                    .uri("/v2/tests/sample/manifests/multiarch")
+                    .body(Body::from(index.clone()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, basic_auth())
+                    .uri("/v2/tests/sample/manifests/multiarch")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], media_type);
+        assert_eq!(collect_body(response.into_body()).await, index.as_bytes());
+    }
+}
+
+/// Returns an artifact manifest of the given type, referring to the sample manifest.
+fn sample_referrer(artifact_type: &str) -> String {
+    serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "artifactType": artifact_type,
+        "config": {
+            "mediaType": "application/vnd.oci.empty.v1+json",
+            "size": 2,
+            "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        },
+        "layers": [{
+            "mediaType": "application/octet-stream",
+            "size": RAW_IMAGE.len(),
+            "digest": IMAGE_DIGEST.to_string()
+        }],
+        "subject": {
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "size": RAW_MANIFEST.len(),
+            "digest": MANIFEST_DIGEST.to_string()
+        },
+        "annotations": {"org.example.kind": artifact_type}
+    })
+    .to_string()
+}
+
+#[tokio::test]
+async fn referrers_listing() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    insert_sample_image(&ctx, &sample_location(), &["latest"]).await;
+    // The empty config of artifacts.
+    insert_blob(&ctx, b"{}").await;
+
+    let signature = sample_referrer("application/vnd.example.signature");
+    let sbom = sample_referrer("application/vnd.example.sbom");
+    for referrer in [&signature, &sbom] {
+        let digest = ImageDigest::new(Digest::from_contents(referrer.as_bytes()));
+        let response = app
+            .call(
+                Request::builder()
+                    .method("PUT")
+                    .header(AUTHORIZATION, basic_auth())
+                    .uri(format!("/v2/tests/sample/manifests/{digest}"))
+                    .body(Body::from(referrer.clone()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            response.headers()["OCI-Subject"],
+            MANIFEST_DIGEST.to_string()
+        );
+    }
+
+    // Broken manifests of the image are skipped.
+    let broken = ctx
+        .registry
+        .storage
+        .put_manifest(
+            &ManifestReference::new(sample_location(), Reference::new_tag("broken")),
+            sample_referrer("application/vnd.example.broken").as_bytes(),
+            "application/vnd.oci.image.manifest.v1+json",
+            true,
+        )
+        .await
+        .unwrap();
+    std::fs::write(
+        ctx.temp_storage
+            .as_ref()
+            .unwrap()
+            .path()
+            .join("manifests")
+            .join(broken.to_string()),
+        b"not a manifest",
+    )
+    .unwrap();
+
+    let list_referrers = |query: &str| {
+        Request::builder()
+            .method("GET")
+            .header(AUTHORIZATION, basic_auth())
+            .uri(format!(
+                "/v2/tests/sample/referrers/{MANIFEST_DIGEST}{query}"
+            ))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app.call(list_referrers("")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[CONTENT_TYPE],
+        "application/vnd.oci.image.index.v1+json"
+    );
+    assert!(response.headers().get("OCI-Filters-Applied").is_none());
+    let index: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(index["schemaVersion"], 2);
+    let mut artifact_types: Vec<_> = index["manifests"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|descriptor| descriptor["artifactType"].as_str().unwrap())
+        .collect();
+    artifact_types.sort();
+    assert_eq!(
+        artifact_types,
+        [
+            "application/vnd.example.sbom",
+            "application/vnd.example.signature"
+        ]
+    );
+
+    let response = app
+        .call(list_referrers(
+            "?artifactType=application/vnd.example.signature",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["OCI-Filters-Applied"], "artifactType");
+    let index: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(
+        index["manifests"],
+        serde_json::json!([{
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "digest": ImageDigest::new(Digest::from_contents(signature.as_bytes())).to_string(),
+            "size": signature.len(),
+            "artifactType": "application/vnd.example.signature",
+            "annotations": {"org.example.kind": "application/vnd.example.signature"}
+        }])
+    );
+
+    // Manifests without referrers have an empty list.
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(format!("/v2/tests/sample/referrers/{IMAGE_DIGEST}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let index: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(index["manifests"], serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn manifest_content_negotiation() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    insert_sample_image(&ctx, &sample_location(), &["latest"]).await;
+
+    let get_manifest = |reference: String, accept: &str| {
+        Request::builder()
+            .method("GET")
+            .header(AUTHORIZATION, basic_auth())
+            .header(ACCEPT, accept)
+            .uri(format!("/v2/tests/sample/manifests/{reference}"))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    // Stored format is acceptable.
+    let response = app
+        .call(get_manifest(
+            "latest".to_owned(),
+            "application/vnd.oci.image.manifest.v1+json, \
+             application/vnd.docker.distribution.manifest.v2+json;q=0.9",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[CONTENT_TYPE],
+        "application/vnd.docker.distribution.manifest.v2+json"
+    );
+    assert_eq!(collect_body(response.into_body()).await, RAW_MANIFEST);
+
+    // Converted to OCI, with its own digest.
+    let response = app
+        .call(get_manifest(
+            "latest".to_owned(),
+            "application/vnd.oci.image.manifest.v1+json",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[CONTENT_TYPE],
+        "application/vnd.oci.image.manifest.v1+json"
+    );
+    let digest = response.headers()["Docker-Content-Digest"]
+        .to_str()
+        .unwrap()
+        .to_owned();
+    let converted = collect_body(response.into_body()).await;
+    assert_eq!(
+        digest,
+        ImageDigest::new(Digest::from_contents(&converted)).to_string()
+    );
+    assert_ne!(digest, MANIFEST_DIGEST.to_string());
+    let converted: serde_json::Value = serde_json::from_slice(&converted).unwrap();
+    assert_eq!(
+        converted["config"]["mediaType"],
+        "application/vnd.oci.image.config.v1+json"
+    );
+
+    // Manifests requested by digest are never converted.
+    let (status, errors) = call_for_error(
+        app,
+        get_manifest(
+            MANIFEST_DIGEST.to_string(),
+            "application/vnd.oci.image.manifest.v1+json",
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+    assert_eq!(errors["errors"][0]["code"], "MANIFEST_UNKNOWN");
+
+    // Neither stored nor convertible.
+    let (status, _) = call_for_error(
+        app,
+        get_manifest(
+            "latest".to_owned(),
+            "application/vnd.oci.image.index.v1+json",
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+}
+
+#[tokio::test]
+async fn arbitrary_depth_names() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    for name in ["alpine", "team/project/service", "team/project"] {
+        insert_sample_image(&ctx, &ImageLocation::new(name).unwrap(), &["latest"]).await;
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .header(AUTHORIZATION, basic_auth())
+                    .uri(format!("/v2/{name}/manifests/latest"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(collect_body(response.into_body()).await, RAW_MANIFEST);
+
+        let response = app
+            .call(
+                Request::builder()
+                    .method("HEAD")
+                    .header(AUTHORIZATION, basic_auth())
+                    .uri(format!("/v2/{name}/blobs/{}", IMAGE_DIGEST))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // Nested images do not clash with tags of their parents.
+    insert_sample_image(
+        &ctx,
+        &ImageLocation::new("team/project").unwrap(),
+        &["service"],
+    )
+    .await;
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/_catalog")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let catalog: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(
+        catalog["repositories"],
+        serde_json::json!(["alpine", "team/project", "team/project/service"])
+    );
+
+    // Invalid names are rejected.
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/Invalid/Name/manifests/latest")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let errors: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(errors["errors"][0]["code"], "NAME_INVALID");
+}
+
+#[tokio::test]
+async fn migrates_legacy_tag_layout() {
+    let storage = tempdir::TempDir::new("container-registry-legacy-layout").unwrap();
+
+    // Populate storage using the old `tags/<repository>/<image>/<tag>` layout.
+    let manifests = storage.path().join("manifests");
+    let legacy_image = storage.path().join("tags").join("tests").join("sample");
+    std::fs::create_dir_all(&manifests).unwrap();
+    std::fs::create_dir_all(&legacy_image).unwrap();
+    std::fs::write(
+        manifests.join(MANIFEST_DIGEST.digest.to_string()),
+        RAW_MANIFEST,
+    )
+    .unwrap();
+    for tag in ["latest", "stable"] {
+        std::os::unix::fs::symlink(
+            format!("../../../manifests/{}", MANIFEST_DIGEST.digest),
+            legacy_image.join(tag),
+        )
+        .unwrap();
+    }
+
+    let ctx = ContainerRegistry::builder()
+        .storage(storage.path())
+        .build_for_testing();
+
+    assert_eq!(
+        ctx.registry
+            .storage
+            .get_manifest(&ManifestReference::new(
+                sample_location(),
+                Reference::new_tag("latest")
+            ))
+            .await
+            .expect("failed to get reference by tag")
+            .expect("missing reference by tag"),
+        RAW_MANIFEST
+    );
+    assert_eq!(
+        ctx.registry.storage.list_repositories().await.unwrap(),
+        vec![sample_location()]
+    );
+    assert_eq!(
+        ctx.registry
+            .storage
+            .list_tags(&sample_location())
+            .await
+            .unwrap(),
+        Some(vec!["latest".to_owned(), "stable".to_owned()])
+    );
+
+    // Migrated manifests are linked to their image, like pushed ones.
+    assert_eq!(
+        ctx.registry
+            .storage
+            .list_image_manifests(&sample_location())
+            .await
+            .unwrap(),
+        vec![MANIFEST_DIGEST.digest]
+    );
+}
+
+#[tokio::test]
+async fn keeps_tags_across_restarts() {
+    let storage = tempdir::TempDir::new("container-registry-restart").unwrap();
+    let alpine = ImageLocation::new("alpine").unwrap();
+    let reference = ManifestReference::new(alpine.clone(), Reference::new_tag("latest"));
+
+    // Single-component images keep `_tags` and `_manifests` right below `tags`, where legacy
+    // images used to be stored.
+    let ctx = ContainerRegistry::builder()
+        .storage(storage.path())
+        .build_for_testing();
+    insert_sample_image(&ctx, &alpine, &["latest"]).await;
+    drop(ctx);
+
+    for _ in 0..2 {
+        let ctx = ContainerRegistry::builder()
+            .storage(storage.path())
+            .build_for_testing();
+
+        assert_eq!(
+            ctx.registry
+                .storage
+                .get_manifest(&reference)
+                .await
+                .expect("failed to get reference by tag")
+                .expect("missing reference by tag"),
+            RAW_MANIFEST
+        );
+        assert_eq!(
+            ctx.registry.storage.list_repositories().await.unwrap(),
+            vec![alpine.clone()]
+        );
+    }
+}
+
+#[tokio::test]
+async fn custom_storage_backend() {
+    let storage = tempdir::TempDir::new("container-registry-custom-backend").unwrap();
+
+    let ctx = ContainerRegistry::builder()
+        .storage_backend(Box::new(FilesystemStorage::new(storage.path()).unwrap()))
+        .build_for_testing();
+    assert!(ctx.temp_storage.is_none());
+
+    insert_sample_image(&ctx, &sample_location(), &["latest"]).await;
+
+    assert!(storage
+        .path()
+        .join("manifests")
+        .join(MANIFEST_DIGEST.digest.to_string())
+        .exists());
+}
+
+/// Sends a request, returning the response status along with the decoded OCI error body.
+async fn call_for_error(
+    app: &mut RouterIntoService<Body>,
+    request: Request<Body>,
+) -> (StatusCode, serde_json::Value) {
+    let response = app.call(request).await.unwrap();
+    let status = response.status();
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+    let errors = serde_json::from_slice(&collect_body(response.into_body()).await)
+        .expect("error body should be JSON");
+
+    (status, errors)
+}
+
+#[tokio::test]
+async fn errors_are_reported_as_oci_errors() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    let request = |method: &str, uri: &str, body: Body| {
+        Request::builder()
+            .method(method)
+            .header(AUTHORIZATION, basic_auth())
+            .uri(uri)
+            .body(body)
+            .unwrap()
+    };
+
+    // Invalid credentials.
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, invalid_basic_auth())
+                .uri("/v2/tests/sample/tags/list")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        response.headers()["WWW-Authenticate"],
+        "Basic realm=\"ContainerRegistry\""
+    );
+    let errors: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(errors["errors"][0]["code"], "UNAUTHORIZED");
+
+    let (status, errors) =
+        call_for_error(app, request("GET", "/v2/Invalid/tags/list", Body::empty())).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(errors["errors"][0]["code"], "NAME_INVALID");
+
+    let (status, errors) = call_for_error(
+        app,
+        request("GET", "/v2/tests/sample/manifests/-latest", Body::empty()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(errors["errors"][0]["code"], "TAG_INVALID");
+
+    let (status, errors) = call_for_error(
+        app,
+        request(
+            "GET",
+            &format!("/v2/tests/sample/blobs/{IMAGE_DIGEST}"),
+            Body::empty(),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(errors["errors"][0]["code"], "BLOB_UNKNOWN");
+
+    let (status, errors) = call_for_error(
+        app,
+        request("PUT", "/v2/tests/sample/manifests/latest", Body::from("{}")),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(errors["errors"][0]["code"], "MANIFEST_INVALID");
+    assert!(errors["errors"][0]["detail"].is_string());
+
+    let (status, errors) = call_for_error(
+        app,
+        request("GET", "/v2/tests/sample/unknown/endpoint", Body::empty()),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(errors["errors"][0]["code"], "UNSUPPORTED");
+
+    let response = app
+        .call(request("POST", "/v2/tests/sample/tags/list", Body::empty()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers()[ALLOW], "GET");
+    let errors: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(errors["errors"][0]["code"], "UNSUPPORTED");
+
+    // Finishing an upload with the wrong digest.
+    let response = app
+        .call(request(
+            "POST",
+            "/v2/tests/sample/blobs/uploads/",
+            Body::empty(),
+        ))
+        .await
+        .unwrap();
+    let upload_location = response.headers()[LOCATION].to_str().unwrap().to_owned();
+    let (status, errors) = call_for_error(
+        app,
+        request(
+            "PUT",
+            &format!("{upload_location}?digest={MANIFEST_DIGEST}"),
+            Body::from(RAW_IMAGE),
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(errors["errors"][0]["code"], "DIGEST_INVALID");
+}
+
+#[tokio::test]
+async fn denied_access_is_reported_as_oci_error() {
+    let ctx = ContainerRegistry::builder()
+        .auth_provider(Arc::new(PublicOnly))
+        .build_for_testing();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    let (status, errors) = call_for_error(
+        app,
+        Request::builder()
+            .method("GET")
+            .uri("/v2/private/a/tags/list")
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(errors["errors"][0]["code"], "DENIED");
+}
+
+#[tokio::test]
+async fn missing_manifest_returns_404() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/doesnot/exist/manifests/latest")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+/// Stores a blob directly, bypassing the HTTP interface.
+async fn insert_blob(ctx: &TestingContainerRegistry, contents: &[u8]) {
+    let upload = ctx
+        .registry
+        .storage
+        .begin_new_upload()
+        .await
+        .expect("could not start upload");
+    let mut writer = ctx
+        .registry
+        .storage
+        .get_upload_writer(0, upload)
+        .await
+        .expect("could not create upload writer");
+    writer
+        .write_all(contents)
+        .await
+        .expect("failed to write blob");
+    writer.flush().await.expect("failed to flush blob");
+    ctx.registry
+        .storage
+        .finalize_upload(upload, Digest::from_contents(contents))
+        .await
+        .expect("failed to finalize upload");
+}
+
+/// Returns the location of the `tests/sample` image.
+fn sample_location() -> ImageLocation {
+    ImageLocation::new("tests/sample").unwrap()
+}
+
+/// Stores the sample image blob and its manifest under the given tags of `location`.
+async fn insert_sample_image(
+    ctx: &TestingContainerRegistry,
+    location: &ImageLocation,
+    tags: &[&str],
+) {
+    insert_blob(ctx, RAW_CONFIG).await;
+    insert_blob(ctx, RAW_IMAGE).await;
+
+    for tag in tags {
+        ctx.registry
+            .storage
+            .put_manifest(
+                &ManifestReference::new(location.clone(), Reference::new_tag(tag)),
+                RAW_MANIFEST,
+                RAW_MANIFEST_MEDIA_TYPE,
+                true,
+            )
+            .await
+            .expect("failed to store manifest");
+    }
+}
+
+#[tokio::test]
+async fn tag_listing() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    insert_sample_image(&ctx, &sample_location(), &["v2", "latest", "v1"]).await;
+
+    // Full listing, sorted lexically.
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/tests/sample/tags/list")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(LINK).is_none());
+    let tag_list: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(
+        tag_list,
+        serde_json::json!({"name": "tests/sample", "tags": ["latest", "v1", "v2"]})
+    );
+
+    // First page.
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/tests/sample/tags/list?n=2")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get(LINK).unwrap(),
+        "</v2/tests/sample/tags/list?n=2&last=v1>; rel=\"next\""
+    );
+    let tag_list: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(tag_list["tags"], serde_json::json!(["latest", "v1"]));
+
+    // Second (and last) page.
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/tests/sample/tags/list?n=2&last=v1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(LINK).is_none());
+    let tag_list: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(tag_list["tags"], serde_json::json!(["v2"]));
+
+    // Unknown images are reported as such.
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/doesnot/exist/tags/list")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn tag_listing_requires_read_access() {
+    let ctx = ContainerRegistry::builder()
+        .auth_provider(Arc::new(Anonymous::new(
+            crate::auth::Permissions::NoAccess,
+            Secret::new(TEST_PASSWORD.to_owned()),
+        )))
+        .build_for_testing();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    insert_sample_image(&ctx, &sample_location(), &["latest"]).await;
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri("/v2/tests/sample/tags/list")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/tests/sample/tags/list")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn manifest_deletion() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    let other_location = ImageLocation::new("other/sample").unwrap();
+    insert_sample_image(&ctx, &sample_location(), &["latest", "v1"]).await;
+    insert_sample_image(&ctx, &other_location, &["latest"]).await;
+
+    // Deleting by tag only removes the tag.
+    let response = app
+        .call(
+            Request::builder()
+                .method("DELETE")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/tests/sample/manifests/v1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(
+        ctx.registry
+            .storage
+            .list_tags(&sample_location())
+            .await
+            .unwrap()
+            .unwrap(),
+        vec!["latest".to_owned()]
+    );
+
+    // Deleting it again fails.
+    let response = app
+        .call(
+            Request::builder()
+                .method("DELETE")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/tests/sample/manifests/v1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let errors: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(errors["errors"][0]["code"], "MANIFEST_UNKNOWN");
+
+    // Deleting by digest removes all remaining tags of the image.
+    let response = app
+        .call(
+            Request::builder()
+                .method("DELETE")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(format!("/v2/tests/sample/manifests/{}", MANIFEST_DIGEST))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert!(ctx
+        .registry
+        .storage
+        .list_tags(&sample_location())
+        .await
+        .unwrap()
+        .unwrap()
+        .is_empty());
+
+    // The manifest is still in use by the other image, which must be unaffected.
+    let other_latest = ManifestReference::new(other_location.clone(), Reference::new_tag("latest"));
+    assert!(ctx
+        .registry
+        .storage
+        .get_manifest(&other_latest)
+        .await
+        .unwrap()
+        .is_some());
+
+    // Images not linking the manifest cannot delete it.
+    let response = app
+        .call(
+            Request::builder()
+                .method("DELETE")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(format!(
+                    "/v2/unrelated/sample/manifests/{}",
+                    MANIFEST_DIGEST
+                ))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let errors: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(errors["errors"][0]["code"], "MANIFEST_UNKNOWN");
+
+    // Once the last user is gone, the manifest is removed.
+    let response = app
+        .call(
+            Request::builder()
+                .method("DELETE")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(format!("/v2/other/sample/manifests/{}", MANIFEST_DIGEST))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert!(ctx
+        .registry
+        .storage
+        .get_manifest(&ManifestReference::new(
+            other_location,
+            Reference::new_digest(MANIFEST_DIGEST.digest)
+        ))
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn manifest_deletion_can_be_disabled() {
+    let ctx = ContainerRegistry::builder()
+        .auth_provider(Arc::new(Secret::new(TEST_PASSWORD.to_owned())))
+        .allow_deletion(false)
+        .build_for_testing();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    insert_sample_image(&ctx, &sample_location(), &["latest"]).await;
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("DELETE")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/tests/sample/manifests/latest")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers()[ALLOW], "GET, HEAD, PUT");
+    let errors: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(errors["errors"][0]["code"], "UNSUPPORTED");
+
+    assert_eq!(
+        ctx.registry
+            .storage
+            .list_tags(&sample_location())
+            .await
+            .unwrap()
+            .unwrap(),
+        vec!["latest".to_owned()]
+    );
+}
+
+#[tokio::test]
+async fn manifest_push_by_digest() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    insert_blob(&ctx, RAW_CONFIG).await;
+    insert_blob(&ctx, RAW_IMAGE).await;
+
+    let put_manifest = |uri: String| {
+        Request::builder()
+            .method("PUT")
+            .header(AUTHORIZATION, basic_auth())
+            .uri(uri)
+            .body(Body::from(RAW_MANIFEST))
+            .unwrap()
+    };
+
+    // The digest must match the manifest.
+    let response = app
+        .call(put_manifest(format!(
+            "/v2/tests/sample/manifests/{IMAGE_DIGEST}"
+        )))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let errors: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(errors["errors"][0]["code"], "DIGEST_INVALID");
+
+    let manifest_by_digest_location = format!("/v2/tests/sample/manifests/{MANIFEST_DIGEST}");
+    let response = app
+        .call(put_manifest(manifest_by_digest_location.clone()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(
+        response.headers()[LOCATION],
+        manifest_by_digest_location.as_str()
+    );
+    assert_eq!(
+        response.headers()["Docker-Content-Digest"],
+        MANIFEST_DIGEST.to_string()
+    );
+
+    // No tag is created, but the image is known.
+    assert_eq!(
+        ctx.registry
+            .storage
+            .list_tags(&sample_location())
+            .await
+            .unwrap(),
+        Some(Vec::new())
+    );
+
+    let get_manifest = |uri: &str| {
+        Request::builder()
+            .method("GET")
+            .header(AUTHORIZATION, basic_auth())
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = app
+        .call(get_manifest(&manifest_by_digest_location))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(collect_body(response.into_body()).await, RAW_MANIFEST);
+
+    // Deleting the manifest from another image keeps it available to the untagged one.
+    let response = app
+        .call(put_manifest("/v2/tests/other/manifests/latest".to_owned()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("DELETE")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(format!("/v2/tests/other/manifests/{MANIFEST_DIGEST}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    let response = app
+        .call(get_manifest(&manifest_by_digest_location))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn manifest_media_type_is_validated_and_stored() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    insert_blob(&ctx, RAW_CONFIG).await;
+    insert_blob(&ctx, RAW_IMAGE).await;
+
+    let put_manifest = |body: Vec<u8>, content_type: &str| {
+        Request::builder()
+            .method("PUT")
+            .header(AUTHORIZATION, basic_auth())
+            .header(CONTENT_TYPE, content_type)
+            .uri("/v2/tests/sample/manifests/latest")
+            .body(Body::from(body))
+            .unwrap()
+    };
+
+    let (status, errors) = call_for_error(
+        app,
+        put_manifest(
+            RAW_MANIFEST.to_vec(),
+            "application/vnd.oci.image.manifest.v1+json",
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(errors["errors"][0]["code"], "MANIFEST_INVALID");
+
+    let (status, _) = call_for_error(
+        app,
+        put_manifest(
+            RAW_MANIFEST.to_vec(),
+            "application/vnd.oci.image.index.v1+json",
+        ),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // Without a media type in the manifest, the `Content-Type` decides.
+    let mut untyped: serde_json::Value = serde_json::from_slice(RAW_MANIFEST).unwrap();
+    untyped.as_object_mut().unwrap().remove("mediaType");
+    let response = app
+        .call(put_manifest(
+            untyped.to_string().into_bytes(),
+            "application/vnd.docker.distribution.manifest.v2+json; charset=utf-8",
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .uri("/v2/tests/sample/manifests/latest")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[CONTENT_TYPE],
+        "application/vnd.docker.distribution.manifest.v2+json"
+    );
+    assert_eq!(
+        collect_body(response.into_body()).await,
+        untyped.to_string().as_bytes()
+    );
+}
+
+#[tokio::test]
+async fn manifest_references_are_validated() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    let put_manifest = |body: Vec<u8>| {
+        Request::builder()
+            .method("PUT")
+            .header(AUTHORIZATION, basic_auth())
+            .uri("/v2/tests/sample/manifests/latest")
+            .body(Body::from(body))
+            .unwrap()
+    };
+    let unknown_digests = |errors: serde_json::Value| -> Vec<String> {
+        errors["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| {
+                assert_eq!(error["code"], "MANIFEST_BLOB_UNKNOWN");
+                error["detail"].as_str().unwrap().to_owned()
+            })
+            .collect()
+    };
+
+    // Neither config nor layer have been uploaded.
+    let response = app.call(put_manifest(RAW_MANIFEST.to_vec())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let errors = serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(
+        unknown_digests(errors),
+        [CONFIG_DIGEST.to_string(), IMAGE_DIGEST.to_string()]
+    );
+
+    // A size mismatch is rejected as well.
+    insert_blob(&ctx, RAW_CONFIG).await;
+    insert_blob(&ctx, RAW_IMAGE).await;
+    let wrong_size = String::from_utf8(RAW_MANIFEST.to_vec())
+        .unwrap()
+        .replace(r#""size": 110"#, r#""size": 111"#);
+    let response = app
+        .call(put_manifest(wrong_size.into_bytes()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let errors = serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(unknown_digests(errors), [IMAGE_DIGEST.to_string()]);
+
+    // Indexes must only reference existing manifests.
+    let index = sample_index("application/vnd.oci.image.index.v1+json");
+    let response = app
+        .call(put_manifest(index.clone().into_bytes()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let errors = serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(unknown_digests(errors), [MANIFEST_DIGEST.to_string()]);
+
+    let response = app.call(put_manifest(RAW_MANIFEST.to_vec())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = app.call(put_manifest(index.into_bytes())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // Nothing was stored for rejected manifests.
+    assert_eq!(
+        ctx.registry.storage.list_manifests().await.unwrap().len(),
+        2
+    );
+
+    // Foreign and non-distributable layers are never pushed.
+    let mut foreign: serde_json::Value = serde_json::from_slice(RAW_MANIFEST).unwrap();
+    let layers = foreign["layers"].as_array_mut().unwrap();
+    layers.push(serde_json::json!({
+        "mediaType": "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
+        "size": 1024,
+        "digest": format!("sha256:{}", "a".repeat(64)),
+        "urls": ["https://example.com/layer.tar.gz"],
+    }));
+    layers.push(serde_json::json!({
+        "mediaType": "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip",
+        "size": 1024,
+        "digest": format!("sha256:{}", "b".repeat(64)),
+    }));
+    let response = app
+        .call(put_manifest(foreign.to_string().into_bytes()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn immutable_tags() {
+    let ctx = ContainerRegistry::builder()
+        .auth_provider(Arc::new(Secret::new(TEST_PASSWORD.to_owned())))
+        .immutable_tags(ImmutableTags::none().rule("tests/*", "v*"))
+        .build_for_testing();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    insert_sample_image(&ctx, &sample_location(), &["latest"]).await;
+    let index = sample_index("application/vnd.oci.image.index.v1+json");
+
+    let request = |method: &str, reference: &str, body: &[u8]| {
+        Request::builder()
+            .method(method)
+            .header(AUTHORIZATION, basic_auth())
+            .uri(format!("/v2/tests/sample/manifests/{reference}"))
+            .body(Body::from(body.to_vec()))
+            .unwrap()
+    };
+
+    // Pushing the same content again is fine.
+    for _ in 0..2 {
+        let response = app
+            .call(request("PUT", "v1.0.0", RAW_MANIFEST))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+    }
+
+    let (status, errors) = call_for_error(app, request("PUT", "v1.0.0", index.as_bytes())).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(errors["errors"][0]["code"], "DENIED");
+
+    let (status, _) = call_for_error(app, request("DELETE", "v1.0.0", &[])).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, _) =
+        call_for_error(app, request("DELETE", &MANIFEST_DIGEST.to_string(), &[])).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Tags not covered by the policy remain mutable.
+    let response = app
+        .call(request("PUT", "latest", index.as_bytes()))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let response = app.call(request("DELETE", "latest", &[])).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+}
+
+#[tokio::test]
+async fn blob_deletion() {
+    let ctx = ContainerRegistry::builder()
+        .auth_provider(Arc::new(Secret::new(TEST_PASSWORD.to_owned())))
+        .protect_referenced_blobs(false)
+        .build_for_testing();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    let other_location = ImageLocation::new("other/tenant").unwrap();
+    insert_sample_image(&ctx, &sample_location(), &["latest"]).await;
+    insert_sample_image(&ctx, &other_location, &["latest"]).await;
+
+    let blob_location = format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST);
+    let delete = || {
+        Request::builder()
+            .method("DELETE")
+            .header(AUTHORIZATION, basic_auth())
+            .uri(&blob_location)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    // Blobs used by other images are protected regardless of the setting.
+    let (status, errors) = call_for_error(app, delete()).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(errors["errors"][0]["code"], "DENIED");
+    assert!(ctx
+        .registry
+        .storage
+        .get_blob_metadata(IMAGE_DIGEST.digest)
+        .await
+        .unwrap()
+        .is_some());
+
+    assert!(ctx
+        .registry
+        .storage
+        .delete_manifest(&other_location, MANIFEST_DIGEST.digest)
+        .await
+        .unwrap());
+
+    // References by the image itself are not checked.
+    let response = app.call(delete()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert!(ctx
+        .registry
+        .storage
+        .get_blob_metadata(IMAGE_DIGEST.digest)
+        .await
+        .unwrap()
+        .is_none());
+
+    let (status, errors) = call_for_error(app, delete()).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(errors["errors"][0]["code"], "BLOB_UNKNOWN");
+}
+
+#[tokio::test]
+async fn blob_range_requests() {
+    let ctx = registry_with_test_password();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    insert_sample_image(&ctx, &sample_location(), &["latest"]).await;
+
+    let blob_location = format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST);
+    let get_range = |range: Option<&str>| {
+        let mut builder = Request::builder()
+            .method("GET")
+            .header(AUTHORIZATION, basic_auth())
+            .uri(&blob_location);
+        if let Some(range) = range {
+            builder = builder.header(RANGE, range);
+        }
+        builder.body(Body::empty()).unwrap()
+    };
+
+    let response = app.call(get_range(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_LENGTH], "110");
+    assert_eq!(response.headers()[ACCEPT_RANGES], "bytes");
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/octet-stream");
+    assert_eq!(
+        response.headers()["Docker-Content-Digest"],
+        IMAGE_DIGEST.to_string()
+    );
+    assert_eq!(collect_body(response.into_body()).await, RAW_IMAGE);
+
+    let response = app.call(get_range(Some("bytes=10-19"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()[CONTENT_LENGTH], "10");
+    assert_eq!(response.headers()[CONTENT_RANGE], "bytes 10-19/110");
+    assert_eq!(collect_body(response.into_body()).await, &RAW_IMAGE[10..20]);
+
+    let response = app.call(get_range(Some("bytes=100-"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()[CONTENT_RANGE], "bytes 100-109/110");
+    assert_eq!(collect_body(response.into_body()).await, &RAW_IMAGE[100..]);
+
+    let response = app.call(get_range(Some("bytes=-5"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()[CONTENT_RANGE], "bytes 105-109/110");
+    assert_eq!(collect_body(response.into_body()).await, &RAW_IMAGE[105..]);
+
+    let response = app.call(get_range(Some("bytes=110-"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers()[CONTENT_RANGE], "bytes */110");
+
+    let response = app.call(get_range(Some("items=0-1"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+    // Unsupported ranges are ignored.
+    let response = app.call(get_range(Some("bytes=0-1,5-6"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(CONTENT_RANGE).is_none());
+    assert_eq!(collect_body(response.into_body()).await, RAW_IMAGE);
+
+    insert_blob(&ctx, b"").await;
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .header(AUTHORIZATION, basic_auth())
+                .header(RANGE, "bytes=0-")
+                .uri(format!(
+                    "/v2/tests/sample/blobs/{}",
+                    ImageDigest::new(Digest::from_contents(b""))
+                ))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_LENGTH], "0");
+    assert!(collect_body(response.into_body()).await.is_empty());
+}
+
+#[tokio::test]
+async fn referenced_blobs_can_be_protected_from_deletion() {
+    let ctx = ContainerRegistry::builder()
+        .auth_provider(Arc::new(Secret::new(TEST_PASSWORD.to_owned())))
+        .protect_referenced_blobs(true)
+        .build_for_testing();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    insert_sample_image(&ctx, &sample_location(), &["latest"]).await;
+
+    let blob_location = format!("/v2/tests/sample/blobs/{}", IMAGE_DIGEST);
+
+    // The manifest still references the blob.
+    let response = app
+        .call(
+            Request::builder()
+                .method("DELETE")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(&blob_location)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert!(ctx
+        .registry
+        .storage
+        .get_blob_metadata(IMAGE_DIGEST.digest)
+        .await
+        .unwrap()
+        .is_some());
+
+    // Unparsable manifests elsewhere in storage do not fail the deletion.
+    let garbage = b"not a manifest";
+    std::fs::write(
+        ctx.temp_storage
+            .as_ref()
+            .unwrap()
+            .path()
+            .join("manifests")
+            .join(Digest::from_contents(garbage).to_string()),
+        garbage,
+    )
+    .unwrap();
+
+    // After removing the manifest, the blob can be deleted.
+    assert!(ctx
+        .registry
+        .storage
+        .delete_manifest(&sample_location(), MANIFEST_DIGEST.digest)
+        .await
+        .unwrap());
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("DELETE")
+                .header(AUTHORIZATION, basic_auth())
+                .uri(&blob_location)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+}
+
+/// Auth provider granting read access only to images in the `public` repository.
+struct PublicOnly;
+
+#[async_trait]
+impl AuthProvider for PublicOnly {
+    async fn check_credentials(&self, _unverified: &Unverified) -> Option<ValidCredentials> {
+        Some(ValidCredentials::new(()))
+    }
+
+    async fn image_permissions(
+        &self,
+        _creds: &ValidCredentials,
+        image: &ImageLocation,
+    ) -> Permissions {
+        if image.components().next() == Some("public") {
+            Permissions::ReadOnly
+        } else {
+            Permissions::NoAccess
+        }
+    }
+
+    async fn blob_permissions(
+        &self,
+        _creds: &ValidCredentials,
+        _blob: &ImageDigest,
+    ) -> Permissions {
+        Permissions::ReadOnly
+    }
+}
+
+#[tokio::test]
+async fn garbage_collection() {
+    let ctx = registry_with_test_password();
+    let storage = &ctx.registry.storage;
+    let location = sample_location();
+
+    // Reachable through tags, directly or as a referrer.
+    insert_sample_image(&ctx, &location, &["latest"]).await;
+    insert_blob(&ctx, b"{}").await;
+    let index = sample_index("application/vnd.oci.image.index.v1+json");
+    let signature = sample_referrer("application/vnd.example.signature");
+    for (reference, manifest, media_type) in [
+        (
+            Reference::new_tag("multiarch"),
+            &index,
+            "application/vnd.oci.image.index.v1+json",
+        ),
+        (
+            Reference::new_digest(Digest::from_contents(signature.as_bytes())),
+            &signature,
+            "application/vnd.oci.image.manifest.v1+json",
+        ),
+    ] {
+        storage
+            .put_manifest(
+                &ManifestReference::new(location.clone(), reference),
+                manifest.as_bytes(),
+                media_type,
+                true,
+            )
+            .await
+            .unwrap();
+    }
+
+    // An image whose tag has been removed, along with a referrer to it.
+    let orphaned_layer = b"orphaned layer";
+    insert_blob(&ctx, orphaned_layer).await;
+    let orphan = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.oci.image.manifest.v1+json",
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "size": RAW_CONFIG.len(),
+            "digest": CONFIG_DIGEST.to_string()
+        },
+        "layers": [{
+            "mediaType": "application/vnd.oci.image.layer.v1.tar",
+            "size": orphaned_layer.len(),
+            "digest": ImageDigest::new(Digest::from_contents(orphaned_layer)).to_string()
+        }]
+    })
+    .to_string();
+    let orphan_digest = storage
+        .put_manifest(
+            &ManifestReference::new(location.clone(), Reference::new_tag("old")),
+            orphan.as_bytes(),
+            "application/vnd.oci.image.manifest.v1+json",
+            true,
+        )
+        .await
+        .unwrap();
+    assert!(storage.delete_tag(&location, "old").await.unwrap());
+    let orphan_referrer = sample_referrer("application/vnd.example.signature").replace(
+        &MANIFEST_DIGEST.to_string(),
+        &ImageDigest::new(orphan_digest).to_string(),
+    );
+    let orphan_referrer_digest = storage
+        .put_manifest(
+            &ManifestReference::new(location.clone(), Reference::new_tag("unrelated")),
+            orphan_referrer.as_bytes(),
+            "application/vnd.oci.image.manifest.v1+json",
+            true,
+        )
+        .await
+        .unwrap();
+    assert!(storage.delete_tag(&location, "unrelated").await.unwrap());
+
+    // A blob that was never referenced at all.
+    let unreferenced = b"unreferenced blob";
+    insert_blob(&ctx, unreferenced).await;
+
+    // Everything was just written, thus is protected by the grace period.
+    let report = ctx
+        .registry
+        .collect_garbage(&GarbageCollector::new())
+        .await
+        .unwrap();
+    assert!(report.manifests().is_empty());
+    assert!(report.blobs().is_empty());
+
+    let collector = GarbageCollector::new().grace_period(Duration::ZERO);
+    let mut expected_manifests = vec![orphan_digest, orphan_referrer_digest];
+    expected_manifests.sort();
+    let mut expected_blobs = vec![
+        Digest::from_contents(orphaned_layer),
+        Digest::from_contents(unreferenced),
+    ];
+    expected_blobs.sort();
+    let expected_bytes =
+        (orphan.len() + orphan_referrer.len() + orphaned_layer.len() + unreferenced.len()) as u64;
+
+    let check_report = |report: GarbageCollectionReport| {
+        let mut manifests: Vec<_> = report
+            .manifests()
+            .iter()
+            .map(|manifest_reference| {
+                assert_eq!(manifest_reference.location(), &location);
+                match manifest_reference.reference() {
+                    Reference::Digest(digest) => *digest,
+                    Reference::Tag(_) => panic!("manifests should be reported by digest"),
+                }
+            })
+            .collect();
+        manifests.sort();
+        assert_eq!(manifests, expected_manifests);
+
+        let mut blobs = report.blobs().to_vec();
+        blobs.sort();
+        assert_eq!(blobs, expected_blobs);
+
+        assert_eq!(report.reclaimable_bytes(), expected_bytes);
+    };
+
+    let report = ctx
+        .registry
+        .collect_garbage(&collector.clone().dry_run(true))
+        .await
+        .unwrap();
+    assert!(report.is_dry_run());
+    check_report(report);
+    let orphan_reference =
+        ManifestReference::new(location.clone(), Reference::new_digest(orphan_digest));
+    assert!(storage
+        .get_manifest(&orphan_reference)
+        .await
+        .unwrap()
+        .is_some());
+    assert!(storage
+        .get_blob_metadata(Digest::from_contents(unreferenced))
+        .await
+        .unwrap()
+        .is_some());
+
+    let report = ctx.registry.collect_garbage(&collector).await.unwrap();
+    assert!(!report.is_dry_run());
+    check_report(report);
+    assert!(storage
+        .get_manifest(&orphan_reference)
+        .await
+        .unwrap()
+        .is_none());
+    for blob in &expected_blobs {
+        assert!(storage.get_blob_metadata(*blob).await.unwrap().is_none());
+    }
+
+    // Reachable content is untouched.
+    let mut remaining = storage.list_image_manifests(&location).await.unwrap();
+    remaining.sort();
+    let mut reachable = vec![
+        MANIFEST_DIGEST.digest,
+        Digest::from_contents(index.as_bytes()),
+        Digest::from_contents(signature.as_bytes()),
+    ];
+    reachable.sort();
+    assert_eq!(remaining, reachable);
+    for blob in [
+        IMAGE_DIGEST.digest,
+        CONFIG_DIGEST.digest,
+        Digest::from_contents(b"{}"),
+    ] {
+        assert!(storage.get_blob_metadata(blob).await.unwrap().is_some());
+    }
+
+    let report = ctx.registry.collect_garbage(&collector).await.unwrap();
+    assert!(report.manifests().is_empty());
+    assert!(report.blobs().is_empty());
+    assert_eq!(report.reclaimable_bytes(), 0);
+}
+
+#[tokio::test]
+async fn catalog_listing() {
+    let ctx = ContainerRegistry::builder()
+        .auth_provider(Arc::new(PublicOnly))
+        .build_for_testing();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    for (repository, image) in [
+        ("public", "b"),
+        ("private", "a"),
+        ("public", "a"),
+        ("public", "c"),
+    ] {
+        let location = ImageLocation::new(format!("{repository}/{image}")).unwrap();
+        insert_sample_image(&ctx, &location, &["latest"]).await;
+    }
+
+    // Only readable repositories are listed.
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri("/v2/_catalog")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(LINK).is_none());
+    let catalog: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(
+        catalog,
+        serde_json::json!({"repositories": ["public/a", "public/b", "public/c"]})
+    );
+
+    // Paginated.
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri("/v2/_catalog?n=2")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get(LINK).unwrap(),
+        "</v2/_catalog?n=2&last=public%2Fb>; rel=\"next\""
+    );
+    let catalog: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(
+        catalog["repositories"],
+        serde_json::json!(["public/a", "public/b"])
+    );
+
+    let response = app
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri("/v2/_catalog?n=2&last=public%2Fb")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(LINK).is_none());
+    let catalog: serde_json::Value =
+        serde_json::from_slice(&collect_body(response.into_body()).await).unwrap();
+    assert_eq!(catalog["repositories"], serde_json::json!(["public/c"]));
+}
+
+/// Auth provider granting fixed permissions per image name, and no access to any other image.
+struct PerImage(Vec<(&'static str, Permissions)>);
+
+#[async_trait]
+impl AuthProvider for PerImage {
+    async fn check_credentials(&self, _unverified: &Unverified) -> Option<ValidCredentials> {
+        Some(ValidCredentials::new(()))
+    }
+
+    async fn image_permissions(
+        &self,
+        _creds: &ValidCredentials,
+        image: &ImageLocation,
+    ) -> Permissions {
+        self.0
+            .iter()
+            .find(|(name, _)| *name == image.name())
+            .map(|(_, permissions)| *permissions)
+            .unwrap_or(Permissions::NoAccess)
+    }
+
+    async fn blob_permissions(
+        &self,
+        _creds: &ValidCredentials,
+        _blob: &ImageDigest,
+    ) -> Permissions {
+        Permissions::ReadOnly
+    }
+}
+
+#[tokio::test]
+async fn cross_repository_blob_mount() {
+    let ctx = ContainerRegistry::builder()
+        .auth_provider(Arc::new(PerImage(vec![
+            ("staging/app", Permissions::ReadOnly),
+            ("prod/app", Permissions::ReadWrite),
+        ])))
+        .build_for_testing();
+    let mut service = ctx.make_service();
+    let app = service.ready().await.expect("could not launch service");
+
+    insert_sample_image(
+        &ctx,
+        &ImageLocation::new("staging/app").unwrap(),
+        &["latest"],
+    )
+    .await;
+    insert_sample_image(
+        &ctx,
+        &ImageLocation::new("secret/app").unwrap(),
+        &["latest"],
+    )
+    .await;
+
+    // Mounting a readable, existing blob succeeds without an upload.
+    let response = app
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri(format!(
+                    "/v2/prod/app/blobs/uploads/?mount={}&from=staging/app",
+                    IMAGE_DIGEST
+                ))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(
+        response.headers().get(LOCATION).unwrap(),
+        format!("/v2/prod/app/blobs/{}", IMAGE_DIGEST).as_str()
+    );
+    assert_eq!(
+        response.headers().get("Docker-Content-Digest").unwrap(),
+        IMAGE_DIGEST.to_string().as_str()
+    );
+
+    // Unreadable sources, missing blobs and invalid names fall back to a regular upload.
+    for (mount, from) in [
+        (IMAGE_DIGEST, "secret/app"),
+        (MANIFEST_DIGEST, "staging/app"),
+        (IMAGE_DIGEST, "Not/Valid"),
+    ] {
+        let response = app
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .uri(format!(
+                        "/v2/prod/app/blobs/uploads/?mount={}&from={}",
+                        mount, from
+                    ))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(response.headers().get("Docker-Upload-UUID").is_some());
+    }
+
+    // Write access to the target is still required.
+    let response = app
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri(format!(
+                    "/v2/staging/app/blobs/uploads/?mount={}&from=staging/app",
+                    IMAGE_DIGEST
+                ))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
 }
 
 #[test]
@@ -5,7 +5,37 @@ use axum::{
     http::header::CONTENT_TYPE,
     response::{IntoResponse, Response},
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::IgnoredAny, Deserialize, Serialize};
+
+/// Media type of an OCI image manifest.
+pub(crate) const OCI_IMAGE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+
+/// Media type of an OCI image index.
+pub(crate) const OCI_IMAGE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+
+/// Media type of a Docker image manifest (schema 2).
+pub(crate) const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+/// Media type of a Docker manifest list.
+pub(crate) const DOCKER_MANIFEST_LIST: &str =
+    "application/vnd.docker.distribution.manifest.list.v2+json";
+
+/// Docker media types and their OCI counterparts, used when converting image manifests.
+const DOCKER_TO_OCI_MEDIA_TYPES: &[(&str, &str)] = &[
+    (DOCKER_MANIFEST, OCI_IMAGE_MANIFEST),
+    (
+        "application/vnd.docker.container.image.v1+json",
+        "application/vnd.oci.image.config.v1+json",
+    ),
+    (
+        "application/vnd.docker.image.rootfs.diff.tar.gzip",
+        "application/vnd.oci.image.layer.v1.tar+gzip",
+    ),
+    (
+        "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
+        "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip",
+    ),
+];
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -13,9 +43,13 @@ pub(crate) struct ContentDescriptor {
     media_type: String,
     digest: String, // TODO: Use digest type
     size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
     urls: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     annotations: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     artifact_type: Option<String>,
 }
 
@@ -24,7 +58,7 @@ pub(crate) struct ContentDescriptor {
 pub(crate) struct ImageManifest {
     schema_version: u32,
 
-    media_type: String,
+    media_type: Option<String>,
     annotations: Option<HashMap<String, String>>,
     artifact_type: Option<String>,
 
@@ -33,28 +67,277 @@ pub(crate) struct ImageManifest {
     subject: Option<ContentDescriptor>,
 }
 
+/// An image index, also known as manifest list, referencing manifests for multiple platforms.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ImageIndex {
+    schema_version: u32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    media_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotations: Option<HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    artifact_type: Option<String>,
+
+    manifests: Vec<ContentDescriptor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subject: Option<ContentDescriptor>,
+}
+
+/// Any kind of manifest supported by the registry.
+#[derive(Debug)]
+pub(crate) enum Manifest {
+    /// A manifest describing a single image.
+    Image(ImageManifest),
+    /// An index of other manifests.
+    Index(ImageIndex),
+}
+
+/// The fields required to determine the kind of a manifest.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestKind {
+    media_type: Option<String>,
+    manifests: Option<IgnoredAny>,
+}
+
+impl ContentDescriptor {
+    pub(crate) fn digest(&self) -> &str {
+        self.digest.as_ref()
+    }
+
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns whether the content is fetched from elsewhere instead of being pushed.
+    ///
+    /// This is the case for foreign or non-distributable layers, e.g. of Windows base images.
+    pub(crate) fn is_external(&self) -> bool {
+        self.urls.as_ref().is_some_and(|urls| !urls.is_empty())
+            || self
+                .media_type
+                .starts_with("application/vnd.oci.image.layer.nondistributable.")
+            || self
+                .media_type
+                .starts_with("application/vnd.docker.image.rootfs.foreign.")
+    }
+}
+
 impl ImageManifest {
     pub(crate) fn media_type(&self) -> &str {
-        self.media_type.as_ref()
+        self.media_type.as_deref().unwrap_or(OCI_IMAGE_MANIFEST)
+    }
+
+    /// Returns the descriptors of all blobs referenced by this manifest.
+    pub(crate) fn blobs(&self) -> impl Iterator<Item = &ContentDescriptor> {
+        std::iter::once(&self.config).chain(self.layers.iter())
     }
 }
 
-// TODO: Return error as:
-// {
-//     "errors:" [{
-//             "code": <error identifier>,
-//             "message": <message describing condition>,
-//             "detail": <unstructured>
-//         },
-//         ...
-//     ]
-// }
+impl ImageIndex {
+    /// Creates a new OCI image index.
+    pub(crate) fn new(manifests: Vec<ContentDescriptor>) -> Self {
+        Self {
+            schema_version: 2,
+            media_type: Some(OCI_IMAGE_INDEX.to_owned()),
+            annotations: None,
+            artifact_type: None,
+            manifests,
+            subject: None,
+        }
+    }
+
+    pub(crate) fn media_type(&self) -> &str {
+        self.media_type.as_deref().unwrap_or(OCI_IMAGE_INDEX)
+    }
+}
+
+impl Manifest {
+    /// Parses a manifest of any supported kind.
+    ///
+    /// The kind is determined by the `mediaType` field. If it is missing, the presence of a
+    /// `manifests` field marks an index.
+    pub(crate) fn from_slice(raw: &[u8]) -> Result<Self, serde_json::Error> {
+        let kind: ManifestKind = serde_json::from_slice(raw)?;
+
+        match kind.media_type.as_deref() {
+            Some(OCI_IMAGE_MANIFEST | DOCKER_MANIFEST) => {
+                serde_json::from_slice(raw).map(Manifest::Image)
+            }
+            Some(OCI_IMAGE_INDEX | DOCKER_MANIFEST_LIST) => {
+                serde_json::from_slice(raw).map(Manifest::Index)
+            }
+            Some(other) => Err(serde::de::Error::custom(format_args!(
+                "unsupported manifest media type `{other}`"
+            ))),
+            None if kind.manifests.is_some() => serde_json::from_slice(raw).map(Manifest::Index),
+            None => serde_json::from_slice(raw).map(Manifest::Image),
+        }
+    }
+
+    /// Returns the media type of the manifest, to be used as its `Content-Type`.
+    pub(crate) fn media_type(&self) -> &str {
+        match self {
+            Manifest::Image(image) => image.media_type(),
+            Manifest::Index(index) => index.media_type(),
+        }
+    }
+
+    /// Checks whether the manifest may be stored under the given media type.
+    ///
+    /// A media type declared in the manifest must match exactly, otherwise any media type of the
+    /// same kind of manifest is allowed.
+    pub(crate) fn is_compatible_media_type(&self, media_type: &str) -> bool {
+        match self {
+            Manifest::Image(ImageManifest {
+                media_type: Some(declared),
+                ..
+            })
+            | Manifest::Index(ImageIndex {
+                media_type: Some(declared),
+                ..
+            }) => declared == media_type,
+            Manifest::Image(_) => [OCI_IMAGE_MANIFEST, DOCKER_MANIFEST].contains(&media_type),
+            Manifest::Index(_) => [OCI_IMAGE_INDEX, DOCKER_MANIFEST_LIST].contains(&media_type),
+        }
+    }
+
+    /// Returns the artifact type of the manifest.
+    ///
+    /// Image manifests without an explicit artifact type use the media type of their config.
+    pub(crate) fn artifact_type(&self) -> Option<&str> {
+        match self {
+            Manifest::Image(image) => image
+                .artifact_type
+                .as_deref()
+                .or(Some(image.config.media_type.as_str())),
+            Manifest::Index(index) => index.artifact_type.as_deref(),
+        }
+    }
+
+    /// Returns the descriptor of the manifest this manifest refers to, if any.
+    pub(crate) fn subject(&self) -> Option<&ContentDescriptor> {
+        match self {
+            Manifest::Image(image) => image.subject.as_ref(),
+            Manifest::Index(index) => index.subject.as_ref(),
+        }
+    }
+
+    /// Creates a descriptor for this manifest, as used in the listing of referrers.
+    pub(crate) fn descriptor(&self, digest: String, size: u64) -> ContentDescriptor {
+        let annotations = match self {
+            Manifest::Image(image) => image.annotations.clone(),
+            Manifest::Index(index) => index.annotations.clone(),
+        };
+
+        ContentDescriptor {
+            media_type: self.media_type().to_owned(),
+            digest,
+            size,
+            urls: None,
+            annotations,
+            data: None,
+            artifact_type: self.artifact_type().map(ToOwned::to_owned),
+        }
+    }
+
+    /// Returns the descriptors of all blobs referenced by this manifest.
+    ///
+    /// Indexes only reference other manifests, never blobs.
+    pub(crate) fn blobs(&self) -> impl Iterator<Item = &ContentDescriptor> {
+        let image = match self {
+            Manifest::Image(image) => Some(image),
+            Manifest::Index(_) => None,
+        };
 
+        image.into_iter().flat_map(ImageManifest::blobs)
+    }
+
+    /// Returns the descriptors of all manifests referenced by this manifest.
+    ///
+    /// Only indexes reference other manifests.
+    pub(crate) fn manifests(&self) -> impl Iterator<Item = &ContentDescriptor> {
+        let index = match self {
+            Manifest::Image(_) => None,
+            Manifest::Index(index) => Some(index),
+        };
+
+        index.into_iter().flat_map(|index| index.manifests.iter())
+    }
+}
+
+/// Converts a raw image manifest between the Docker and OCI formats.
+///
+/// Returns the converted manifest, or `None` if it cannot be represented as `media_type`, e.g.
+/// because it uses features only available in OCI manifests or references unknown media types.
+pub(crate) fn convert_image_manifest(raw: &[u8], media_type: &str) -> Option<Vec<u8>> {
+    let to_oci = match media_type {
+        OCI_IMAGE_MANIFEST => true,
+        DOCKER_MANIFEST => false,
+        _ => return None,
+    };
+
+    let convert_media_type = |value: &mut serde_json::Value| -> Option<()> {
+        let current = value.as_str()?;
+        let converted = DOCKER_TO_OCI_MEDIA_TYPES
+            .iter()
+            .find(|&&(docker, oci)| current == docker || current == oci)
+            .map(|&(docker, oci)| if to_oci { oci } else { docker })?;
+        *value = converted.into();
+        Some(())
+    };
+
+    let mut manifest: serde_json::Value = serde_json::from_slice(raw).ok()?;
+    let fields = manifest.as_object_mut()?;
+
+    // Docker manifests have no equivalent of these.
+    if !to_oci && (fields.contains_key("subject") || fields.contains_key("artifactType")) {
+        return None;
+    }
+
+    fields.insert("mediaType".to_owned(), media_type.into());
+    convert_media_type(fields.get_mut("config")?.get_mut("mediaType")?)?;
+    for layer in fields.get_mut("layers")?.as_array_mut()? {
+        convert_media_type(layer.get_mut("mediaType")?)?;
+    }
+
+    serde_json::to_vec(&manifest).ok()
+}
+
+/// Response body of the tag listing endpoint.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct TagList {
+    pub(crate) name: String,
+    pub(crate) tags: Vec<String>,
+}
+
+/// Response body of the catalog endpoint.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Catalog {
+    pub(crate) repositories: Vec<String>,
+}
+
+/// A single error, as returned in the body of failed requests.
+///
+/// Errors are always returned wrapped in an [`OciErrors`]:
+///
+/// ```json
+/// {
+///     "errors": [{
+///         "code": "<error identifier>",
+///         "message": "<message describing condition>",
+///         "detail": <unstructured, optional>
+///     }]
+/// }
+/// ```
 #[derive(Debug, Serialize)]
 pub(crate) struct OciError {
     code: ErrorCode,
     message: String,
-    // not supported: detail
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Serialize)]
@@ -70,13 +353,28 @@ impl OciErrors {
     }
 }
 
+impl FromIterator<OciError> for OciErrors {
+    fn from_iter<T: IntoIterator<Item = OciError>>(iter: T) -> Self {
+        Self {
+            errors: iter.into_iter().collect(),
+        }
+    }
+}
+
 impl OciError {
     pub(crate) fn new(code: ErrorCode) -> Self {
         Self {
             code,
             message: code.to_string(),
+            detail: None,
         } // TODO: Use actual message
     }
+
+    /// Attaches additional, unstructured information to the error.
+    pub(crate) fn with_detail<D: Serialize>(mut self, detail: D) -> Self {
+        self.detail = Some(serde_json::to_value(detail).expect("serialization should not fail"));
+        self
+    }
 }
 
 #[derive(Clone, Copy, Debug, Serialize)]
@@ -93,15 +391,17 @@ pub(crate) enum ErrorCode {
     NameInvalid,
     NameUnknown,
     SizeInvalid,
+    /// Not part of the OCI specification, but used by the reference implementation.
+    TagInvalid,
     Unauthorized,
     Denied,
     Unsupported,
     #[serde(rename = "TOOMANYREQUESTS")]
     TooManyRequests,
+    /// Not part of the specification, used for internal errors.
+    Unknown,
 }
 
-// TOOD: Derive HTTP status from error code.
-
 impl ErrorCode {
     fn message(&self) -> &'static str {
         match self {
@@ -115,10 +415,12 @@ impl ErrorCode {
             ErrorCode::NameInvalid => "invalid repository name",
             ErrorCode::NameUnknown => "repository name not known to registry",
             ErrorCode::SizeInvalid => "provided length did not match content length",
+            ErrorCode::TagInvalid => "manifest tag did not match URI",
             ErrorCode::Unauthorized => "authentication required",
             ErrorCode::Denied => "requested access to the resource is denied",
             ErrorCode::Unsupported => "the operation is unsupported",
             ErrorCode::TooManyRequests => "too many requests",
+            ErrorCode::Unknown => "unknown error",
         }
     }
 }
@@ -142,7 +444,7 @@ impl IntoResponse for OciErrors {
 
 #[cfg(test)]
 mod tests {
-    use super::ImageManifest;
+    use super::{convert_image_manifest, ImageManifest, Manifest};
 
     #[test]
     fn simple_example_schema_parse() {
@@ -165,4 +467,130 @@ mod tests {
 
         let _manifest: ImageManifest = serde_json::from_str(raw).expect("could not parse manifest");
     }
+
+    #[test]
+    fn dispatches_on_media_type() {
+        let index = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "manifests": [
+                {
+                    "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                    "size": 7143,
+                    "digest": "sha256:e692418e4cbaf90ca69d05a66403747baa33ee08806650b51fab815ad7fc331f",
+                    "platform": {
+                        "architecture": "ppc64le",
+                        "os": "linux"
+                    }
+                }
+            ]
+        }"#;
+        match Manifest::from_slice(index.as_bytes()).expect("could not parse index") {
+            Manifest::Index(index) => {
+                assert_eq!(
+                    index.media_type(),
+                    "application/vnd.oci.image.index.v1+json"
+                );
+                assert_eq!(index.manifests.len(), 1);
+            }
+            other => panic!("unexpected manifest {other:?}"),
+        }
+
+        let manifest_list = index.replace(
+            "application/vnd.oci.image.index.v1+json",
+            "application/vnd.docker.distribution.manifest.list.v2+json",
+        );
+        assert!(matches!(
+            Manifest::from_slice(manifest_list.as_bytes()),
+            Ok(Manifest::Index(_))
+        ));
+
+        // Without a media type, the structure decides.
+        let untyped_index = index.replace(
+            r#""mediaType": "application/vnd.oci.image.index.v1+json","#,
+            "",
+        );
+        assert!(matches!(
+            Manifest::from_slice(untyped_index.as_bytes()),
+            Ok(Manifest::Index(_))
+        ));
+
+        // An index is not a valid image manifest.
+        let mislabeled = index.replace(
+            "application/vnd.oci.image.index.v1+json",
+            "application/vnd.oci.image.manifest.v1+json",
+        );
+        assert!(Manifest::from_slice(mislabeled.as_bytes()).is_err());
+
+        let unknown = index.replace(
+            "application/vnd.oci.image.index.v1+json",
+            "application/x-unknown",
+        );
+        assert!(Manifest::from_slice(unknown.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn converts_between_docker_and_oci_manifests() {
+        let docker = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+            "config": {
+               "mediaType": "application/vnd.docker.container.image.v1+json",
+               "size": 2298,
+               "digest": "sha256:e4c58958181a5925816faa528ce959e487632f4cfd192f8132f71b32df2744b4"
+            },
+            "layers": [
+               {
+                  "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip",
+                  "size": 30439111,
+                  "digest": "sha256:43f89b94cd7df92a2f7e565b8fb1b7f502eff2cd225508cbd7ea2d36a9a3a601"
+               }
+            ]
+        }"#;
+
+        let oci = convert_image_manifest(
+            docker.as_bytes(),
+            "application/vnd.oci.image.manifest.v1+json",
+        )
+        .expect("could not convert to OCI");
+        let oci_value: serde_json::Value = serde_json::from_slice(&oci).unwrap();
+        assert_eq!(
+            oci_value["mediaType"],
+            "application/vnd.oci.image.manifest.v1+json"
+        );
+        assert_eq!(
+            oci_value["config"]["mediaType"],
+            "application/vnd.oci.image.config.v1+json"
+        );
+        assert_eq!(
+            oci_value["layers"][0]["mediaType"],
+            "application/vnd.oci.image.layer.v1.tar+gzip"
+        );
+        assert_eq!(oci_value["layers"][0]["size"], 30439111);
+
+        let round_trip =
+            convert_image_manifest(&oci, "application/vnd.docker.distribution.manifest.v2+json")
+                .expect("could not convert back to Docker");
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&round_trip).unwrap(),
+            serde_json::from_str::<serde_json::Value>(docker).unwrap()
+        );
+
+        // OCI-only features and unknown layer types cannot be converted.
+        let mut artifact = oci_value.clone();
+        artifact["artifactType"] = "application/vnd.example".into();
+        assert!(convert_image_manifest(
+            artifact.to_string().as_bytes(),
+            "application/vnd.docker.distribution.manifest.v2+json"
+        )
+        .is_none());
+
+        let mut zstd = oci_value;
+        zstd["layers"][0]["mediaType"] = "application/vnd.oci.image.layer.v1.tar+zstd".into();
+        assert!(convert_image_manifest(
+            zstd.to_string().as_bytes(),
+            "application/vnd.docker.distribution.manifest.v2+json"
+        )
+        .is_none());
+    }
 }